@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap};
+use storage::{Error, SqliteStorage};
+use kcoin::Network;
+use genesis::Genesis;
+use block;
+use state;
+use exchange;
+use producer::Authority;
+
+/// Something the audit found that doesn't add up.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Discrepancy {
+    Block {
+        height: u32,
+        reason: String
+    },
+    TransactionHash {
+        hash: String,
+        block: u32,
+        index: u32
+    },
+    Signature {
+        hash: String,
+        block: u32,
+        index: u32
+    },
+    Nonce {
+        hash: String,
+        block: u32,
+        index: u32,
+        expected: u64
+    },
+    Replay {
+        hash: String,
+        block: u32,
+        index: u32,
+        reason: String
+    },
+    StateRoot {
+        height: u32,
+        expected: String,
+        actual: String
+    },
+    Balance {
+        address: String,
+        coin: String,
+        expected: i64,
+        actual: i64
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub height: u32,
+    pub transactions: u64,
+    pub discrepancies: Vec<Discrepancy>
+}
+
+/// Rebuilds every balance from genesis by replaying all mined transactions in `(block, index)`
+/// order into an in-memory database, using the same code that applies them and matches orders
+/// when a block is made.
+/// The result is compared to the stored balances. Along the way every block, every transaction
+/// signature and the nonces of every sender get verified again. A chain taken over from a datadir
+/// without a genesis block is replayed from its legacy checkpoint on.
+pub fn run(storage: &SqliteStorage, network: &Network, authority: &Authority, genesis: &Genesis) -> Result<Report, Error> {
+    let replay = SqliteStorage::new_in_memory(genesis)?;
+    let replay_conn = replay.get_conn()?;
+    let legacy_height = storage.legacy_height()?;
+
+    let mut discrepancies = Vec::new();
+    let mut transactions = 0;
+    let height = storage.block_height()?;
+    let mut prev_hash = genesis.hash.clone();
+    let mut prev_time = genesis.timestamp;
+    // The last nonce of every sender, legacy blocks included
+    let mut nonces: HashMap<String, u64> = HashMap::new();
+    for h in 0..height + 1 {
+        let txs = storage.chain_get_transactions(Some(h), None, None, None, u32::max_value(), network)?;
+        let tx_hashes: Vec<String> = txs.iter().map(|tx| tx.tx_envelope.hash.clone()).collect();
+
+        let legacy = h > 0 && h <= legacy_height;
+        let stored_block = match storage.block_get_by_height(h) {
+            Ok(b) => {
+                let problem = match legacy {
+                    true => block::check_legacy_block(&b, &prev_hash, &tx_hashes)?,
+                    false => block::check_block(&b, &prev_hash, prev_time, &tx_hashes, authority)?
+                };
+                if let Some(problem) = problem {
+                    discrepancies.push(Discrepancy::Block { height: h, reason: problem.to_owned() });
+                }
+                prev_hash = b.hash.clone();
+                prev_time = b.time;
+                Some(b)
+            },
+            Err(Error::NotFound) => {
+                discrepancies.push(Discrepancy::Block { height: h, reason: "missing".to_owned() });
+                None
+            },
+            Err(e) => return Err(e)
+        };
+
+        for tx in txs.iter() {
+            transactions += 1;
+            let envelope = &tx.tx_envelope;
+            if envelope.tx.hash(envelope.version).ok().as_ref() != Some(&envelope.hash) {
+                discrepancies.push(Discrepancy::TransactionHash { hash: envelope.hash.clone(), block: tx.block, index: tx.index });
+            }
+            if !envelope.verify(&replay, &replay_conn) {
+                discrepancies.push(Discrepancy::Signature { hash: envelope.hash.clone(), block: tx.block, index: tx.index });
+            }
+            let expected = nonces.get(&envelope.tx.from.address).map_or(0, |n| n + 1);
+            nonces.insert(envelope.tx.from.address.clone(), envelope.tx.nonce);
+            if legacy {
+                continue;
+            }
+            if envelope.tx.nonce != expected {
+                discrepancies.push(Discrepancy::Nonce { hash: envelope.hash.clone(), block: tx.block, index: tx.index, expected });
+            }
+            if let Err(e) = replay.transaction_insert_with_conn(&replay_conn, tx.block, tx.index, envelope) {
+                discrepancies.push(Discrepancy::Replay { hash: envelope.hash.clone(), block: tx.block, index: tx.index, reason: e.to_string() });
+            }
+        }
+
+        // Orders get matched after the transactions of every block, like when it was applied
+        if h > 0 && !legacy {
+            exchange::match_orders_with_conn(&replay, &replay_conn, h)?;
+        }
+        if legacy {
+            if h == legacy_height {
+                replay.legacy_seed_with_conn(&replay_conn, storage)?;
+            }
+            continue;
+        }
+        if let Some(b) = stored_block {
+            let replay_root = state::root(&replay.balance_get_all_with_conn(&replay_conn)?);
+            if replay_root != b.state_root {
+                discrepancies.push(Discrepancy::StateRoot { height: h, expected: replay_root, actual: b.state_root });
+            }
+        }
+    }
+
+    let mut expected = BTreeMap::new();
+    for b in replay.balance_get_all_with_conn(&replay_conn)? {
+        expected.insert((b.address, b.coin), b.balance);
+    }
+    let mut actual = BTreeMap::new();
+    let conn = storage.get_conn()?;
+    for b in storage.balance_get_all_with_conn(&conn)? {
+        actual.insert((b.address, b.coin), b.balance);
+    }
+    for (key, expected_balance) in expected.iter() {
+        let actual_balance = actual.get(key).cloned().unwrap_or(0);
+        if actual_balance != *expected_balance {
+            discrepancies.push(Discrepancy::Balance { address: key.0.clone(), coin: key.1.clone(), expected: *expected_balance, actual: actual_balance });
+        }
+    }
+    for (key, actual_balance) in actual.iter() {
+        if !expected.contains_key(key) && *actual_balance != 0 {
+            discrepancies.push(Discrepancy::Balance { address: key.0.clone(), coin: key.1.clone(), expected: 0, actual: *actual_balance });
+        }
+    }
+
+    Ok(Report {
+        ok: discrepancies.len() == 0,
+        height,
+        transactions,
+        discrepancies
+    })
+}
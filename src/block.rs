@@ -1,6 +1,7 @@
 
 use storage::Error;
 use storage::SqliteStorage;
+use storage::PrunedTx;
 use kcoin::Network;
 use time::Timespec;
 use tx::{MinedTx, TransactionEnvelope};
@@ -9,36 +10,106 @@ use sha2::{Sha256, Digest};
 use std::vec::Vec;
 use hex;
 use rusqlite::Connection;
+use storage::AddressBalance;
+use genesis::Genesis;
+use merkle;
+use state;
+use producer::{Producer, Authority};
+use exchange;
+
+/// How many seconds a block received from another node may be ahead of the local clock.
+pub const MAX_CLOCK_DRIFT: i64 = 15;
+
+/// How many local blocks a node replaces at most to switch to a competing branch.
+pub const MAX_REORG_DEPTH: u32 = 10;
 
 #[derive(Debug)]
 pub struct Block {
     pub height: u32,
     pub hash: String,
-    pub time: i64
+    pub prev_hash: String,
+    pub merkle_root: String,
+    pub state_root: String,
+    pub time: i64,
+    pub signature: String
 }
 
+
 #[derive(Debug)]
 pub struct BlockWithTransactions {
     pub block: Block,
     pub txs: Vec<TransactionEnvelope>
 }
 
-pub fn generate(storage: &SqliteStorage, block_size: u64, network: &Network) -> Result<(), Error> {
+pub fn generate(storage: &SqliteStorage, block_size: u64, network: &Network, producer: &Producer) -> Result<Option<BlockWithTransactions>, Error> {
+    let authority = Authority::single(&producer.public_key());
+    generate_at(storage, block_size, network, producer, &authority, time::get_time().sec)
+}
+
+/// Makes a block with the given timestamp. Returns `None` if there was nothing to put in it.
+/// Expired transactions are pruned from the mempool first.
+/// Fails if a block made at `time` on top of the current tip isn't `producer`'s to sign.
+pub fn generate_at(storage: &SqliteStorage, block_size: u64, network: &Network, producer: &Producer, authority: &Authority, time: i64) -> Result<Option<BlockWithTransactions>, Error> {
     let conn = storage.get_conn()?;
-    println!("gen block");
-    let txs = storage.mempool_get_block_candidates(block_size, network)?;
+    let height = storage.block_height()? + 1;
+    let pruned = storage.mempool_prune_expired(height)?;
+    if pruned.len() > 0 {
+        info!("Pruned {} expired transactions from the mempool", pruned.len());
+    }
+    let txs = storage.mempool_get_block_candidates(block_size, height, time, network)?;
 
     if txs.len() == 0 {
         // No transactions available. No need for a block.
-        return Ok(());
+        return Ok(None);
     }
 
     // We have some txs, let's make a block.
     // They are already sorted to maximize the fee revenue.
     // We just need to update the balances accordingly.
-    println!("found {:?} pending transactions. will craft block", txs.len());
+    debug!("Making a block of {} pending transactions", txs.len());
+    storage.start_transaction(&conn)?;
+    match generate_with_conn(storage, &conn, &txs, network, producer, authority, time) {
+        Ok(block) => {
+            storage.commit_transaction(&conn)?;
+            Ok(Some(BlockWithTransactions { block, txs }))
+        },
+        Err(e) => {
+            storage.rollback_transaction(&conn)?;
+            Err(e)
+        }
+    }
+}
+
+fn generate_with_conn(storage: &SqliteStorage, conn: &Connection, txs: &Vec<TransactionEnvelope>, network: &Network, producer: &Producer, authority: &Authority, time: i64) -> Result<Block, Error> {
+    let height = storage.block_height_with_conn(&conn)? + 1;
+    let prev = storage.block_get_by_height_with_conn(&conn, height - 1)?;
+    if authority.expected_signer(height, prev.time, time) != Some(producer.public_key().as_str()) {
+        // Another producer's block arrived in the meantime
+        return Err(Error::InvalidBlock { height, reason: "not this producer's turn".to_owned() });
+    }
+    let prev_hash = prev.hash;
+
+    let tx_hashes = apply_transactions_with_conn(storage, conn, height, txs)?;
+    exchange::match_orders_with_conn(storage, conn, height)?;
+
+    let merkle_root = merkle_root(&tx_hashes)?;
+    let state = state::Tree::new(storage.balance_get_all_with_conn(&conn)?);
+    let state_root = state.root();
+    let hash = block_hash(height, time, &prev_hash, &merkle_root, &state_root);
+    let signature = producer.sign(&hash).map_err(|_| Error::InternalError)?;
+    let block = Block {height: height, hash: hash, prev_hash: prev_hash, merkle_root: merkle_root, state_root: state_root, time, signature};
+    storage.block_add_with_conn(&conn, &block)?;
+    storage.state_tree_put(&block.hash, state)?;
+    debug!("Made block {:?}", block);
+    Ok(block)
+}
+
+/// Applies a block made by another node on top of the local chain. The transactions are applied
+/// with the same code as when making a block and the result has to match the block's header.
+pub fn apply(storage: &SqliteStorage, block: &BlockWithTransactions, authority: &Authority) -> Result<(), Error> {
+    let conn = storage.get_conn()?;
     storage.start_transaction(&conn)?;
-    match generate_with_conn(storage, &conn, &txs, network) {
+    match apply_with_conn(storage, &conn, block, authority) {
         Ok(_) => {
             storage.commit_transaction(&conn)?;
             Ok(())
@@ -50,41 +121,338 @@ pub fn generate(storage: &SqliteStorage, block_size: u64, network: &Network) ->
     }
 }
 
-fn generate_with_conn(storage: &SqliteStorage, conn: &Connection, txs: &Vec<TransactionEnvelope>, network: &Network) -> Result<(), Error> {
-    let height = storage.block_height()? + 1;
+fn apply_with_conn(storage: &SqliteStorage, conn: &Connection, block: &BlockWithTransactions, authority: &Authority) -> Result<(), Error> {
+    let height = storage.block_height_with_conn(&conn)? + 1;
+    let invalid = |reason: &str| Error::InvalidBlock { height: block.block.height, reason: reason.to_owned() };
+    if block.block.height != height {
+        return Err(invalid("does not extend the local chain"));
+    }
+    // A producer could otherwise claim the turns of the producers after it by dating its block ahead.
+    if block.block.time > time::get_time().sec + MAX_CLOCK_DRIFT {
+        return Err(invalid("time is in the future"));
+    }
+    let prev = storage.block_get_by_height_with_conn(&conn, height - 1)?;
+
+    for tx in block.txs.iter() {
+        if tx.tx.hash(tx.version).ok().as_ref() != Some(&tx.hash) {
+            return Err(invalid("transaction hash does not match its contents"));
+        }
+        if !tx.tx.is_mature(height, block.block.time) {
+            return Err(invalid("transaction is not valid yet"));
+        }
+        if tx.tx.is_expired(height) {
+            return Err(invalid("transaction has expired"));
+        }
+    }
+    let tx_hashes = apply_transactions_with_conn(storage, conn, height, &block.txs)?;
+    exchange::match_orders_with_conn(storage, conn, height)?;
+
+    let state = state::Tree::new(storage.balance_get_all_with_conn(&conn)?);
+    if state.root() != block.block.state_root {
+        return Err(invalid("state root does not match the balances"));
+    }
+    if let Some(problem) = check_block(&block.block, &prev.hash, prev.time, &tx_hashes, authority)? {
+        return Err(invalid(problem));
+    }
+    storage.block_add_with_conn(&conn, &block.block)?;
+    storage.state_tree_put(&block.block.hash, state)?;
+    debug!("Applied block {:?}", block.block);
+    Ok(())
+}
+
+/// What `rollback` removed from the chain.
+#[derive(Debug, Serialize)]
+pub struct Rollback {
+    pub from_height: u32,
+    pub to_height: u32,
+    pub tx_hashes: Vec<String>,
+    pub returned_to_mempool: bool,
+    /// Transactions that couldn't go back into the mempool, see `mempool_return_with_conn`
+    pub dropped: Vec<PrunedTx>,
+    /// The name the caller gave, nothing checks it
+    pub unverified_operator: String,
+    pub source: String
+}
+
+/// Removes the top `blocks` blocks, restores the balances from before them and optionally puts
+/// their transactions back into the mempool, dropping those that can't be mined anymore.
+/// Everything happens in one db transaction together with a record of who claims to have rolled
+/// back what and how they asked for it. The genesis block can't be rolled back.
+///
+/// Only the local chain changes. Peers and followers keep the removed blocks, so callers refuse
+/// to roll back while any are configured and every node of the network has to be rolled back on
+/// its own.
+pub fn rollback(storage: &SqliteStorage, blocks: u32, operator: &str, source: &str, to_mempool: bool, network: &Network) -> Result<Rollback, Error> {
+    let from_height = storage.block_height()?;
+    if blocks == 0 || blocks > from_height {
+        return Err(Error::InvalidBlock { height: from_height, reason: format!("cannot roll back {} blocks", blocks) });
+    }
+    let to_height = from_height - blocks;
+    // There is no balance history before the legacy checkpoint
+    if to_height < storage.legacy_height()? {
+        return Err(Error::InvalidBlock { height: from_height, reason: "cannot roll back past the legacy checkpoint".to_owned() });
+    }
+
+    // Read the transactions block by block so they go back into the mempool in chain order.
+    let mut txs = Vec::new();
+    for height in to_height + 1..from_height + 1 {
+        for tx in storage.chain_get_transactions(Some(height), None, None, None, u32::max_value(), network)? {
+            txs.push(tx.tx_envelope);
+        }
+    }
+
+    let conn = storage.get_conn()?;
+    storage.start_transaction(&conn)?;
+    match rollback_with_conn(storage, &conn, from_height, to_height, &txs, operator, source, to_mempool) {
+        Ok(rollback) => {
+            storage.commit_transaction(&conn)?;
+            Ok(rollback)
+        },
+        Err(e) => {
+            storage.rollback_transaction(&conn)?;
+            Err(e)
+        }
+    }
+}
+
+fn rollback_with_conn(storage: &SqliteStorage, conn: &Connection, from_height: u32, to_height: u32, txs: &Vec<TransactionEnvelope>, operator: &str, source: &str, to_mempool: bool) -> Result<Rollback, Error> {
+    if storage.block_height_with_conn(&conn)? != from_height {
+        return Err(Error::InvalidBlock { height: from_height, reason: "chain changed during rollback".to_owned() });
+    }
+    storage.rollback_to_height_with_conn(&conn, to_height)?;
+    let dropped = if to_mempool {
+        storage.mempool_return_with_conn(&conn, txs, to_height)?
+    } else {
+        Vec::new()
+    };
+    storage.balance_sanity_check_with_conn(&conn)?;
+
+    let tx_hashes: Vec<String> = txs.iter().map(|tx| tx.hash.clone()).collect();
+    storage.rollback_log_add_with_conn(&conn, operator, source, from_height, to_height, &tx_hashes, to_mempool)?;
+    info!("Rolled back blocks {} to {}, requested by {} through {}", to_height + 1, from_height, operator, source);
+    Ok(Rollback {
+        from_height,
+        to_height,
+        tx_hashes,
+        returned_to_mempool: to_mempool,
+        dropped,
+        unverified_operator: operator.to_owned(),
+        source: source.to_owned()
+    })
+}
+
+/// Fork choice between two blocks on top of the same parent made at `prev_time`. The block made
+/// in the earlier turn wins, it belongs to the producer that was on time. Two blocks of the same
+/// turn come from the same producer, the lower hash wins then. Every node makes the same choice
+/// no matter which block it saw first.
+pub fn prefers(candidate: &Block, current: &Block, prev_time: i64, authority: &Authority) -> bool {
+    match (authority.turn(prev_time, candidate.time), authority.turn(prev_time, current.time)) {
+        (Some(a), Some(b)) => a < b || (a == b && candidate.hash < current.hash),
+        (Some(_), None) => true,
+        (None, _) => false
+    }
+}
 
-    let mut hash_result: Vec<u8> = Vec::new();
+/// Replaces the local blocks above `fork_height` with `branch`, if the first block of `branch`
+/// wins the fork choice against the local block at that height, see `prefers`. The transactions
+/// of the replaced blocks that `branch` doesn't have go back into the mempool as far as they are
+/// still valid. Everything happens in one db transaction and gets recorded in the rollback log.
+pub fn reorg(storage: &SqliteStorage, fork_height: u32, branch: &[BlockWithTransactions], authority: &Authority, network: &Network) -> Result<Rollback, Error> {
+    let from_height = storage.block_height()?;
+    let invalid = |reason: &str| Error::InvalidBlock { height: fork_height + 1, reason: reason.to_owned() };
+    if branch.is_empty() || fork_height >= from_height {
+        return Err(invalid("no local blocks to replace"));
+    }
+    if from_height - fork_height > MAX_REORG_DEPTH {
+        return Err(invalid("fork is too deep"));
+    }
+    if fork_height < storage.legacy_height()? {
+        return Err(invalid("cannot replace blocks before the legacy checkpoint"));
+    }
+    let parent = storage.block_get_by_height(fork_height)?;
+    let current = storage.block_get_by_height(fork_height + 1)?;
+    if !prefers(&branch[0].block, &current, parent.time, authority) {
+        return Err(invalid("loses the fork choice against the local block"));
+    }
+
+    let mut txs = Vec::new();
+    for height in fork_height + 1..from_height + 1 {
+        for tx in storage.chain_get_transactions(Some(height), None, None, None, u32::max_value(), network)? {
+            txs.push(tx.tx_envelope);
+        }
+    }
+
+    let conn = storage.get_conn()?;
+    storage.start_transaction(&conn)?;
+    match reorg_with_conn(storage, &conn, from_height, fork_height, branch, &txs, authority) {
+        Ok(rollback) => {
+            storage.commit_transaction(&conn)?;
+            Ok(rollback)
+        },
+        Err(e) => {
+            storage.rollback_transaction(&conn)?;
+            Err(e)
+        }
+    }
+}
+
+fn reorg_with_conn(storage: &SqliteStorage, conn: &Connection, from_height: u32, fork_height: u32, branch: &[BlockWithTransactions], txs: &Vec<TransactionEnvelope>, authority: &Authority) -> Result<Rollback, Error> {
+    if storage.block_height_with_conn(&conn)? != from_height {
+        return Err(Error::InvalidBlock { height: from_height, reason: "chain changed during fork choice".to_owned() });
+    }
+    storage.rollback_to_height_with_conn(&conn, fork_height)?;
+    for block in branch.iter() {
+        apply_with_conn(storage, conn, block, authority)?;
+    }
+    let dropped = storage.mempool_return_with_conn(&conn, txs, fork_height + branch.len() as u32)?;
+    storage.balance_sanity_check_with_conn(&conn)?;
+
+    let tx_hashes: Vec<String> = txs.iter().map(|tx| tx.hash.clone()).collect();
+    storage.rollback_log_add_with_conn(&conn, "", "fork choice", from_height, fork_height, &tx_hashes, true)?;
+    info!("Replaced blocks {} to {} with a competing branch of {} blocks", fork_height + 1, from_height, branch.len());
+    Ok(Rollback {
+        from_height,
+        to_height: fork_height,
+        tx_hashes,
+        returned_to_mempool: true,
+        dropped,
+        unverified_operator: String::new(),
+        source: "fork choice".to_owned()
+    })
+}
+
+/// Applies the transactions of the block at `height` in order and returns their hashes.
+fn apply_transactions_with_conn(storage: &SqliteStorage, conn: &Connection, height: u32, txs: &Vec<TransactionEnvelope>) -> Result<Vec<String>, Error> {
+    let mut tx_hashes = Vec::new();
     for (i, tx) in txs.iter().enumerate() {
-        println!("{:?}", tx);
+        debug!("Applying transaction {}", tx.hash);
+        // The nonces of a sender have no gaps, otherwise its skipped transactions could never be mined
+        let expected_nonce = storage.address_nonce_mined_with_conn(&conn, &tx.tx.from)?.map_or(0, |n| n + 1);
+        if tx.tx.nonce != expected_nonce {
+            return Err(Error::InvalidBlock { height, reason: "nonce does not follow the sender's last mined nonce".to_owned() });
+        }
+        // Checked one by one, a key rotated earlier in the block already applies
+        if !tx.verify(storage, conn) {
+            return Err(Error::InvalidBlock { height, reason: "invalid transaction signature".to_owned() });
+        }
         storage.transaction_insert_with_conn(&conn, height, i as u32, tx)?;
-        storage.mempool_remove_with_conn(&conn, &tx.hash);
-        hash_result.extend(hex::decode(&tx.hash).map_err(|e| Error::InternalError)?);
-        hash_result = hash(&hash_result);
-        println!("hash step {:?}", hex::encode(&hash_result));
+        storage.mempool_remove_mined_with_conn(&conn, &tx.tx.from, tx.tx.nonce)?;
+        tx_hashes.push(tx.hash.clone());
     }
 
     // sanity balance check
     storage.balance_sanity_check_with_conn(&conn)?;
+    Ok(tx_hashes)
+}
+
+/// Builds the block at height 0. Its parent hash is the hash of the genesis file, so the whole
+/// chain commits to the genesis it was started from. It is the same on every node and unsigned.
+pub fn genesis_block(genesis: &Genesis, balances: &[AddressBalance]) -> Result<Block, Error> {
+    let merkle_root = merkle_root(&[])?;
+    let state_root = state::root(balances);
+    let hash = block_hash(0, genesis.timestamp, &genesis.hash, &merkle_root, &state_root);
+    Ok(Block {
+        height: 0,
+        hash,
+        prev_hash: genesis.hash.clone(),
+        merkle_root,
+        state_root,
+        time: genesis.timestamp,
+        signature: String::new()
+    })
+}
+
+/// Computes the merkle root over the hashes of a block's transactions, in block order.
+pub fn merkle_root(tx_hashes: &[String]) -> Result<String, Error> {
+    let mut leaves = Vec::new();
+    for tx_hash in tx_hashes.iter() {
+        leaves.push(hex::decode(tx_hash).map_err(|_| Error::InternalError)?);
+    }
+    Ok(hex::encode(merkle::root(&leaves)))
+}
 
-    let time = time::get_time().sec;
-    let addition_json = json!({
+/// Computes the hash of a block header. It commits to the block's height and time, to the merkle
+/// root of its transactions, to the state root of all balances after the block and to the hash
+/// of the previous block.
+pub fn block_hash(height: u32, time: i64, prev_hash: &str, merkle_root: &str, state_root: &str) -> String {
+    let header_json = json!({
         "height": height,
+        "merkle_root": merkle_root,
+        "prev_hash": prev_hash,
+        "state_root": state_root,
         "time": time
     });
+    hex::encode(hash(header_json.to_string().as_bytes()))
+}
 
-    println!("addition {:?}", addition_json.to_string());
-    let addition_hash = hash(addition_json.to_string().as_bytes());
-    println!("addition hash {:?}", hex::encode(&addition_hash));
+/// Returns the sibling path proving that the transaction at `index` is part of a block with the
+/// given transactions. Verify it with `merkle::verify` against the block's merkle root.
+pub fn transaction_proof(tx_hashes: &[String], index: usize) -> Result<Vec<merkle::ProofStep>, Error> {
+    let mut leaves = Vec::new();
+    for tx_hash in tx_hashes.iter() {
+        leaves.push(hex::decode(tx_hash).map_err(|_| Error::InternalError)?);
+    }
+    merkle::proof(&leaves, index).ok_or(Error::NotFound)
+}
 
-    hash_result.extend(&addition_hash);
-    hash_result = hash(&hash_result);
+/// Walks the chain from the genesis block to the tip and checks every block with `check_block`,
+/// or `check_legacy_block` up to the legacy checkpoint.
+/// Returns the height of the first broken block, or `None` if the chain is intact.
+pub fn validate_chain(storage: &SqliteStorage, authority: &Authority) -> Result<Option<u32>, Error> {
+    let height = storage.block_height()?;
+    let legacy_height = storage.legacy_height()?;
+    let mut prev_hash = storage.genesis_hash().to_owned();
+    let mut prev_time = 0;
+    for h in 0..height + 1 {
+        let block = match storage.block_get_by_height(h) {
+            Ok(v) => v,
+            Err(Error::NotFound) => return Ok(Some(h)),
+            Err(e) => return Err(e)
+        };
+        let tx_hashes = storage.block_get_tx_hashes(h)?;
+        let problem = match h > 0 && h <= legacy_height {
+            true => check_legacy_block(&block, &prev_hash, &tx_hashes)?,
+            false => check_block(&block, &prev_hash, prev_time, &tx_hashes, authority)?
+        };
+        if let Some(problem) = problem {
+            info!("Block {} is invalid: {}", h, problem);
+            return Ok(Some(h));
+        }
+        prev_hash = block.hash;
+        prev_time = block.time;
+    }
+    Ok(None)
+}
 
-    let hash = hex::encode(hash_result);
-    let block = Block {height: height, hash: hash, time};
-    println!("autocommit before adding block {:?}", conn.is_autocommit());
-    storage.block_add_with_conn(&conn, &block)?;
-    println!("block added {:?}", block);
-    Ok(())
+/// Checks that a block points to `prev_hash`, that its merkle root and hash match its contents
+/// and that it is signed by the producer whose turn it was after a parent made at `prev_time`.
+/// The genesis block is not signed. Returns a description of the first problem found.
+pub fn check_block(block: &Block, prev_hash: &str, prev_time: i64, tx_hashes: &[String], authority: &Authority) -> Result<Option<&'static str>, Error> {
+    if block.prev_hash != prev_hash {
+        return Ok(Some("does not point to its parent"));
+    }
+    if merkle_root(tx_hashes)? != block.merkle_root {
+        return Ok(Some("merkle root does not match its transactions"));
+    }
+    if block_hash(block.height, block.time, &block.prev_hash, &block.merkle_root, &block.state_root) != block.hash {
+        return Ok(Some("hash does not match its header"));
+    }
+    if block.height > 0 && !authority.verify(block.height, prev_time, block.time, &block.hash, &block.signature) {
+        return Ok(Some("invalid producer signature"));
+    }
+    Ok(None)
+}
+
+/// Checks a block from before the genesis block, see `SqliteStorage::legacy_height`. Only how it
+/// links up and its merkle root can be checked, its hash and signature predate both.
+pub fn check_legacy_block(block: &Block, prev_hash: &str, tx_hashes: &[String]) -> Result<Option<&'static str>, Error> {
+    if block.prev_hash != prev_hash {
+        return Ok(Some("does not point to its parent"));
+    }
+    if merkle_root(tx_hashes)? != block.merkle_root {
+        return Ok(Some("merkle root does not match its transactions"));
+    }
+    Ok(None)
 }
 
 fn hash(bytes: &[u8]) -> Vec<u8> {
@@ -92,3 +460,103 @@ fn hash(bytes: &[u8]) -> Vec<u8> {
     hasher.input(bytes);
     hasher.result().to_vec()
 }
+
+#[cfg(test)]
+mod tests {
+    use kcoin::Network;
+    use block;
+    use block::{Block, BlockWithTransactions};
+    use producer::Authority;
+    use storage::Error;
+    use testutil;
+    use testutil::FUNDS;
+
+    #[test]
+    fn block_skipping_a_nonce_is_rejected() {
+        let alice = testutil::keypair(10);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let bob = testutil::address(&testutil::keypair(11));
+        let authority = Authority::single(&producer.public_key());
+
+        for nonce in 0..2 {
+            testutil::send(&storage, &testutil::transfer(chain_id, &alice, &bob, 100, nonce)).unwrap();
+        }
+        let mined = testutil::mine(&storage, &producer);
+        assert_eq!(mined.txs.len(), 2);
+
+        let (peer, _) = testutil::chain(&[&alice]);
+        let copy = |txs| BlockWithTransactions {
+            block: Block {
+                height: mined.block.height,
+                hash: mined.block.hash.clone(),
+                prev_hash: mined.block.prev_hash.clone(),
+                merkle_root: mined.block.merkle_root.clone(),
+                state_root: mined.block.state_root.clone(),
+                time: mined.block.time,
+                signature: mined.block.signature.clone()
+            },
+            txs
+        };
+        let skipping = copy(vec![testutil::transfer(chain_id, &alice, &bob, 100, 1)]);
+        match block::apply(&peer, &skipping, &authority) {
+            Err(Error::InvalidBlock { reason, .. }) => assert_eq!(reason, "nonce does not follow the sender's last mined nonce"),
+            r => panic!("block was not rejected: {:?}", r)
+        }
+        assert_eq!(peer.block_height().unwrap(), 0);
+
+        let complete = copy((0..2).map(|nonce| testutil::transfer(chain_id, &alice, &bob, 100, nonce)).collect());
+        block::apply(&peer, &complete, &authority).unwrap();
+        assert_eq!(testutil::balance(&peer, &bob, "KCN"), 200);
+    }
+
+    #[test]
+    fn rollback_returns_what_is_still_valid() {
+        let alice = testutil::keypair(10);
+        let new_key = testutil::keypair(20);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let address = testutil::address(&alice);
+        let bob = testutil::address(&testutil::keypair(11));
+
+        let rotation = testutil::sign(chain_id, &alice, json!({
+            "type": "rotate_key",
+            "from": address.address,
+            "key": testutil::public_key(&new_key),
+            "fee": 1000,
+            "memo": "",
+            "nonce": 0
+        }));
+        testutil::send(&storage, &rotation).unwrap();
+        testutil::mine(&storage, &producer);
+        let transfer = testutil::sign(chain_id, &new_key, json!({
+            "from": address.address,
+            "to": bob.address,
+            "coin": "KCN",
+            "amount": 100,
+            "fee": 1000,
+            "memo": "",
+            "nonce": 1
+        }));
+        testutil::send(&storage, &transfer).unwrap();
+        testutil::mine(&storage, &producer);
+
+        assert!(block::rollback(&storage, 0, "tester", "test", true, &Network::Mainnet).is_err());
+        assert!(block::rollback(&storage, 3, "tester", "test", true, &Network::Mainnet).is_err());
+
+        // Without the rotation the transfer is signed with the wrong key
+        let rollback = block::rollback(&storage, 2, "tester", "test", true, &Network::Mainnet).unwrap();
+        assert_eq!((rollback.from_height, rollback.to_height), (2, 0));
+        assert_eq!(rollback.tx_hashes, vec![rotation.hash.clone(), transfer.hash.clone()]);
+        assert_eq!(rollback.dropped.len(), 1);
+        assert_eq!(rollback.dropped[0].hash, transfer.hash);
+        assert!(storage.mempool_exists(&rotation.hash).unwrap());
+        assert!(!storage.mempool_exists(&transfer.hash).unwrap());
+        assert!(storage.mempool_get_pruned(&transfer.hash).is_ok());
+
+        assert_eq!(storage.block_height().unwrap(), 0);
+        assert_eq!(testutil::balance(&storage, &address, "KCN"), FUNDS);
+        assert_eq!(testutil::balance(&storage, &bob, "KCN"), 0);
+        assert_eq!(storage.address_key(&address).unwrap().to_bytes(), alice.public.to_bytes());
+    }
+}
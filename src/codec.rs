@@ -0,0 +1,104 @@
+use std::io::Read;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "unexpected end of data")]
+    UnexpectedEnd,
+    #[fail(display = "invalid utf-8 string")]
+    InvalidString,
+    #[fail(display = "length {} too large", length)]
+    TooLong {
+        length: u32
+    },
+}
+
+// Length prefixes larger than this are treated as corrupt input instead of being allocated.
+const MAX_LENGTH: u32 = 16 * 1024 * 1024;
+
+// All integers are big endian. Byte strings are prefixed with their length as u32.
+
+pub fn put_u8(buf: &mut Vec<u8>, v: u8) {
+    buf.push(v);
+}
+
+pub fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    for i in (0..4).rev() {
+        buf.push((v >> (i * 8)) as u8);
+    }
+}
+
+pub fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    for i in (0..8).rev() {
+        buf.push((v >> (i * 8)) as u8);
+    }
+}
+
+pub fn put_i64(buf: &mut Vec<u8>, v: i64) {
+    put_u64(buf, v as u64);
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, v: &[u8]) {
+    put_u32(buf, v.len() as u32);
+    buf.extend_from_slice(v);
+}
+
+pub fn put_str(buf: &mut Vec<u8>, v: &str) {
+    put_bytes(buf, v.as_bytes());
+}
+
+/// Reads values written by the `put_*` functions from a stream.
+pub struct Reader<R: Read> {
+    inner: R
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Reader { inner }
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.inner.read_exact(buf).map_err(|_| Error::UnexpectedEnd)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        let mut buf = [0u8; 1];
+        self.fill(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let mut buf = [0u8; 4];
+        self.fill(&mut buf)?;
+        Ok(buf.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.fill(&mut buf)?;
+        Ok(buf.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, Error> {
+        Ok(self.u64()? as i64)
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.u32()?;
+        if length > MAX_LENGTH {
+            return Err(Error::TooLong { length });
+        }
+        let mut buf = vec![0u8; length as usize];
+        self.fill(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn string(&mut self) -> Result<String, Error> {
+        String::from_utf8(self.bytes()?).map_err(|_| Error::InvalidString)
+    }
+
+    /// Whether everything has been read. Consumes a byte if not.
+    pub fn at_end(&mut self) -> Result<bool, Error> {
+        let mut buf = [0u8; 1];
+        self.inner.read(&mut buf).map(|n| n == 0).map_err(|_| Error::UnexpectedEnd)
+    }
+}
@@ -1,4 +1,5 @@
 use jsonrpc_minihttp_server::jsonrpc_core::*;
+use storage::Restriction;
 
 
 
@@ -16,6 +17,26 @@ pub fn mempool_full_own_txs() -> Error { jsonrpc_error("Mempool is filled exclus
 pub fn insufficient_balance() -> Error { jsonrpc_error("Insufficient balance", -33012, None) }
 pub fn fee_too_low() -> Error { jsonrpc_error("Fee too low", -33013, None) }
 pub fn not_found() -> Error { jsonrpc_error("Not found", -33014, None) }
+pub fn tx_hash_mismatch(expected: &str) -> Error { jsonrpc_error("Transaction hash does not match its contents", -33015, Some(json!({"expected": expected}))) }
+pub fn read_only() -> Error { jsonrpc_error("Node is a read-only follower", -33016, None) }
+pub fn unauthorized() -> Error { jsonrpc_error("Invalid admin token", -33017, None) }
+pub fn block_rejected(reason: &str) -> Error { jsonrpc_error("Block rejected", -33018, Some(json!({"reason": reason}))) }
+pub fn wrong_chain(chain_id: &str) -> Error { jsonrpc_error("Transaction was signed for a different chain", -33019, Some(json!({"chain_id": chain_id}))) }
+pub fn tx_expired(height: u32) -> Error { jsonrpc_error("Transaction has expired", -33020, Some(json!({"height": height}))) }
+pub fn unknown_coin(coin: &str) -> Error { jsonrpc_error("Unknown coin", -33021, Some(json!({"coin": coin}))) }
+pub fn coin_exists(coin: &str) -> Error { jsonrpc_error("Coin exists already", -33022, Some(json!({"coin": coin}))) }
+pub fn not_issuer(issuer: &str) -> Error { jsonrpc_error("Only the issuer of the coin can do this", -33023, Some(json!({"issuer": issuer}))) }
+pub fn coin_restricted(restriction: &Restriction) -> Error {
+    match restriction {
+        Restriction::Paused { coin } => jsonrpc_error("Coin is paused", -33024, Some(json!({"coin": coin}))),
+        Restriction::Frozen { coin, address } => jsonrpc_error("Address is frozen for this coin", -33025, Some(json!({"coin": coin, "address": address})))
+    }
+}
+pub fn htlc_invalid(reason: &str) -> Error { jsonrpc_error("Invalid hash time lock transaction", -33026, Some(json!({"reason": reason}))) }
+pub fn order_invalid(reason: &str) -> Error { jsonrpc_error("Invalid order transaction", -33027, Some(json!({"reason": reason}))) }
+pub fn version_not_accepted(version: u8) -> Error { jsonrpc_error("Transaction version is not accepted", -33028, Some(json!({"version": version}))) }
+pub fn rollback_refused(reason: &str) -> Error { jsonrpc_error("Rollback refused", -33029, Some(json!({"reason": reason}))) }
+pub fn supply_exceeded(coin: &str) -> Error { jsonrpc_error("Mint would exceed the maximum supply of the coin", -33030, Some(json!({"coin": coin}))) }
 
 pub fn jsonrpc_error(message: &str, code: i64, data: Option<Value>) -> Error {
     Error {
@@ -0,0 +1,201 @@
+use std::cmp::Ordering;
+use rusqlite::Connection;
+use storage::{SqliteStorage, Error, Order, Fill};
+
+/// Settles the crossing orders of every pair after the transactions of the block at `height` are
+/// applied. Producers and followers run it alike, so everything here has to be deterministic.
+///
+/// Per pair the best offers of both sides are matched as long as they cross. A match happens at
+/// the rate of the older order, the maker, and fills as much as both can take. The maker gets
+/// what it asks rounded up, the taker never gets a worse rate than its own. If a match would
+/// round to nothing, the order that is too small sits out the rest of the block and the next one
+/// on its side gets matched instead. It stays open and may fill against a later counter order.
+pub fn match_orders_with_conn(storage: &SqliteStorage, conn: &Connection, height: u32) -> Result<(), Error> {
+    for (coin, want) in storage.exchange_get_pairs_with_conn(conn)? {
+        // Every pair is matched once, from the side of the lower ticker
+        if coin > want {
+            continue;
+        }
+        let mut asks = storage.exchange_get_tradable_orders_with_conn(conn, &coin, &want)?;
+        let mut bids = storage.exchange_get_tradable_orders_with_conn(conn, &want, &coin)?;
+        asks.sort_by(by_rate);
+        bids.sort_by(by_rate);
+
+        while !asks.is_empty() && !bids.is_empty() {
+            if !crosses(&asks[0], &bids[0]) {
+                break;
+            }
+            let ask_is_maker = (asks[0].height, asks[0].index) < (bids[0].height, bids[0].index);
+            let traded = {
+                let (maker, taker) = match ask_is_maker {
+                    true => (&asks[0], &bids[0]),
+                    false => (&bids[0], &asks[0])
+                };
+                trade(maker, taker).ok_or(limited_by_taker(maker, taker))
+            };
+            let (given, taken) = match traded {
+                Ok(t) => t,
+                Err(taker_limited) => {
+                    // The bid is the one too small if it's the taker and limits the match, or
+                    // the maker and doesn't
+                    if taker_limited == ask_is_maker {
+                        bids.remove(0);
+                    } else {
+                        asks.remove(0);
+                    }
+                    continue;
+                }
+            };
+            // The maker gives `given` of its coin and takes `taken` of the taker's coin
+            let (ask_gives, bid_gives) = match ask_is_maker {
+                true => (given, taken),
+                false => (taken, given)
+            };
+            settle(storage, conn, height, &mut asks[0], &bids[0].hash, ask_gives, bid_gives, ask_is_maker)?;
+            settle(storage, conn, height, &mut bids[0], &asks[0].hash, bid_gives, ask_gives, !ask_is_maker)?;
+            if asks[0].remaining() == 0 {
+                asks.remove(0);
+            }
+            if bids[0].remaining() == 0 {
+                bids.remove(0);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Best rate first, that is the least asked per unit offered. Equal rates go by age.
+pub fn by_rate(a: &Order, b: &Order) -> Ordering {
+    let a_rate = a.want_amount as u128 * b.amount as u128;
+    let b_rate = b.want_amount as u128 * a.amount as u128;
+    a_rate.cmp(&b_rate).then((a.height, a.index).cmp(&(b.height, b.index)))
+}
+
+/// Whether `ask` asks no more than `bid` offers, both at their own rate.
+fn crosses(ask: &Order, bid: &Order) -> bool {
+    ask.want_amount as u128 * bid.want_amount as u128 <= ask.amount as u128 * bid.amount as u128
+}
+
+/// How much the maker gives and takes when matched with the taker, at the maker's rate.
+fn trade(maker: &Order, taker: &Order) -> Option<(u64, u64)> {
+    let affordable = taker.remaining() as u128 * maker.amount as u128 / maker.want_amount as u128;
+    let given = (maker.remaining() as u128).min(affordable);
+    let taken = (given * maker.want_amount as u128 + maker.amount as u128 - 1) / maker.amount as u128;
+    if given == 0 || taken == 0 || taken > taker.remaining() as u128 {
+        return None;
+    }
+    // Rounding up for the maker must not push the taker below its own rate
+    if given * (taker.amount as u128) < taken * (taker.want_amount as u128) {
+        return None;
+    }
+    Some((given as u64, taken as u64))
+}
+
+/// Whether the taker can afford less than what is left of the maker, that is whether the taker is
+/// the smaller side of a match.
+fn limited_by_taker(maker: &Order, taker: &Order) -> bool {
+    (taker.remaining() as u128) * (maker.amount as u128) < (maker.remaining() as u128) * (maker.want_amount as u128)
+}
+
+/// Credits the owner of `order` with what it received, records the fill and closes the order
+/// once nothing is left of it.
+fn settle(storage: &SqliteStorage, conn: &Connection, height: u32, order: &mut Order, counter_hash: &str, gives: u64, receives: u64, maker: bool) -> Result<(), Error> {
+    storage.balance_add_with_conn(conn, &order.from, &order.want, receives)?;
+    storage.balance_history_record_with_conn(conn, height, &order.from, &order.want)?;
+    storage.fill_insert_with_conn(conn, &Fill {
+        height,
+        order_hash: order.hash.clone(),
+        counter_hash: counter_hash.to_owned(),
+        address: order.from.clone(),
+        coin: order.coin.clone(),
+        amount: gives,
+        want: order.want.clone(),
+        received: receives,
+        maker
+    })?;
+    order.filled = order.filled.saturating_add(gives);
+    order.received = order.received.saturating_add(receives);
+    if order.remaining() == 0 {
+        storage.order_close_with_conn(conn, height, &order.hash, "filled")?;
+        order.state = "filled".to_owned();
+        order.closed_height = height;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Keypair;
+    use storage::{SqliteStorage, Order};
+    use tx::TransactionEnvelope;
+    use testutil;
+    use testutil::FUNDS;
+
+    fn place(storage: &SqliteStorage, from: &Keypair, coin: &str, amount: u64, want: &str, want_amount: u64, nonce: u64) -> TransactionEnvelope {
+        let tx = testutil::sign(storage.genesis_hash(), from, json!({
+            "type": "place_order",
+            "from": testutil::address(from).address,
+            "coin": coin,
+            "amount": amount,
+            "want": want,
+            "want_amount": want_amount,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        }));
+        testutil::send(storage, &tx).unwrap();
+        tx
+    }
+
+    fn cancel(storage: &SqliteStorage, from: &Keypair, order: &str, nonce: u64) -> TransactionEnvelope {
+        testutil::sign(storage.genesis_hash(), from, json!({
+            "type": "cancel_order",
+            "from": testutil::address(from).address,
+            "order": order,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        }))
+    }
+
+    fn order(storage: &SqliteStorage, hash: &str) -> Order {
+        storage.order_get_with_conn(&storage.get_conn().unwrap(), hash).unwrap()
+    }
+
+    #[test]
+    fn crossing_orders_fill_at_the_makers_rate() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+
+        // Alice asks 2 USD per KCN
+        let ask = place(&storage, &alice, "KCN", 1000, "USD", 2000, 0);
+        testutil::mine(&storage, &producer);
+        // Bob offers 3 USD per KCN, and 1 USD per KCN in an order that doesn't cross
+        let bid = place(&storage, &bob, "USD", 3000, "KCN", 1000, 0);
+        let low = place(&storage, &bob, "USD", 1000, "KCN", 1000, 1);
+        testutil::mine(&storage, &producer);
+
+        let ask = order(&storage, &ask.hash);
+        assert_eq!((ask.state.as_str(), ask.filled, ask.received), ("filled", 1000, 2000));
+        let bid = order(&storage, &bid.hash);
+        assert_eq!((bid.state.as_str(), bid.filled, bid.received), ("open", 2000, 1000));
+        let low = order(&storage, &low.hash);
+        assert_eq!((low.state.as_str(), low.filled), ("open", 0));
+
+        // Only the owner can cancel, and only open orders
+        assert!(testutil::send(&storage, &cancel(&storage, &alice, &bid.hash, 1)).is_err());
+        assert!(testutil::send(&storage, &cancel(&storage, &alice, &ask.hash, 1)).is_err());
+        testutil::send(&storage, &cancel(&storage, &bob, &bid.hash, 2)).unwrap();
+        testutil::mine(&storage, &producer);
+        assert_eq!(order(&storage, &bid.hash).state, "cancelled");
+
+        let alice = testutil::address(&alice);
+        let bob = testutil::address(&bob);
+        assert_eq!(testutil::balance(&storage, &alice, "KCN"), FUNDS - 1000 - 1000);
+        assert_eq!(testutil::balance(&storage, &alice, "USD"), FUNDS + 2000);
+        assert_eq!(testutil::balance(&storage, &bob, "KCN"), FUNDS + 1000 - 3 * 1000);
+        // What the cancelled order didn't trade came back, the open one still holds its amount
+        assert_eq!(testutil::balance(&storage, &bob, "USD"), FUNDS - 2000 - 1000);
+    }
+}
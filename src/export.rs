@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use serde_json;
+use serde_json::Value;
+use storage;
+use storage::SqliteStorage;
+use kcoin::Network;
+use block;
+use block::{Block, BlockWithTransactions};
+use tx;
+use tx::TransactionEnvelope;
+use codec;
+use producer::Authority;
+
+/// Bumped whenever the layout of an export changes. Imports only read the current version.
+pub const VERSION: u32 = 1;
+
+// The first bytes of a binary export. JSON-lines exports start with `{`.
+const MAGIC: &[u8] = b"KCNX";
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "io error: {}", message)]
+    Io {
+        message: String
+    },
+    #[fail(display = "invalid export: {}", reason)]
+    Invalid {
+        reason: String
+    },
+    #[fail(display = "{}", error)]
+    Storage {
+        error: storage::Error
+    },
+}
+
+impl From<storage::Error> for Error {
+    fn from(error: storage::Error) -> Self {
+        Error::Storage { error }
+    }
+}
+
+impl From<codec::Error> for Error {
+    fn from(error: codec::Error) -> Self {
+        Error::Invalid { reason: error.to_string() }
+    }
+}
+
+impl From<::std::io::Error> for Error {
+    fn from(error: ::std::io::Error) -> Self {
+        Error::Io { message: error.to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Binary
+}
+
+/// Describes where an export comes from. Blocks can only be imported on top of the same genesis.
+/// Which keys signed them is up to the importing node, an export can't vouch for itself.
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    genesis_hash: String,
+    height: u32
+}
+
+/// Writes all blocks after genesis together with their transactions to `path`.
+pub fn export(storage: &SqliteStorage, network: &Network, path: &Path, format: Format) -> Result<u32, Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let header = Header {
+        version: VERSION,
+        genesis_hash: storage.genesis_hash().to_owned(),
+        height: storage.block_height()?
+    };
+
+    match format {
+        Format::JsonLines => {
+            writeln!(out, "{}", json!(header))?;
+        },
+        Format::Binary => {
+            let mut buf = MAGIC.to_vec();
+            codec::put_u32(&mut buf, header.version);
+            codec::put_str(&mut buf, &header.genesis_hash);
+            codec::put_u32(&mut buf, header.height);
+            out.write_all(&buf)?;
+        }
+    }
+
+    for height in 1..header.height + 1 {
+        let block = storage.block_get_by_height(height)?;
+        let txs: Vec<TransactionEnvelope> = storage
+            .chain_get_transactions(Some(height), None, None, None, u32::max_value(), network)?
+            .into_iter()
+            .map(|tx| tx.tx_envelope)
+            .collect();
+        match format {
+            Format::JsonLines => {
+                writeln!(out, "{}", block_to_json(&block, &txs))?;
+            },
+            Format::Binary => {
+                out.write_all(&block_to_bytes(&block, &txs))?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(header.height)
+}
+
+/// Reads an export in either format and applies its blocks on top of the genesis block.
+/// Every block and transaction is validated again, the balances are rebuilt from the transactions.
+/// Block signatures are checked against `authority`. The export has to hold exactly as many
+/// blocks as its header says, the blocks before a missing or surplus one stay imported.
+pub fn import(storage: &SqliteStorage, network: &Network, authority: &Authority, path: &Path) -> Result<u32, Error> {
+    if storage.block_height()? != 0 {
+        return Err(Error::Invalid { reason: "datadir already has blocks".to_owned() });
+    }
+
+    let mut input = BufReader::new(File::open(path)?);
+    let is_binary = input.fill_buf()?.starts_with(MAGIC);
+    let imported = match is_binary {
+        true => import_binary(storage, network, authority, input)?,
+        false => import_json_lines(storage, network, authority, input)?
+    };
+    Ok(imported)
+}
+
+fn check_header(storage: &SqliteStorage, header: &Header) -> Result<(), Error> {
+    if header.version != VERSION {
+        return Err(Error::Invalid { reason: format!("unsupported version {}", header.version) });
+    }
+    if header.genesis_hash != storage.genesis_hash() {
+        return Err(Error::Invalid { reason: "export was made from a different genesis".to_owned() });
+    }
+    Ok(())
+}
+
+fn import_json_lines<R: BufRead>(storage: &SqliteStorage, network: &Network, authority: &Authority, input: R) -> Result<u32, Error> {
+    let mut lines = input.lines();
+    let header_line = lines.next().ok_or(Error::Invalid { reason: "empty export".to_owned() })??;
+    let header: Header = serde_json::from_str(&header_line).map_err(|e| Error::Invalid { reason: e.to_string() })?;
+    check_header(storage, &header)?;
+
+    let mut imported = 0;
+    for line in lines {
+        if imported == header.height {
+            return Err(Error::Invalid { reason: format!("more blocks than the {} in the header", header.height) });
+        }
+        let value: Value = serde_json::from_str(&line?).map_err(|e| Error::Invalid { reason: e.to_string() })?;
+        let block = block_from_json(value, network, storage.genesis_hash())?;
+        block::apply(storage, &block, authority)?;
+        imported += 1;
+    }
+    if imported != header.height {
+        return Err(Error::Invalid { reason: format!("{} blocks instead of the {} in the header", imported, header.height) });
+    }
+    Ok(imported)
+}
+
+fn import_binary<R: Read>(storage: &SqliteStorage, network: &Network, authority: &Authority, input: R) -> Result<u32, Error> {
+    let mut reader = codec::Reader::new(input);
+    for _ in 0..MAGIC.len() {
+        reader.u8()?;
+    }
+    let header = Header {
+        version: reader.u32()?,
+        genesis_hash: reader.string()?,
+        height: reader.u32()?
+    };
+    check_header(storage, &header)?;
+
+    for _ in 0..header.height {
+        let block = block_from_reader(&mut reader, network, storage.genesis_hash())?;
+        block::apply(storage, &block, authority)?;
+    }
+    if !reader.at_end()? {
+        return Err(Error::Invalid { reason: format!("more blocks than the {} in the header", header.height) });
+    }
+    Ok(header.height)
+}
+
+pub fn block_to_json(block: &Block, txs: &[TransactionEnvelope]) -> Value {
+    json!({
+        "height": block.height,
+        "hash": block.hash,
+        "prev_hash": block.prev_hash,
+        "merkle_root": block.merkle_root,
+        "state_root": block.state_root,
+        "time": block.time,
+        "signature": block.signature,
+        "txs": txs
+    })
+}
+
+/// Parses a block as written by `block_to_json`. Transactions go through the same validation as
+/// transactions sent to the node, except for their signatures. Those are checked when the block
+/// is applied, against the keys at that point of the chain.
+pub fn block_from_json(value: Value, network: &Network, chain_id: &str) -> Result<BlockWithTransactions, Error> {
+    let invalid = |field: &str| Error::Invalid { reason: format!("invalid block field {}", field) };
+    let field_str = |field: &str| value.get(field).and_then(|v| v.as_str()).map(|v| v.to_owned()).ok_or(invalid(field));
+    let block = Block {
+        height: value.get("height").and_then(|v| v.as_u64()).filter(|h| *h <= u32::max_value() as u64).ok_or(invalid("height"))? as u32,
+        hash: field_str("hash")?,
+        prev_hash: field_str("prev_hash")?,
+        merkle_root: field_str("merkle_root")?,
+        state_root: field_str("state_root")?,
+        time: value.get("time").and_then(|v| v.as_i64()).ok_or(invalid("time"))?,
+        signature: field_str("signature")?
+    };
+
+    let mut txs = Vec::new();
+    for tx in value.get("txs").and_then(|v| v.as_array()).ok_or(invalid("txs"))?.iter() {
+        txs.push(envelope_from_json(tx.clone(), network, chain_id)?);
+    }
+    Ok(BlockWithTransactions { block, txs })
+}
+
+fn envelope_from_json(value: Value, network: &Network, chain_id: &str) -> Result<TransactionEnvelope, Error> {
+    let seen = value.get("seen").and_then(|v| v.as_i64());
+    let map = match value {
+        Value::Object(m) => m,
+        _ => return Err(Error::Invalid { reason: "transaction is not an object".to_owned() })
+    };
+    let mut envelope = TransactionEnvelope::from_json(map, network, chain_id)
+        .map_err(|e| Error::Invalid { reason: e.to_string() })?;
+    if let Some(seen) = seen {
+        envelope.seen = seen;
+    }
+    Ok(envelope)
+}
+
+fn block_to_bytes(block: &Block, txs: &[TransactionEnvelope]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    codec::put_u32(&mut buf, block.height);
+    codec::put_str(&mut buf, &block.hash);
+    codec::put_str(&mut buf, &block.prev_hash);
+    codec::put_str(&mut buf, &block.merkle_root);
+    codec::put_str(&mut buf, &block.state_root);
+    codec::put_i64(&mut buf, block.time);
+    codec::put_str(&mut buf, &block.signature);
+    codec::put_u32(&mut buf, txs.len() as u32);
+    for envelope in txs.iter() {
+        codec::put_str(&mut buf, &envelope.hash);
+        codec::put_str(&mut buf, &envelope.signature);
+        // Single key transactions write an empty string
+        match envelope.multisig {
+            Some(ref multisig) => codec::put_str(&mut buf, &json!(multisig).to_string()),
+            None => codec::put_str(&mut buf, "")
+        }
+        // As does a transaction without a fee payer
+        codec::put_str(&mut buf, envelope.fee_payer_signature.as_ref().map_or("", |s| s.as_str()));
+        codec::put_i64(&mut buf, envelope.seen);
+        buf.extend_from_slice(&envelope.tx.encode(envelope.version));
+    }
+    buf
+}
+
+fn block_from_reader<R: Read>(reader: &mut codec::Reader<R>, network: &Network, chain_id: &str) -> Result<BlockWithTransactions, Error> {
+    let block = Block {
+        height: reader.u32()?,
+        hash: reader.string()?,
+        prev_hash: reader.string()?,
+        merkle_root: reader.string()?,
+        state_root: reader.string()?,
+        time: reader.i64()?,
+        signature: reader.string()?
+    };
+
+    let count = reader.u32()?;
+    let mut txs = Vec::new();
+    for _ in 0..count {
+        let hash = reader.string()?;
+        let signature = reader.string()?;
+        let multisig = reader.string()?;
+        let fee_payer_signature = reader.string()?;
+        let seen = reader.i64()?;
+        let (version, tx_value) = tx::Transaction::read_json(reader)?;
+        // Rebuild the JSON form so binary imports go through exactly the same validation.
+        let mut value = json!({
+            "version": version,
+            "hash": hash,
+            "signature": signature,
+            "seen": seen,
+            "tx": tx_value
+        });
+        if !multisig.is_empty() {
+            value["multisig"] = serde_json::from_str::<Value>(&multisig).map_err(|e| Error::Invalid { reason: e.to_string() })?;
+        }
+        if !fee_payer_signature.is_empty() {
+            value["fee_payer_signature"] = json!(fee_payer_signature);
+        }
+        txs.push(envelope_from_json(value, network, chain_id)?);
+    }
+    Ok(BlockWithTransactions { block, txs })
+}
@@ -0,0 +1,269 @@
+use std::thread;
+use std::time::Duration;
+use time;
+use storage::SqliteStorage;
+use kcoin::Network;
+use rpcclient::RpcClient;
+use producer::{Producer, Authority};
+use block;
+use export;
+use follower;
+
+/// Produces blocks together with the other nodes of the authority set.
+///
+/// Every node polls its peers for blocks it doesn't have yet and makes a block itself whenever it
+/// is its turn. New blocks are pushed to all peers with `chain_proposeBlock`. Peers that can't be
+/// reached are skipped, the node catches up with them once they are back. A late block and the
+/// block of the producer that took over compete for the same height, every node keeps the one
+/// `block::prefers` and replaces the other.
+pub struct Federation {
+    storage: SqliteStorage,
+    network: Network,
+    authority: Authority,
+    producer: Producer,
+    peers: Vec<RpcClient>,
+    block_size: u64,
+    block_time: i64
+}
+
+impl Federation {
+    pub fn new(storage: SqliteStorage, network: Network, authority: Authority, producer: Producer, peers: Vec<RpcClient>, block_size: u64, block_time: i64) -> Self {
+        Federation { storage, network, authority, producer, peers, block_size, block_time }
+    }
+
+    pub fn run(&self, poll_interval: Duration) {
+        if !self.authority.keys().contains(&self.producer.public_key()) {
+            info!("Producer key is not part of the authority set. Only syncing from peers.");
+        }
+        loop {
+            self.sync();
+            if let Err(e) = self.produce() {
+                info!("Error during block production: {}", e);
+            }
+            thread::sleep(poll_interval);
+        }
+    }
+
+    fn sync(&self) {
+        for peer in self.peers.iter() {
+            match follower::sync_from(&self.storage, &self.network, peer, &self.authority) {
+                Ok(0) => {},
+                Ok(n) => info!("Synced {} blocks from peer", n),
+                Err(e) => debug!("Cannot sync from peer: {}", e)
+            }
+        }
+    }
+
+    /// Makes and broadcasts a block if it is this node's turn, at least `block_time` seconds
+    /// passed since the last block and there are transactions waiting.
+    fn produce(&self) -> Result<(), ::storage::Error> {
+        let tip = self.storage.block_get_by_height(self.storage.block_height()?)?;
+        let now = time::get_time().sec;
+        if now < tip.time + self.block_time {
+            return Ok(());
+        }
+        if self.authority.expected_signer(tip.height + 1, tip.time, now) != Some(self.producer.public_key().as_str()) {
+            return Ok(());
+        }
+
+        if let Some(block) = block::generate_at(&self.storage, self.block_size, &self.network, &self.producer, &self.authority, now)? {
+            let proposal = json!({"block": export::block_to_json(&block.block, &block.txs)});
+            for peer in self.peers.iter() {
+                if let Err(e) = peer.call("chain_proposeBlock", proposal.clone()) {
+                    info!("Peer did not accept block {}: {}", block.block.height, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+    use ed25519_dalek::Keypair;
+    use kcoin::Network;
+    use storage::SqliteStorage;
+    use producer::{Producer, Authority};
+    use block;
+    use block::BlockWithTransactions;
+    use genesis::Genesis;
+    use testutil;
+
+    struct Setup {
+        genesis: Genesis,
+        producers: Vec<Producer>,
+        authority: Authority,
+        alice: Keypair,
+        bob: Keypair
+    }
+
+    // Three producers with a timeout of 30 seconds. Height 1 is the turn of the second one. The
+    // genesis is old enough that blocks of later turns aren't ahead of the clock.
+    fn setup() -> Setup {
+        let producers: Vec<Producer> = (1..4).map(testutil::producer).collect();
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let genesis = testutil::genesis(
+            &[&testutil::address(&alice), &testutil::address(&bob)],
+            1000000000,
+            &producers.iter().collect::<Vec<&Producer>>(),
+            30,
+            testutil::now() - 600
+        );
+        let authority = Authority::new(genesis.producers.clone(), genesis.producer_timeout);
+        Setup { genesis, producers, authority, alice, bob }
+    }
+
+    fn make(storage: &SqliteStorage, producer: &Producer, authority: &Authority, time: i64) -> BlockWithTransactions {
+        block::generate_at(storage, 100, &Network::Mainnet, producer, authority, time).unwrap().unwrap()
+    }
+
+    fn tip(storage: &SqliteStorage) -> String {
+        storage.block_get_by_height(storage.block_height().unwrap()).unwrap().hash
+    }
+
+    #[test]
+    fn producers_take_turns() {
+        let s = setup();
+        let nodes: Vec<SqliteStorage> = (0..3).map(|_| testutil::storage(&s.genesis)).collect();
+        let chain_id = s.genesis.hash.clone();
+        let bob = testutil::address(&s.bob);
+
+        for height in 1..4u32 {
+            let time = s.genesis.timestamp + height as i64;
+            let tx = testutil::transfer(&chain_id, &s.alice, &bob, 1000, height as u64 - 1);
+            let on_turn = height as usize % 3;
+            let off_turn = (on_turn + 1) % 3;
+
+            testutil::mempool_add(&nodes[off_turn], &tx);
+            assert!(block::generate_at(&nodes[off_turn], 100, &Network::Mainnet, &s.producers[off_turn], &s.authority, time).is_err());
+
+            testutil::mempool_add(&nodes[on_turn], &tx);
+            let mut block = make(&nodes[on_turn], &s.producers[on_turn], &s.authority, time);
+            assert_eq!(block.block.height, height);
+            assert_eq!(block.txs.len(), 1);
+
+            for i in (0..3).filter(|i| *i != on_turn) {
+                let signature = block.block.signature.clone();
+                block.block.signature = s.producers[i].sign(&block.block.hash).unwrap();
+                assert!(block::apply(&nodes[i], &block, &s.authority).is_err());
+                block.block.signature = signature;
+
+                block::apply(&nodes[i], &block, &s.authority).unwrap();
+                assert!(block::apply(&nodes[i], &block, &s.authority).is_err());
+            }
+            assert!(!nodes[off_turn].mempool_exists(&tx.hash).unwrap());
+        }
+
+        for node in nodes.iter() {
+            assert_eq!(node.block_height().unwrap(), 3);
+            assert_eq!(tip(node), tip(&nodes[0]));
+        }
+    }
+
+    #[test]
+    fn next_producer_takes_over_after_timeout() {
+        let s = setup();
+        let nodes: Vec<SqliteStorage> = (0..3).map(|_| testutil::storage(&s.genesis)).collect();
+        let bob = testutil::address(&s.bob);
+        let tx = testutil::transfer(&s.genesis.hash, &s.alice, &bob, 1000, 0);
+        for node in nodes.iter() {
+            testutil::mempool_add(node, &tx);
+        }
+
+        let time = s.genesis.timestamp + 31;
+        assert!(block::generate_at(&nodes[1], 100, &Network::Mainnet, &s.producers[1], &s.authority, time).is_err());
+        let block = make(&nodes[2], &s.producers[2], &s.authority, time);
+        block::apply(&nodes[0], &block, &s.authority).unwrap();
+        block::apply(&nodes[1], &block, &s.authority).unwrap();
+
+        // One turn after it, height 2 is the first producer's
+        let tx = testutil::transfer(&s.genesis.hash, &s.alice, &bob, 1000, 1);
+        testutil::mempool_add(&nodes[0], &tx);
+        let block = make(&nodes[0], &s.producers[0], &s.authority, time + 31);
+        block::apply(&nodes[1], &block, &s.authority).unwrap();
+        block::apply(&nodes[2], &block, &s.authority).unwrap();
+        for node in nodes.iter() {
+            assert_eq!(tip(node), block.block.hash);
+        }
+    }
+
+    #[test]
+    fn block_ahead_of_the_clock_is_rejected() {
+        let s = setup();
+        let producer = &s.producers[0];
+        let genesis = testutil::genesis(&[&testutil::address(&s.alice)], 1000000000, &[producer, &s.producers[1]], 30, testutil::now());
+        let authority = Authority::new(genesis.producers.clone(), genesis.producer_timeout);
+        let nodes: Vec<SqliteStorage> = (0..2).map(|_| testutil::storage(&genesis)).collect();
+        let tx = testutil::transfer(&genesis.hash, &s.alice, &testutil::address(&s.bob), 1000, 0);
+        testutil::mempool_add(&nodes[0], &tx);
+
+        // Height 1 is the second producer's, the first one dates its block into the next turn
+        let block = make(&nodes[0], producer, &authority, genesis.timestamp + 45);
+        assert!(block::apply(&nodes[1], &block, &authority).is_err());
+        assert_eq!(nodes[1].block_height().unwrap(), 0);
+    }
+
+    #[test]
+    fn earlier_turn_wins_the_fork() {
+        let s = setup();
+        let nodes: Vec<SqliteStorage> = (0..3).map(|_| testutil::storage(&s.genesis)).collect();
+        let alice = testutil::address(&s.alice);
+        let bob = testutil::address(&s.bob);
+        let on_time_tx = testutil::transfer(&s.genesis.hash, &s.alice, &bob, 1000, 0);
+        let takeover_tx = testutil::transfer(&s.genesis.hash, &s.bob, &alice, 1000, 0);
+
+        // The second producer's block reaches nobody before the third one takes over
+        testutil::mempool_add(&nodes[1], &on_time_tx);
+        let on_time = make(&nodes[1], &s.producers[1], &s.authority, s.genesis.timestamp + 1);
+        testutil::mempool_add(&nodes[2], &takeover_tx);
+        let takeover = make(&nodes[2], &s.producers[2], &s.authority, s.genesis.timestamp + 31);
+
+        block::apply(&nodes[0], &takeover, &s.authority).unwrap();
+        let rollback = block::reorg(&nodes[0], 0, slice::from_ref(&on_time), &s.authority, &Network::Mainnet).unwrap();
+        assert_eq!(rollback.tx_hashes, vec![takeover_tx.hash.clone()]);
+        assert!(nodes[0].mempool_exists(&takeover_tx.hash).unwrap());
+
+        assert!(block::reorg(&nodes[1], 0, slice::from_ref(&takeover), &s.authority, &Network::Mainnet).is_err());
+        assert_eq!(tip(&nodes[1]), on_time.block.hash);
+
+        block::reorg(&nodes[2], 0, slice::from_ref(&on_time), &s.authority, &Network::Mainnet).unwrap();
+        assert!(nodes[2].mempool_exists(&takeover_tx.hash).unwrap());
+        for node in nodes.iter() {
+            assert_eq!(tip(node), on_time.block.hash);
+        }
+
+        // The replaced transaction makes it into the next block
+        let next = make(&nodes[2], &s.producers[2], &s.authority, s.genesis.timestamp + 2);
+        assert_eq!(next.txs[0].hash, takeover_tx.hash);
+        block::apply(&nodes[0], &next, &s.authority).unwrap();
+        block::apply(&nodes[1], &next, &s.authority).unwrap();
+        for node in nodes.iter() {
+            assert_eq!(tip(node), next.block.hash);
+        }
+    }
+
+    #[test]
+    fn lower_hash_wins_within_a_turn() {
+        let s = setup();
+        let nodes: Vec<SqliteStorage> = (0..4).map(|_| testutil::storage(&s.genesis)).collect();
+        let alice = testutil::address(&s.alice);
+        let bob = testutil::address(&s.bob);
+
+        // The second producer signs two blocks in its turn
+        testutil::mempool_add(&nodes[0], &testutil::transfer(&s.genesis.hash, &s.alice, &bob, 1000, 0));
+        let first = make(&nodes[0], &s.producers[1], &s.authority, s.genesis.timestamp + 1);
+        testutil::mempool_add(&nodes[1], &testutil::transfer(&s.genesis.hash, &s.bob, &alice, 1000, 0));
+        let second = make(&nodes[1], &s.producers[1], &s.authority, s.genesis.timestamp + 2);
+        let winner = if first.block.hash < second.block.hash { &first } else { &second };
+
+        block::apply(&nodes[2], &first, &s.authority).unwrap();
+        block::apply(&nodes[3], &second, &s.authority).unwrap();
+        for (node, other) in vec![(&nodes[0], &second), (&nodes[1], &first), (&nodes[2], &second), (&nodes[3], &first)] {
+            let switched = block::reorg(node, 0, slice::from_ref(other), &s.authority, &Network::Mainnet).is_ok();
+            assert_eq!(switched, other.block.hash == winner.block.hash);
+            assert_eq!(tip(node), winner.block.hash);
+        }
+    }
+}
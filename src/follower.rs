@@ -0,0 +1,133 @@
+use std::cmp;
+use std::thread;
+use std::time;
+use serde_json::Value;
+use storage::SqliteStorage;
+use kcoin::Network;
+use rpcclient::RpcClient;
+use producer::Authority;
+use block;
+use export;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "primary error: {}", message)]
+    Primary {
+        message: String
+    },
+    #[fail(display = "primary was started from a different genesis")]
+    GenesisMismatch,
+    #[fail(display = "{}", message)]
+    Apply {
+        message: String
+    },
+}
+
+/// Keeps a local copy of the chain of a primary node without producing blocks.
+pub struct Follower {
+    storage: SqliteStorage,
+    network: Network,
+    primary: RpcClient,
+    authority: Authority
+}
+
+impl Follower {
+    /// Connects to the primary and makes sure both nodes were started from the same genesis.
+    /// Blocks are checked against `authority`, which comes from the local configuration. Nothing
+    /// the primary says about its keys is trusted.
+    pub fn new(storage: SqliteStorage, network: Network, primary: RpcClient, authority: Authority) -> Result<Self, Error> {
+        let primary_genesis = fetch_block(&primary, &network, storage.genesis_hash(), 0)?;
+        if primary_genesis.block.prev_hash != storage.genesis_hash() {
+            return Err(Error::GenesisMismatch);
+        }
+        Ok(Follower { storage, network, primary, authority })
+    }
+
+    /// The keys blocks of the primary get checked against.
+    pub fn authority(&self) -> &Authority {
+        &self.authority
+    }
+
+    /// Polls the primary forever, applying new blocks as they show up.
+    pub fn run(&self, poll_interval: time::Duration) {
+        loop {
+            match self.sync() {
+                Ok(0) => {},
+                Ok(n) => info!("Synced {} blocks from primary", n),
+                Err(e) => info!("Error while following primary: {}", e)
+            }
+            thread::sleep(poll_interval);
+        }
+    }
+
+    /// Applies every block the primary has and this node doesn't.
+    pub fn sync(&self) -> Result<u32, Error> {
+        sync_from(&self.storage, &self.network, &self.primary, &self.authority)
+    }
+}
+
+/// Applies every block `node` has and the local chain doesn't. Each block gets validated with the
+/// same code that applies transactions when making a block. If the chains forked, the local one
+/// switches to the branch of `node` when that wins the fork choice, see `block::prefers`.
+/// Otherwise `node` is expected to switch when it syncs from this node.
+pub fn sync_from(storage: &SqliteStorage, network: &Network, node: &RpcClient, authority: &Authority) -> Result<u32, Error> {
+    let apply_error = |e: ::storage::Error| Error::Apply { message: e.to_string() };
+    let remote_height = node.call("chain_getHeight", json!({}))
+        .map_err(|e| Error::Primary { message: e.to_string() })?
+        .get("height").and_then(|v| v.as_u64())
+        .ok_or(Error::Primary { message: "invalid height".to_owned() })? as u32;
+    let local_height = storage.block_height().map_err(apply_error)?;
+
+    // Walk back to the last block both chains have
+    let mut fork_height = cmp::min(local_height, remote_height);
+    while fork_height > 0 {
+        let local = storage.block_get_by_height(fork_height).map_err(apply_error)?;
+        if fetch_block(node, network, storage.genesis_hash(), fork_height)?.block.hash == local.hash {
+            break;
+        }
+        if local_height - fork_height >= block::MAX_REORG_DEPTH {
+            return Err(Error::Apply { message: format!("no common block within the last {} blocks", block::MAX_REORG_DEPTH) });
+        }
+        fork_height -= 1;
+    }
+
+    if fork_height < local_height {
+        if fork_height == remote_height {
+            return Ok(0);
+        }
+        let first = fetch_block(node, network, storage.genesis_hash(), fork_height + 1)?;
+        let parent = storage.block_get_by_height(fork_height).map_err(apply_error)?;
+        let current = storage.block_get_by_height(fork_height + 1).map_err(apply_error)?;
+        if !block::prefers(&first.block, &current, parent.time, authority) {
+            return Ok(0);
+        }
+        let mut branch = vec![first];
+        for height in fork_height + 2..remote_height + 1 {
+            branch.push(fetch_block(node, network, storage.genesis_hash(), height)?);
+        }
+        block::reorg(storage, fork_height, &branch, authority, network).map_err(apply_error)?;
+        return Ok(branch.len() as u32);
+    }
+
+    let mut synced = 0;
+    for height in local_height + 1..remote_height + 1 {
+        let block = fetch_block(node, network, storage.genesis_hash(), height)?;
+        block::apply(storage, &block, authority).map_err(apply_error)?;
+        synced += 1;
+    }
+    Ok(synced)
+}
+
+fn fetch_block(node: &RpcClient, network: &Network, chain_id: &str, height: u32) -> Result<block::BlockWithTransactions, Error> {
+    let mut value = node.call("chain_getBlockByHeight", json!({"height": height}))
+        .map_err(|e| Error::Primary { message: e.to_string() })?;
+
+    // chain_getBlockByHeight wraps every envelope together with its position in the block.
+    let envelopes: Vec<Value> = match value.get("txs").and_then(|v| v.as_array()) {
+        Some(txs) => txs.iter().filter_map(|tx| tx.get("tx_envelope").cloned()).collect(),
+        None => return Err(Error::Primary { message: "block without txs".to_owned() })
+    };
+    value["txs"] = json!(envelopes);
+
+    export::block_from_json(value, network, chain_id).map_err(|e| Error::Primary { message: e.to_string() })
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::path::Path;
+use std::collections::HashMap;
+use sha2::{Sha256, Digest};
+use serde_json;
+use hex;
+use regex::Regex;
+use ed25519_dalek::PublicKey;
+use kcoin::{Bech32Address, Network};
+use block::MAX_CLOCK_DRIFT;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "cannot read genesis file: {}", message)]
+    CannotRead {
+        message: String
+    },
+    #[fail(display = "invalid genesis: {}", reason)]
+    Invalid {
+        reason: String
+    },
+}
+
+/// Initial balance of an address. Amounts are in base units, 1 KCN = 100000000.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Allocation {
+    pub address: String,
+    pub coin: String,
+    pub amount: u64
+}
+
+/// Layout of the genesis file:
+///
+/// ```json
+/// {
+///     "network": "kcn",
+///     "timestamp": 1540000000,
+///     "fee_address": "kcn1...",
+///     "allocations": [
+///         {"address": "kcn1...", "coin": "KCN", "amount": 10000000000000000},
+///         {"address": "kcn1...", "coin": "USD", "amount": 500000000}
+///     ],
+///     "producers": ["<hex ed25519 public key>", "<hex ed25519 public key>"],
+///     "producer_timeout": 30
+/// }
+/// ```
+///
+/// Every coin that shows up in `allocations` exists from the start. `producers` is optional. If
+/// it is set, only these keys may sign blocks and they take turns, see `producer::Authority`.
+/// `producer_timeout` is how many seconds a producer gets before the next one takes over. It has
+/// to be longer than blocks may be ahead of the clock, or a producer could take the next turn by
+/// dating its block ahead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GenesisFile {
+    network: String,
+    timestamp: i64,
+    fee_address: String,
+    allocations: Vec<Allocation>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    producers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    producer_timeout: Option<u64>
+}
+
+const DEFAULT_PRODUCER_TIMEOUT: u64 = 30;
+
+#[derive(Debug, Clone)]
+pub struct Genesis {
+    /// SHA-256 of the genesis file. The genesis block commits to it.
+    pub hash: String,
+    pub timestamp: i64,
+    /// Receives all transaction fees.
+    pub fee_address: Bech32Address,
+    pub allocations: Vec<Allocation>,
+    /// Hex encoded public keys of the producers. Empty if a single producer signs every block.
+    pub producers: Vec<String>,
+    pub producer_timeout: i64
+}
+
+impl Genesis {
+    pub fn from_file(path: &Path, network: &Network) -> Result<Self, Error> {
+        let bytes = fs::read(path).map_err(|e| Error::CannotRead { message: e.to_string() })?;
+        Genesis::from_bytes(&bytes, network)
+    }
+
+    /// Builds the genesis nodes used before there were genesis files: the whole KCN supply goes to
+    /// one address which also receives the fees.
+    pub fn from_initial_supply(kcn_address: &Bech32Address, kcn_supply: u64, network: &Network) -> Result<Self, Error> {
+        let file = GenesisFile {
+            network: network.prefix(),
+            timestamp: 0,
+            fee_address: kcn_address.address.clone(),
+            allocations: vec![Allocation {
+                address: kcn_address.address.clone(),
+                coin: "KCN".to_owned(),
+                amount: kcn_supply.checked_mul(100000000).ok_or(Error::Invalid { reason: "supply too large".to_owned() })?
+            }],
+            producers: Vec::new(),
+            producer_timeout: None
+        };
+        let bytes = serde_json::to_vec(&file).map_err(|e| Error::Invalid { reason: e.to_string() })?;
+        Genesis::from_bytes(&bytes, network)
+    }
+
+    pub fn from_bytes(bytes: &[u8], network: &Network) -> Result<Self, Error> {
+        lazy_static! {
+            static ref coin_regex: Regex = Regex::new(r"^[A-Z]{3,4}$").unwrap();
+        }
+        let file: GenesisFile = serde_json::from_slice(bytes).map_err(|e| Error::Invalid { reason: e.to_string() })?;
+        if file.network != network.prefix() {
+            return Err(Error::Invalid { reason: format!("genesis is for network {} but node runs {}", file.network, network.prefix()) });
+        }
+        let fee_address = Bech32Address::new(&file.fee_address, network.clone())
+            .map_err(|_| Error::Invalid { reason: format!("invalid fee_address {}", file.fee_address) })?;
+
+        let mut supplies: HashMap<&str, u64> = HashMap::new();
+        for allocation in file.allocations.iter() {
+            Bech32Address::new(&allocation.address, network.clone())
+                .map_err(|_| Error::Invalid { reason: format!("invalid address {}", allocation.address) })?;
+            if !coin_regex.is_match(&allocation.coin) {
+                return Err(Error::Invalid { reason: format!("invalid coin {}", allocation.coin) });
+            }
+            if allocation.amount == 0 {
+                return Err(Error::Invalid { reason: format!("zero allocation for {}", allocation.address) });
+            }
+            let supply = supplies.entry(allocation.coin.as_str()).or_insert(0);
+            *supply = supply.checked_add(allocation.amount)
+                .filter(|s| *s <= i64::max_value() as u64)
+                .ok_or(Error::Invalid { reason: format!("supply of {} too large", allocation.coin) })?;
+        }
+        if !supplies.contains_key("KCN") {
+            return Err(Error::Invalid { reason: "no KCN allocated".to_owned() });
+        }
+
+        for (i, producer) in file.producers.iter().enumerate() {
+            hex::decode(producer).ok().and_then(|b| PublicKey::from_bytes(&b).ok())
+                .ok_or(Error::Invalid { reason: format!("invalid producer key {}", producer) })?;
+            if file.producers[..i].contains(producer) {
+                return Err(Error::Invalid { reason: format!("duplicate producer key {}", producer) });
+            }
+        }
+        let producer_timeout = file.producer_timeout.unwrap_or(DEFAULT_PRODUCER_TIMEOUT);
+        if producer_timeout == 0 || producer_timeout > i64::max_value() as u64 {
+            return Err(Error::Invalid { reason: "invalid producer_timeout".to_owned() });
+        }
+        if !file.producers.is_empty() && producer_timeout <= MAX_CLOCK_DRIFT as u64 {
+            return Err(Error::Invalid { reason: format!("producer_timeout has to be longer than {} seconds", MAX_CLOCK_DRIFT) });
+        }
+
+        let mut hasher = Sha256::default();
+        hasher.input(bytes);
+        Ok(Genesis {
+            hash: hex::encode(hasher.result()),
+            timestamp: file.timestamp,
+            fee_address,
+            allocations: file.allocations,
+            producers: file.producers,
+            producer_timeout: producer_timeout as i64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kcoin::Network;
+    use genesis::Genesis;
+    use producer::Producer;
+    use testutil;
+
+    #[test]
+    fn producer_timeout_has_to_exceed_clock_drift() {
+        let owner = testutil::address(&testutil::keypair(10));
+        let producers = [testutil::producer(1), testutil::producer(2)];
+        let parse = |producers: &[&Producer], timeout: u64| {
+            Genesis::from_bytes(testutil::genesis_json(&[&owner], 1000, producers, timeout, 0).as_bytes(), &Network::Mainnet)
+        };
+        assert!(parse(&[&producers[0], &producers[1]], 15).is_err());
+        assert!(parse(&[&producers[0], &producers[1]], 16).is_ok());
+        // A single producer has no turns to take
+        assert!(parse(&[], 15).is_ok());
+    }
+}
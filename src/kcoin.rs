@@ -1,6 +1,6 @@
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::env;
 use log::LevelFilter;
 use env_logger::{Builder, Target};
@@ -11,16 +11,29 @@ use serde_json;
 use std::num::ParseIntError;
 use bech32::{Bech32, convert_bits};
 use ed25519_dalek::PublicKey;
+use hex;
 use std::thread;
 use std::time;
 use jsonrpc_minihttp_server::{ServerBuilder, DomainsValidation};
 use jsonrpc_minihttp_server::jsonrpc_core::{Params, Value, IoHandler, Compatibility, Error};
 use jsonrpc_minihttp_server::cors::AccessControlAllowOrigin;
 use block;
+use audit;
+use errors;
+use rpcclient::RpcClient;
+use follower::Follower;
+use federation::Federation;
+use export;
+use genesis::Genesis;
+use std::fs;
+use producer::{Producer, Authority};
 use serde::{Serialize, Serializer};
 
 pub const NEW_COIN_FEE: u64 = 1000000000;
 
+/// Decimals of KCN. Coins from the genesis and coins created before the coin registry have them too.
+pub const DEFAULT_DECIMALS: u8 = 8;
+
 #[derive(Debug, Fail)]
 pub enum KCoinError {
     #[fail(display = "invalid address")]
@@ -31,6 +44,11 @@ pub enum KCoinError {
     InvalidArgument {
         argument: String,
         reason: String
+    },
+    #[fail(display = "{} failed: {}", command, reason)]
+    CommandFailed {
+        command: String,
+        reason: String
     }
 }
 
@@ -41,12 +59,17 @@ pub enum Network {
 }
 
 impl Network {
-    fn prefix(&self) -> String {
+    pub fn prefix(&self) -> String {
         match self {
             Network::Mainnet => "kcn".to_owned(),
             Network::Regtest => "ktest".to_owned()
         }
     }
+
+    /// The hrp of multisig addresses, see `multisig::Policy`.
+    pub fn multisig_prefix(&self) -> String {
+        self.prefix() + "m"
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -78,12 +101,20 @@ impl Bech32Address {
         let c = self.address.parse::<Bech32>();
         match c {
             Ok(address) => {
-                address.hrp() == self.network.prefix()
+                address.hrp() == self.network.prefix() || address.hrp() == self.network.multisig_prefix()
             },
             Err(_) => false
         }
     }
 
+    /// Multisig addresses have no single public key, their transactions carry a `multisig::Multisig`.
+    pub fn is_multisig(&self) -> bool {
+        match self.address.parse::<Bech32>() {
+            Ok(address) => address.hrp() == self.network.multisig_prefix(),
+            Err(_) => false
+        }
+    }
+
     pub fn public_key(&self) -> Result<PublicKey, KCoinError> {
         let c = self.address.parse::<Bech32>();
         match c {
@@ -145,16 +176,22 @@ pub fn init() -> Result<(), KCoinError> {
             .short("a")
             .long("kcn-address")
             .value_name("ADDRESS")
-            .help("Owner of the initial KCN supply")
+            .help("Owner of the initial KCN supply. Ignored when a genesis file is given.")
             .takes_value(true)
-            .required(true))
+            .required_unless("genesis"))
         .arg(Arg::with_name("kcn-supply")
             .short("s")
             .long("kcn-supply")
             .value_name("AMOUNT")
-            .help("How many KCNs get created initially")
+            .help("How many KCNs get created initially. Ignored when a genesis file is given.")
             .takes_value(true)
             .default_value("100000000"))
+        .arg(Arg::with_name("genesis")
+            .short("g")
+            .long("genesis")
+            .value_name("PATH")
+            .help("JSON file with the initial allocations, the fee address, the network and the genesis timestamp")
+            .takes_value(true))
         .arg(Arg::with_name("block-time")
             .short("t")
             .long("block-time")
@@ -176,10 +213,94 @@ pub fn init() -> Result<(), KCoinError> {
             .help("How many transactions the each block can fit")
             .takes_value(true)
             .default_value("100"))
+        .arg(Arg::with_name("producer-key")
+            .short("k")
+            .long("producer-key")
+            .value_name("PATH")
+            .help("File holding the hex encoded ed25519 secret key blocks get signed with. Defaults to producer.key in the datadir and gets generated if missing.")
+            .takes_value(true))
+        .arg(Arg::with_name("chain-producer-key")
+            .long("chain-producer-key")
+            .value_name("HEX")
+            .help("Hex encoded public key that signs the blocks of a chain whose genesis lists no producers. Following, auditing or importing such a chain needs it unless the datadir has the key pinned from an earlier run. The datadir pins it on first use and refuses any other key afterwards.")
+            .takes_value(true))
+        .arg(Arg::with_name("follow")
+            .short("f")
+            .long("follow")
+            .value_name("RPC-URL")
+            .help("Runs a read-only node that doesn't produce blocks but copies and validates the blocks of the primary node at this url, e.g. http://127.0.0.1:3030. Polls every block-time seconds.")
+            .takes_value(true))
+        .arg(Arg::with_name("peers")
+            .long("peers")
+            .value_name("RPC-URLS")
+            .help("Comma separated rpc urls of the other producers, e.g. http://127.0.0.1:3031,http://127.0.0.1:3032. Only used if the genesis lists producers. block-time is the minimum time between blocks then and should be lower than the producer_timeout of the genesis.")
+            .takes_value(true)
+            .multiple(true)
+            .use_delimiter(true))
+        .arg(Arg::with_name("accept-v1-transactions")
+            .long("accept-v1-transactions")
+            .help("Accepts new transactions signed with version 1. Their signature doesn't cover the chain id, so they can be replayed on any other chain."))
+        .arg(Arg::with_name("admin-token")
+            .long("admin-token")
+            .value_name("TOKEN")
+            .help("Enables the admin_* rpc calls. They have to pass this token.")
+            .takes_value(true))
         .arg(Arg::with_name("regtest")
             .short("r")
             .long("regtest")
             .help("Enables regtest mode. Regtest mode disables automatic block generation and allows generating blocks on demand by invoking the generate rpc call."))
+        .subcommand(SubCommand::with_name("audit")
+            .about("Replays the whole chain, compares the result to the stored balances and verifies every block and signature")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .help("Write the JSON report to this file instead of stdout")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Writes all blocks and their transactions to a file")
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("PATH")
+                .help("File the export gets written to")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .value_name("FORMAT")
+                .help("jsonl or binary")
+                .takes_value(true)
+                .possible_values(&["jsonl", "binary"])
+                .default_value("jsonl")))
+        .subcommand(SubCommand::with_name("import")
+            .about("Validates and applies the blocks of an export on top of an empty datadir")
+            .arg(Arg::with_name("input")
+                .short("i")
+                .long("input")
+                .value_name("PATH")
+                .help("File written by the export command, in either format")
+                .takes_value(true)
+                .required(true)))
+        .subcommand(SubCommand::with_name("rollback")
+            .about("Removes the latest blocks and reverts their balance changes. Only changes this datadir, every other node of the network has to be rolled back as well.")
+            .arg(Arg::with_name("blocks")
+                .short("n")
+                .long("blocks")
+                .value_name("NUMBER")
+                .help("How many blocks to remove from the top of the chain")
+                .takes_value(true)
+                .required(true))
+            .arg(Arg::with_name("operator")
+                .short("o")
+                .long("operator")
+                .value_name("NAME")
+                .help("Who is doing the rollback. Gets recorded in the rollback log as given, unverified. Defaults to $USER.")
+                .takes_value(true))
+            .arg(Arg::with_name("to-mempool")
+                .long("to-mempool")
+                .help("Puts the transactions of the removed blocks back into the mempool")))
         .get_matches();
 
     let mut io = IoHandler::with_compatibility(Compatibility::V2);
@@ -201,12 +322,23 @@ pub fn init() -> Result<(), KCoinError> {
         true => Network::Regtest
     };
 
-    debug!("{:?}", matches.value_of("kcn-address"));
-    let kcn_address = Bech32Address::new(matches.value_of("kcn-address").unwrap(), network.clone()).map_err(|e| KCoinError::InvalidArgument { argument: "kcn-address".to_owned(), reason: e.to_string()})?;
-    debug!("Value for kcn-address: {:?}", kcn_address);
+    let genesis = match matches.value_of("genesis") {
+        Some(path) => {
+            debug!("Value for genesis: {}", path);
+            Genesis::from_file(Path::new(path), &network).map_err(|e| KCoinError::InvalidArgument { argument: "genesis".to_owned(), reason: e.to_string()})?
+        },
+        None => {
+            debug!("{:?}", matches.value_of("kcn-address"));
+            let kcn_address = Bech32Address::new(matches.value_of("kcn-address").unwrap(), network.clone()).map_err(|e| KCoinError::InvalidArgument { argument: "kcn-address".to_owned(), reason: e.to_string()})?;
+            debug!("Value for kcn-address: {:?}", kcn_address);
 
-    let kcn_supply: u64 = matches.value_of("kcn-supply").unwrap_or_default().parse().map_err(|e: ParseIntError| KCoinError::InvalidArgument{ argument: "kcn-supply".to_owned(), reason: e.to_string()})?;
-    debug!("Value for kcn-supply: {}", kcn_supply);
+            let kcn_supply: u64 = matches.value_of("kcn-supply").unwrap_or_default().parse().map_err(|e: ParseIntError| KCoinError::InvalidArgument{ argument: "kcn-supply".to_owned(), reason: e.to_string()})?;
+            debug!("Value for kcn-supply: {}", kcn_supply);
+
+            Genesis::from_initial_supply(&kcn_address, kcn_supply, &network).map_err(|e| KCoinError::InvalidArgument { argument: "kcn-supply".to_owned(), reason: e.to_string()})?
+        }
+    };
+    info!("Genesis hash: {}", genesis.hash);
 
     let block_time: u64 = matches.value_of("block-time").unwrap_or_default().parse().map_err(|e: ParseIntError| KCoinError::InvalidArgument{ argument: "block-time".to_owned(), reason: e.to_string()})?;
     debug!("Value for block-time: {}", block_time);
@@ -217,16 +349,141 @@ pub fn init() -> Result<(), KCoinError> {
     let mempool_size: u64 = matches.value_of("mempool-size").unwrap_or_default().parse().map_err(|e: ParseIntError| KCoinError::InvalidArgument{ argument: "mempool-size".to_owned(), reason: e.to_string()})?;
     debug!("Value for mempool-size: {}", mempool_size);
 
-    let storage = storage::SqliteStorage::new(&Path::new(datadir), regtest, kcn_address, kcn_supply).unwrap();
+    let storage = storage::SqliteStorage::new(&Path::new(datadir), regtest, &genesis).map_err(|e| KCoinError::InvalidArgument { argument: "datadir".to_owned(), reason: e.to_string()})?;
+
+    let chain_producer_key = match matches.value_of("chain-producer-key") {
+        Some(key) => {
+            hex::decode(key).ok().and_then(|b| PublicKey::from_bytes(&b).ok())
+                .ok_or(KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: "not a hex encoded ed25519 public key".to_owned() })?;
+            Some(key.to_owned())
+        },
+        None => None
+    };
+    debug!("Value for chain-producer-key: {:?}", chain_producer_key);
+
+    if let Some(audit_matches) = matches.subcommand_matches("audit") {
+        let authority = chain_authority(&genesis, &storage, chain_producer_key.as_ref().map(|k| k.as_str()))?
+            .ok_or(KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: "needed to audit a chain whose genesis lists no producers".to_owned() })?;
+        let report = audit::run(&storage, &network, &authority, &genesis)
+            .map_err(|e| KCoinError::CommandFailed { command: "audit".to_owned(), reason: e.to_string() })?;
+        let report_json = serde_json::to_string_pretty(&report)
+            .map_err(|e| KCoinError::CommandFailed { command: "audit".to_owned(), reason: e.to_string() })?;
+        match audit_matches.value_of("output") {
+            Some(path) => fs::write(path, report_json)
+                .map_err(|e| KCoinError::CommandFailed { command: "audit".to_owned(), reason: e.to_string() })?,
+            None => println!("{}", report_json)
+        }
+        if !report.ok {
+            return Err(KCoinError::CommandFailed { command: "audit".to_owned(), reason: format!("{} discrepancies found", report.discrepancies.len()) });
+        }
+        return Ok(());
+    }
+
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        let format = match export_matches.value_of("format") {
+            Some("binary") => export::Format::Binary,
+            _ => export::Format::JsonLines
+        };
+        let output = export_matches.value_of("output").unwrap();
+        let exported = export::export(&storage, &network, Path::new(output), format)
+            .map_err(|e| KCoinError::CommandFailed { command: "export".to_owned(), reason: e.to_string() })?;
+        info!("Exported {} blocks to {}", exported, output);
+        return Ok(());
+    }
+
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let input = import_matches.value_of("input").unwrap();
+        let authority = chain_authority(&genesis, &storage, chain_producer_key.as_ref().map(|k| k.as_str()))?
+            .ok_or(KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: "needed to import a chain whose genesis lists no producers".to_owned() })?;
+        let imported = export::import(&storage, &network, &authority, Path::new(input))
+            .map_err(|e| KCoinError::CommandFailed { command: "import".to_owned(), reason: e.to_string() })?;
+        if genesis.producers.is_empty() {
+            storage.producer_key_pin(&authority.keys()[0]).map_err(|e| KCoinError::CommandFailed { command: "import".to_owned(), reason: e.to_string() })?;
+        }
+        info!("Imported {} blocks from {}", imported, input);
+        return Ok(());
+    }
+
+    if let Some(rollback_matches) = matches.subcommand_matches("rollback") {
+        if matches.is_present("peers") || matches.is_present("follow") {
+            return Err(KCoinError::CommandFailed { command: "rollback".to_owned(), reason: "peers and followed nodes keep the removed blocks, roll back every node on its own".to_owned() });
+        }
+        let blocks: u32 = rollback_matches.value_of("blocks").unwrap_or_default().parse().map_err(|e: ParseIntError| KCoinError::InvalidArgument{ argument: "blocks".to_owned(), reason: e.to_string()})?;
+        let operator = match rollback_matches.value_of("operator") {
+            Some(v) => v.to_owned(),
+            None => env::var("USER").unwrap_or("unknown".to_owned())
+        };
+        let rollback = block::rollback(&storage, blocks, &operator, "cli", rollback_matches.is_present("to-mempool"), &network)
+            .map_err(|e| KCoinError::CommandFailed { command: "rollback".to_owned(), reason: e.to_string() })?;
+        info!("Rolled back to height {}, {} transactions removed, {} dropped from the mempool", rollback.to_height, rollback.tx_hashes.len(), rollback.dropped.len());
+        return Ok(());
+    }
 
-    if regtest == true {
+    // Only a running node signs anything, the commands above don't need a producer key
+    let producer_key_path = match matches.value_of("producer-key") {
+        Some(v) => PathBuf::from(v),
+        None => Path::new(datadir).join("producer.key")
+    };
+    debug!("Value for producer-key: {:?}", producer_key_path);
+    let producer = Producer::load_or_create(&producer_key_path).map_err(|e| KCoinError::InvalidArgument{ argument: "producer-key".to_owned(), reason: e.to_string()})?;
+    info!("Producer public key: {}", producer.public_key());
+
+    let follower = match matches.value_of("follow") {
+        Some(url) => {
+            debug!("Value for follow: {}", url);
+            let primary = RpcClient::new(url).map_err(|e| KCoinError::InvalidArgument { argument: "follow".to_owned(), reason: e.to_string()})?;
+            let authority = chain_authority(&genesis, &storage, chain_producer_key.as_ref().map(|k| k.as_str()))?
+                .ok_or(KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: "needed to follow a chain whose genesis lists no producers".to_owned() })?;
+            let follower = Follower::new(storage.clone(), network.clone(), primary, authority).map_err(|e| KCoinError::InvalidArgument { argument: "follow".to_owned(), reason: e.to_string()})?;
+            if genesis.producers.is_empty() {
+                storage.producer_key_pin(&follower.authority().keys()[0]).map_err(|e| KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: e.to_string()})?;
+            }
+            info!("Following {}. Block production has been disabled.", url);
+            Some(follower)
+        },
+        None => None
+    };
+
+    // Followers check blocks against the key of their primary. A single producer signs with its
+    // own key, and its datadir refuses to be signed by any other.
+    let (producer_key, authority) = match follower {
+        Some(ref f) => (f.authority().keys()[0].clone(), f.authority().clone()),
+        None => {
+            if genesis.producers.is_empty() {
+                if chain_producer_key.as_ref().map_or(false, |k| k != &producer.public_key()) {
+                    return Err(KCoinError::InvalidArgument { argument: "producer-key".to_owned(), reason: "does not match chain-producer-key".to_owned() });
+                }
+                storage.producer_key_pin(&producer.public_key()).map_err(|e| KCoinError::InvalidArgument { argument: "producer-key".to_owned(), reason: e.to_string()})?;
+            }
+            (producer.public_key(), Authority::for_genesis(&genesis, &producer.public_key()))
+        }
+    };
+
+    let mut peers = Vec::new();
+    for url in matches.values_of("peers").map(|v| v.collect::<Vec<&str>>()).unwrap_or(Vec::new()) {
+        peers.push(RpcClient::new(url).map_err(|e| KCoinError::InvalidArgument { argument: "peers".to_owned(), reason: e.to_string()})?);
+    }
+    let federated = authority.is_federated() && follower.is_none();
+    if federated {
+        info!("Producing blocks in turns with {} producers and {} peers", authority.keys().len(), peers.len());
+
+        let storage_clone = storage.clone();
+        let network_clone = network.clone();
+        let authority_clone = authority.clone();
+        io.add_method("chain_proposeBlock", move |params| {
+            rpccalls::chain::chain_propose_block(&storage_clone, &network_clone, &authority_clone, param_map(params)?)
+        });
+    }
+
+    if regtest == true && follower.is_none() && !federated {
         info!("Regtest mode enabled. Automated block production has been disabled.");
 
         let block_gen_storage = storage.clone();
         let network_clone = network.clone();
         let block_size_clone = block_size;
+        let producer_clone = producer.clone();
         io.add_method("regtest_generate", move |_| {
-            rpccalls::regtest::regtest_generate(&block_gen_storage, block_size_clone, &network_clone)
+            rpccalls::regtest::regtest_generate(&block_gen_storage, block_size_clone, &network_clone, &producer_clone)
         });
     }
 
@@ -245,6 +502,36 @@ pub fn init() -> Result<(), KCoinError> {
         });
     }
 
+    {
+        let storage_clone = storage.clone();
+        io.add_method("chain_getId", move |_| {
+            rpccalls::chain::chain_get_id(&storage_clone)
+        });
+    }
+
+    {
+        let network_clone = network.clone();
+        io.add_method("chain_multisigAddress", move |params| {
+            rpccalls::chain::chain_multisig_address(param_map(params)?, &network_clone)
+        });
+    }
+
+    {
+        let producer_key_clone = producer_key.clone();
+        let authority_clone = authority.clone();
+        io.add_method("chain_getProducer", move |_| {
+            rpccalls::chain::chain_get_producer(&producer_key_clone, &authority_clone)
+        });
+    }
+
+    {
+        let storage_clone = storage.clone();
+        let authority_clone = authority.clone();
+        io.add_method("chain_validate", move |_| {
+            rpccalls::chain::chain_validate(&storage_clone, &authority_clone)
+        });
+    }
+
     {
         let storage_clone = storage.clone();
         let network_clone = network.clone();
@@ -269,6 +556,42 @@ pub fn init() -> Result<(), KCoinError> {
         });
     }
 
+    {
+        let storage_clone = storage.clone();
+        io.add_method("coin_getInfo", move |params| {
+            rpccalls::coin::coin_get_info(&storage_clone, param_map(params)?)
+        });
+    }
+
+    {
+        let storage_clone = storage.clone();
+        io.add_method("coin_list", move |_| {
+            rpccalls::coin::coin_list(&storage_clone)
+        });
+    }
+
+    {
+        let storage_clone = storage.clone();
+        io.add_method("exchange_getDepth", move |params| {
+            rpccalls::exchange::exchange_get_depth(&storage_clone, param_map(params)?)
+        });
+    }
+
+    {
+        let storage_clone = storage.clone();
+        let network_clone = network.clone();
+        io.add_method("exchange_getFills", move |params| {
+            rpccalls::exchange::exchange_get_fills(&storage_clone, &network_clone, param_map(params)?)
+        });
+    }
+
+    {
+        let storage_clone = storage.clone();
+        io.add_method("mempool_getPrunedTransaction", move |params| {
+            rpccalls::mempool::mempool_get_pruned_transaction(&storage_clone, param_map(params)?)
+        });
+    }
+
     {
         let storage_clone = storage.clone();
         let network_clone = network.clone();
@@ -285,6 +608,14 @@ pub fn init() -> Result<(), KCoinError> {
         });
     }
 
+    {
+        let storage_clone = storage.clone();
+        let network_clone = network.clone();
+        io.add_method("chain_getTransactionProof", move |params| {
+            rpccalls::chain::chain_get_transaction_proof(&storage_clone, &network_clone, param_map(params)?)
+        });
+    }
+
     {
         let storage_clone = storage.clone();
         let network_clone = network.clone();
@@ -300,22 +631,47 @@ pub fn init() -> Result<(), KCoinError> {
         let storage_clone = storage.clone();
         let network_clone = network.clone();
         let mempool_size_clone = mempool_size;
+        let read_only = follower.is_some();
+        let accept_v1 = matches.is_present("accept-v1-transactions");
         io.add_method("tx_send", move |params| {
-            rpccalls::tx::tx_send(&storage_clone, &network_clone, mempool_size_clone, param_map(params)?)
+            if read_only {
+                return Err(errors::read_only());
+            }
+            rpccalls::tx::tx_send(&storage_clone, &network_clone, mempool_size_clone, accept_v1, param_map(params)?)
         });
     }
 
+    if let Some(admin_token) = matches.value_of("admin-token") {
+        let storage_clone = storage.clone();
+        let network_clone = network.clone();
+        let admin_token = admin_token.to_owned();
+        let networked = follower.is_some() || matches.is_present("peers");
+        io.add_method("admin_rollback", move |params| {
+            rpccalls::admin::admin_rollback(&storage_clone, &network_clone, &admin_token, networked, param_map(params)?)
+        });
+    }
 
-    if regtest == false {
+    if let Some(follower) = follower {
+        let poll_interval = time::Duration::from_millis((block_time * 1000).into());
+        thread::spawn(move || {
+            follower.run(poll_interval);
+        });
+    } else if federated {
+        let federation = Federation::new(storage.clone(), network.clone(), authority.clone(), producer.clone(), peers, block_size, block_time as i64);
+        thread::spawn(move || {
+            federation.run(time::Duration::from_secs(1));
+        });
+    } else if regtest == false {
         let block_gen_storage = storage.clone();
         let network_clone = network.clone();
         let block_size_clone = block_size;
+        let producer_clone = producer.clone();
         let block_gen_thread = thread::spawn(move || {
             loop {
-                match block::generate(&block_gen_storage, block_size_clone, &network) {
+                match block::generate(&block_gen_storage, block_size_clone, &network, &producer_clone) {
                     Ok(_) => {},
                     Err(e) => {
-                        println!("Error during block production: {:?}", e);
+                        warn!("Error during block production: {:?}", e);
                     }
                 }
 
@@ -335,6 +691,22 @@ pub fn init() -> Result<(), KCoinError> {
     Ok(())
 }
 
+/// The keys that sign the blocks of the chain. Those are the producers of the genesis if it lists
+/// any, otherwise the key given with --chain-producer-key or the one pinned in the datadir, which
+/// have to agree. `None` if neither is known.
+fn chain_authority(genesis: &Genesis, storage: &storage::SqliteStorage, given: Option<&str>) -> Result<Option<Authority>, KCoinError> {
+    if !genesis.producers.is_empty() {
+        return Ok(Some(Authority::new(genesis.producers.clone(), genesis.producer_timeout)));
+    }
+    let pinned = storage.producer_key().map_err(|e| KCoinError::InvalidArgument { argument: "datadir".to_owned(), reason: e.to_string()})?;
+    match (given, pinned) {
+        (Some(given), Some(ref pinned)) if given != pinned => Err(KCoinError::InvalidArgument { argument: "chain-producer-key".to_owned(), reason: format!("the datadir is pinned to producer key {}", pinned) }),
+        (Some(given), _) => Ok(Some(Authority::single(given))),
+        (None, Some(pinned)) => Ok(Some(Authority::single(&pinned))),
+        (None, None) => Ok(None)
+    }
+}
+
 fn param_map(params: Params) -> Result<serde_json::Map<String, Value>, Error> {
     match params {
         Params::Map(m) => Ok(m),
@@ -18,6 +18,7 @@ extern crate ed25519_dalek;
 extern crate sha2;
 extern crate time;
 extern crate regex;
+extern crate rand;
 
 mod rpccalls;
 mod errors;
@@ -25,6 +26,20 @@ mod storage;
 mod kcoin;
 mod tx;
 mod block;
+mod merkle;
+mod state;
+mod producer;
+mod audit;
+mod genesis;
+mod codec;
+mod export;
+mod rpcclient;
+mod follower;
+mod federation;
+mod multisig;
+mod exchange;
+#[cfg(test)]
+mod testutil;
 
 fn main() {
     match kcoin::init() {
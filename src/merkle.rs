@@ -0,0 +1,223 @@
+use sha2::{Sha256, Digest};
+use hex;
+
+// Leaves and inner nodes are hashed with different prefixes so that an inner node can never be
+// passed off as a leaf.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Left,
+    Right
+}
+
+/// One step of an inclusion proof: the sibling hash and on which side of the path it sits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: String,
+    pub position: Position
+}
+
+/// A merkle tree with all its levels kept in memory, so that any number of proofs can be read
+/// from it without hashing the leaves again.
+/// A level with an odd number of nodes promotes its last node to the next level unchanged.
+pub struct Tree {
+    levels: Vec<Vec<Vec<u8>>>
+}
+
+impl Tree {
+    pub fn new(leaves: &[Vec<u8>]) -> Self {
+        let mut levels: Vec<Vec<Vec<u8>>> = vec![leaves.iter().map(|l| leaf_hash(l)).collect()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = next_level(&levels[levels.len() - 1]);
+            levels.push(next);
+        }
+        Tree { levels }
+    }
+
+    pub fn root(&self) -> Vec<u8> {
+        match self.levels[self.levels.len() - 1].first() {
+            Some(root) => root.clone(),
+            None => hash(&[])
+        }
+    }
+
+    /// Returns the sibling path from the leaf at `index` up to the root.
+    pub fn proof(&self, index: usize) -> Option<Vec<ProofStep>> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut steps = Vec::new();
+        let mut index = index;
+        for level in self.levels[..self.levels.len() - 1].iter() {
+            if index % 2 == 1 {
+                steps.push(ProofStep { hash: hex::encode(&level[index - 1]), position: Position::Left });
+            } else if index + 1 < level.len() {
+                steps.push(ProofStep { hash: hex::encode(&level[index + 1]), position: Position::Right });
+            }
+            index = index / 2;
+        }
+        Some(steps)
+    }
+}
+
+/// Computes the merkle root over the given leaves.
+pub fn root(leaves: &[Vec<u8>]) -> Vec<u8> {
+    Tree::new(leaves).root()
+}
+
+/// Returns the sibling path from the leaf at `index` up to the root.
+pub fn proof(leaves: &[Vec<u8>], index: usize) -> Option<Vec<ProofStep>> {
+    Tree::new(leaves).proof(index)
+}
+
+/// Checks that `leaf` is included under `root` using the given sibling path.
+/// `leaf` and `root` are hex encoded, as returned by the rpc interface.
+pub fn verify(leaf: &str, proof: &[ProofStep], root: &str) -> bool {
+    let mut current = match hex::decode(leaf) {
+        Ok(v) => leaf_hash(&v),
+        Err(_) => return false
+    };
+    for step in proof.iter() {
+        let sibling = match hex::decode(&step.hash) {
+            Ok(v) => v,
+            Err(_) => return false
+        };
+        current = match step.position {
+            Position::Left => node_hash(&sibling, &current),
+            Position::Right => node_hash(&current, &sibling)
+        };
+    }
+    hex::encode(current) == root
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut next = Vec::new();
+    for pair in level.chunks(2) {
+        if pair.len() == 2 {
+            next.push(node_hash(&pair[0], &pair[1]));
+        } else {
+            next.push(pair[0].clone());
+        }
+    }
+    next
+}
+
+fn leaf_hash(leaf: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![LEAF_PREFIX];
+    bytes.extend(leaf);
+    hash(&bytes)
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![NODE_PREFIX];
+    bytes.extend(left);
+    bytes.extend(right);
+    hash(&bytes)
+}
+
+fn hash(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.input(bytes);
+    hasher.result().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use hex;
+    use merkle;
+    use merkle::{Position, ProofStep, Tree};
+
+    fn leaves(n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!("leaf {}", i).into_bytes()).collect()
+    }
+
+    fn verifies(leaves: &[Vec<u8>], index: usize, proof: &[ProofStep]) -> bool {
+        merkle::verify(&hex::encode(&leaves[index]), proof, &hex::encode(merkle::root(leaves)))
+    }
+
+    #[test]
+    fn every_leaf_has_a_proof() {
+        for n in 1..18 {
+            let leaves = leaves(n);
+            let tree = Tree::new(&leaves);
+            assert_eq!(tree.root(), merkle::root(&leaves));
+            for i in 0..n {
+                let proof = tree.proof(i).unwrap();
+                assert!(verifies(&leaves, i, &proof), "leaf {} of {}", i, n);
+                if n > 1 {
+                    assert!(!verifies(&leaves, (i + 1) % n, &proof));
+                }
+            }
+            assert!(tree.proof(n).is_none());
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_the_root() {
+        let leaves = leaves(1);
+        assert_eq!(Tree::new(&leaves).proof(0).unwrap().len(), 0);
+        assert_ne!(merkle::root(&leaves), leaves[0]);
+        assert!(verifies(&leaves, 0, &[]));
+    }
+
+    #[test]
+    fn empty_tree() {
+        let tree = Tree::new(&[]);
+        assert_eq!(tree.root(), merkle::root(&[]));
+        assert!(tree.proof(0).is_none());
+    }
+
+    #[test]
+    fn odd_level_promotes_its_last_node() {
+        // With 5 leaves the last one only gets paired at the top
+        let leaves = leaves(5);
+        let proof = merkle::proof(&leaves, 4).unwrap();
+        assert_eq!(proof.len(), 1);
+        assert_eq!(proof[0].position, Position::Left);
+        assert_eq!(proof[0].hash, hex::encode(merkle::root(&leaves[..4])));
+        assert!(verifies(&leaves, 4, &proof));
+    }
+
+    #[test]
+    fn tampered_proofs_fail() {
+        let leaves = leaves(7);
+        let proof = merkle::proof(&leaves, 2).unwrap();
+        assert!(verifies(&leaves, 2, &proof));
+
+        let mut flipped = proof.clone();
+        flipped[0].position = match flipped[0].position {
+            Position::Left => Position::Right,
+            Position::Right => Position::Left
+        };
+        assert!(!verifies(&leaves, 2, &flipped));
+
+        let mut changed = proof.clone();
+        changed[1].hash = hex::encode(merkle::root(&leaves[..1]));
+        assert!(!verifies(&leaves, 2, &changed));
+
+        assert!(!verifies(&leaves, 2, &proof[..proof.len() - 1]));
+
+        let mut extended = proof.clone();
+        extended.push(proof[0].clone());
+        assert!(!verifies(&leaves, 2, &extended));
+
+        let mut invalid = proof.clone();
+        invalid[0].hash = "not hex".to_owned();
+        assert!(!verifies(&leaves, 2, &invalid));
+    }
+
+    #[test]
+    fn inner_node_is_not_a_leaf() {
+        // The hashes of the two children of the root, passed off as a leaf, don't verify
+        let leaves = leaves(4);
+        let tree = Tree::new(&leaves);
+        let left = tree.proof(3).unwrap().pop().unwrap();
+        let right = tree.proof(0).unwrap().pop().unwrap();
+        let mut inner = hex::decode(&left.hash).unwrap();
+        inner.extend(hex::decode(&right.hash).unwrap());
+        assert!(!merkle::verify(&hex::encode(&inner), &[], &hex::encode(tree.root())));
+    }
+}
@@ -0,0 +1,217 @@
+use ed25519_dalek::{PublicKey, Signature};
+use sha2::{Sha256, Sha512, Digest};
+use serde_json::{Value, Map};
+use bech32::{Bech32, convert_bits};
+use hex;
+use kcoin::{Bech32Address, Network};
+
+/// How many keys a multisig address can have.
+pub const MAX_KEYS: usize = 16;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "invalid multisig: {}", reason)]
+    Invalid {
+        reason: String
+    },
+}
+
+/// A threshold of `keys` that has to sign for a multisig address.
+///
+/// The address commits to the threshold and the sorted keys, so the same set always ends up at the
+/// same address no matter in which order the keys are given. Its hrp is the network prefix
+/// followed by `m`, e.g. `kcnm1...`, so wallets can tell it apart from single key addresses.
+#[derive(Debug, Clone, Serialize)]
+pub struct Policy {
+    pub threshold: u8,
+    pub keys: Vec<String>
+}
+
+impl Policy {
+    pub fn new(threshold: u8, keys: Vec<String>) -> Result<Self, Error> {
+        let mut keys: Vec<String> = keys.into_iter().map(|k| k.to_lowercase()).collect();
+        keys.sort();
+        if keys.is_empty() || keys.len() > MAX_KEYS {
+            return Err(Error::Invalid { reason: format!("between 1 and {} keys are needed", MAX_KEYS) });
+        }
+        if keys.windows(2).any(|w| w[0] == w[1]) {
+            return Err(Error::Invalid { reason: "duplicate key".to_owned() });
+        }
+        for key in keys.iter() {
+            parse_key(key)?;
+        }
+        if threshold == 0 || threshold as usize > keys.len() {
+            return Err(Error::Invalid { reason: "threshold has to be between 1 and the number of keys".to_owned() });
+        }
+        Ok(Policy { threshold, keys })
+    }
+
+    /// Reads `threshold` and `keys` from a JSON object.
+    pub fn from_json(json: &Map<String, Value>) -> Result<Self, Error> {
+        let threshold = json.get("threshold").and_then(|v| v.as_u64())
+            .filter(|t| *t <= u8::max_value() as u64)
+            .ok_or(Error::Invalid { reason: "invalid threshold".to_owned() })?;
+        let keys = json.get("keys").and_then(|v| v.as_array())
+            .ok_or(Error::Invalid { reason: "invalid keys".to_owned() })?
+            .iter()
+            .map(|k| k.as_str().map(|k| k.to_owned()).ok_or(Error::Invalid { reason: "invalid keys".to_owned() }))
+            .collect::<Result<Vec<String>, Error>>()?;
+        Policy::new(threshold as u8, keys)
+    }
+
+    /// SHA-256 of `u8 threshold | u8 number of keys | keys`, the data of the address.
+    fn hash(&self) -> Vec<u8> {
+        let mut hasher = Sha256::default();
+        hasher.input(&[self.threshold, self.keys.len() as u8]);
+        for key in self.keys.iter() {
+            hasher.input(&hex::decode(key).unwrap_or(Vec::new()));
+        }
+        hasher.result().to_vec()
+    }
+
+    pub fn address(&self, network: &Network) -> Bech32Address {
+        let data = convert_bits(&self.hash(), 8, 5, true).expect("32 bytes always convert to base32");
+        let address = Bech32::new_check_data(network.multisig_prefix(), data).expect("multisig prefix is a valid hrp");
+        Bech32Address { address: address.to_string(), network: network.clone() }
+    }
+}
+
+/// One signature of a multisig transaction together with the key that made it.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeySignature {
+    pub key: String,
+    pub signature: String
+}
+
+/// What a transaction from a multisig address carries instead of a single signature: the policy
+/// the address was derived from and the signatures of at least `threshold` of its keys. Every
+/// signer signs the same data a single key would.
+#[derive(Debug, Clone, Serialize)]
+pub struct Multisig {
+    #[serde(flatten)]
+    pub policy: Policy,
+    pub signatures: Vec<KeySignature>
+}
+
+impl Multisig {
+    pub fn from_json(json: &Map<String, Value>) -> Result<Self, Error> {
+        let policy = Policy::from_json(json)?;
+        let values = json.get("signatures").and_then(|v| v.as_array())
+            .ok_or(Error::Invalid { reason: "invalid signatures".to_owned() })?;
+        if values.len() > policy.keys.len() {
+            return Err(Error::Invalid { reason: "more signatures than keys".to_owned() });
+        }
+        let mut signatures = Vec::new();
+        for value in values.iter() {
+            let key = value.get("key").and_then(|v| v.as_str());
+            let signature = value.get("signature").and_then(|v| v.as_str());
+            match (key, signature) {
+                (Some(key), Some(signature)) => signatures.push(KeySignature { key: key.to_lowercase(), signature: signature.to_owned() }),
+                _ => return Err(Error::Invalid { reason: "invalid signatures".to_owned() })
+            }
+        }
+        Ok(Multisig { policy, signatures })
+    }
+
+    /// True if `address` was derived from the policy and at least `threshold` distinct keys of it
+    /// signed `data`.
+    pub fn verify(&self, address: &Bech32Address, data: &[u8]) -> bool {
+        if self.policy.address(&address.network).address != address.address.to_lowercase() {
+            debug!("Multisig policy doesn't match address {}", address.address);
+            return false;
+        }
+        let mut signed: Vec<&str> = Vec::new();
+        for s in self.signatures.iter() {
+            if !self.policy.keys.contains(&s.key) || signed.contains(&s.key.as_str()) {
+                return false;
+            }
+            let key = match parse_key(&s.key) {
+                Ok(k) => k,
+                Err(_) => return false
+            };
+            let signature = match hex::decode(&s.signature).ok().and_then(|b| Signature::from_bytes(&b).ok()) {
+                Some(s) => s,
+                None => return false
+            };
+            if key.verify::<Sha512>(data, &signature).is_err() {
+                debug!("Invalid multisig signature of {}", s.key);
+                return false;
+            }
+            signed.push(&s.key);
+        }
+        signed.len() >= self.policy.threshold as usize
+    }
+}
+
+fn parse_key(key: &str) -> Result<PublicKey, Error> {
+    hex::decode(key).ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+        .ok_or(Error::Invalid { reason: format!("invalid key {}", key) })
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Keypair;
+    use kcoin::Network;
+    use multisig::{Policy, KeySignature};
+    use tx::TransactionEnvelope;
+    use testutil;
+    use testutil::FUNDS;
+
+    #[test]
+    fn address_commits_to_threshold_and_key_set() {
+        let keys: Vec<String> = (21..24).map(|seed| testutil::public_key(&testutil::keypair(seed))).collect();
+        let address = Policy::new(2, keys.clone()).unwrap().address(&Network::Mainnet);
+        assert!(address.is_multisig());
+
+        let reversed: Vec<String> = keys.iter().rev().cloned().collect();
+        assert_eq!(Policy::new(2, reversed).unwrap().address(&Network::Mainnet).address, address.address);
+        assert_ne!(Policy::new(1, keys.clone()).unwrap().address(&Network::Mainnet).address, address.address);
+        assert_ne!(Policy::new(2, keys[..2].to_vec()).unwrap().address(&Network::Mainnet).address, address.address);
+
+        assert!(Policy::new(0, keys.clone()).is_err());
+        assert!(Policy::new(4, keys.clone()).is_err());
+        assert!(Policy::new(1, vec![keys[0].clone(), keys[0].clone()]).is_err());
+    }
+
+    #[test]
+    fn spending_needs_threshold_of_distinct_keys() {
+        let alice = testutil::keypair(10);
+        let signers: Vec<Keypair> = (21..24).map(testutil::keypair).collect();
+        let outsider = testutil::keypair(24);
+        let keys: Vec<String> = signers.iter().map(testutil::public_key).collect();
+        let address = Policy::new(2, keys.clone()).unwrap().address(&Network::Mainnet);
+        let bob = testutil::address(&testutil::keypair(11));
+        let (storage, producer) = testutil::chain(&[&alice]);
+
+        testutil::send(&storage, &testutil::transfer(storage.genesis_hash(), &alice, &address, 10000, 0)).unwrap();
+        testutil::mine(&storage, &producer);
+
+        let spend = |signed_by: &[&Keypair]| -> TransactionEnvelope {
+            let mut tx = testutil::unsigned(storage.genesis_hash(), json!({
+                "from": address.address,
+                "to": bob.address,
+                "coin": "KCN",
+                "amount": 5000,
+                "fee": 1000,
+                "memo": "",
+                "nonce": 0
+            }), json!({"multisig": {"threshold": 2, "keys": keys, "signatures": []}}));
+            for signer in signed_by.iter() {
+                let signature = testutil::signature(signer, &tx);
+                tx.multisig.as_mut().unwrap().signatures.push(KeySignature { key: testutil::public_key(signer), signature });
+            }
+            tx
+        };
+        assert!(testutil::send(&storage, &spend(&[&signers[0]])).is_err());
+        assert!(testutil::send(&storage, &spend(&[&signers[0], &signers[0]])).is_err());
+        assert!(testutil::send(&storage, &spend(&[&signers[0], &outsider])).is_err());
+
+        let tx = spend(&[&signers[2], &signers[0]]);
+        testutil::send(&storage, &tx).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs[0].hash, tx.hash);
+        assert_eq!(testutil::balance(&storage, &address, "KCN"), 10000 - 5000 - 1000);
+        assert_eq!(testutil::balance(&storage, &bob, "KCN"), 5000);
+        assert_eq!(testutil::balance(&storage, &testutil::address(&alice), "KCN"), FUNDS - 10000 - 1000);
+    }
+}
@@ -0,0 +1,173 @@
+use ed25519_dalek::{Keypair, SecretKey, PublicKey, Signature};
+use sha2::Sha512;
+use std::sync::Arc;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use hex;
+use genesis::Genesis;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "cannot read producer key: {}", message)]
+    CannotReadKey {
+        message: String
+    },
+    #[fail(display = "cannot write producer key: {}", message)]
+    CannotWriteKey {
+        message: String
+    },
+    #[fail(display = "cannot generate producer key: {}", message)]
+    CannotGenerateKey {
+        message: String
+    },
+    #[fail(display = "invalid producer key")]
+    InvalidKey,
+}
+
+/// The ed25519 key blocks are signed with.
+#[derive(Clone)]
+pub struct Producer {
+    keypair: Arc<Keypair>
+}
+
+impl Producer {
+    /// Loads the hex encoded secret key stored at `path`. If there is no file yet, a new key
+    /// gets generated and written there, readable by the owner only.
+    pub fn load_or_create(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            let mut secret = [0u8; 32];
+            OsRng::new()
+                .and_then(|mut rng| rng.try_fill_bytes(&mut secret))
+                .map_err(|e| Error::CannotGenerateKey { message: e.to_string() })?;
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut f| f.write_all(hex::encode(&secret[..]).as_bytes()))
+                .map_err(|e| Error::CannotWriteKey { message: e.to_string() })?;
+            info!("Generated new producer key at {:?}", path);
+        }
+        let contents = fs::read_to_string(path).map_err(|e| Error::CannotReadKey { message: e.to_string() })?;
+        Producer::from_secret_hex(contents.trim())
+    }
+
+    pub fn from_secret_hex(secret: &str) -> Result<Self, Error> {
+        let bytes = hex::decode(secret).map_err(|_| Error::InvalidKey)?;
+        let secret = SecretKey::from_bytes(&bytes).map_err(|_| Error::InvalidKey)?;
+        let public = PublicKey::from_secret::<Sha512>(&secret);
+        Ok(Producer {
+            keypair: Arc::new(Keypair { secret, public })
+        })
+    }
+
+    /// Hex encoded public key. Followers use it to verify block signatures.
+    pub fn public_key(&self) -> String {
+        hex::encode(&self.keypair.public.to_bytes()[..])
+    }
+
+    /// Signs a hex encoded block hash and returns the hex encoded signature.
+    pub fn sign(&self, hash: &str) -> Result<String, Error> {
+        let message = hex::decode(hash).map_err(|_| Error::InvalidKey)?;
+        let signature = self.keypair.sign::<Sha512>(&message);
+        Ok(hex::encode(&signature.to_bytes()[..]))
+    }
+}
+
+/// Checks a block signature made by `Producer::sign`. All arguments are hex encoded.
+pub fn verify(public_key: &str, hash: &str, signature: &str) -> bool {
+    let public_key = match hex::decode(public_key).ok().and_then(|b| PublicKey::from_bytes(&b).ok()) {
+        Some(v) => v,
+        None => return false
+    };
+    let signature = match hex::decode(signature).ok().and_then(|b| Signature::from_bytes(&b).ok()) {
+        Some(v) => v,
+        None => return false
+    };
+    let message = match hex::decode(hash) {
+        Ok(v) => v,
+        Err(_) => return false
+    };
+    public_key.verify::<Sha512>(&message, &signature).is_ok()
+}
+
+/// The keys that may sign blocks. A chain started without producers in its genesis has a single
+/// key that signs every block.
+///
+/// With several keys the producers take turns: height `h` belongs to producer `h mod n`. If that
+/// producer doesn't make the block within `timeout` seconds after the previous block, the turn
+/// moves on to the next producer, and so on. Which producer had to sign a block therefore follows
+/// from its height and the time between it and its parent. When a late producer's block and the
+/// block of the next one compete, `block::prefers` picks one.
+#[derive(Debug, Clone)]
+pub struct Authority {
+    keys: Vec<String>,
+    timeout: i64
+}
+
+impl Authority {
+    pub fn single(key: &str) -> Self {
+        Authority { keys: vec![key.to_owned()], timeout: 0 }
+    }
+
+    pub fn new(keys: Vec<String>, timeout: i64) -> Self {
+        Authority { keys, timeout }
+    }
+
+    /// Uses the producers of the genesis if it has any, otherwise `producer_key` alone.
+    pub fn for_genesis(genesis: &Genesis, producer_key: &str) -> Self {
+        match genesis.producers.len() {
+            0 => Authority::single(producer_key),
+            _ => Authority::new(genesis.producers.clone(), genesis.producer_timeout)
+        }
+    }
+
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+
+    pub fn timeout(&self) -> i64 {
+        self.timeout
+    }
+
+    pub fn is_federated(&self) -> bool {
+        self.keys.len() > 1
+    }
+
+    /// How many turns passed between a block made at `prev_time` and one made at `time` on top of
+    /// it, that is how many producers missed theirs. Always 0 with a single producer. Blocks can't
+    /// be older than their parent on a federated chain.
+    pub fn turn(&self, prev_time: i64, time: i64) -> Option<u64> {
+        if !self.is_federated() {
+            return Some(0);
+        }
+        if time < prev_time || self.timeout <= 0 {
+            return None;
+        }
+        Some(((time - prev_time) / self.timeout) as u64)
+    }
+
+    /// The key that has to sign the block at `height` made at `time` on top of a block made at
+    /// `prev_time`.
+    pub fn expected_signer(&self, height: u32, prev_time: i64, time: i64) -> Option<&str> {
+        if !self.is_federated() {
+            return self.keys.first().map(|k| k.as_str());
+        }
+        let turns = self.turn(prev_time, time)?;
+        let index = (height as u64 + turns) % self.keys.len() as u64;
+        Some(&self.keys[index as usize])
+    }
+
+    /// Checks that a block is signed by the producer whose turn it was.
+    pub fn verify(&self, height: u32, prev_time: i64, time: i64, hash: &str, signature: &str) -> bool {
+        match self.expected_signer(height, prev_time, time) {
+            Some(key) => verify(key, hash, signature),
+            None => false
+        }
+    }
+}
@@ -0,0 +1,52 @@
+extern crate jsonrpc_minihttp_server;
+
+use ::errors;
+
+use jsonrpc_minihttp_server::jsonrpc_core::*;
+use storage::SqliteStorage;
+use storage;
+use kcoin::Network;
+use block;
+use super::get_string;
+
+/// Rolls back the local chain. Refused while the node has peers or follows a primary, they would
+/// keep the removed blocks. The operator is recorded as given, anyone with the token can claim
+/// any name.
+pub fn admin_rollback(storage: &SqliteStorage, network: &Network, admin_token: &str, networked: bool, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to admin_rollback");
+
+    let token = get_string(&params, "token")?;
+    if !token_matches(token, admin_token) {
+        return Err(errors::unauthorized());
+    }
+    if networked {
+        return Err(errors::rollback_refused("the node has peers or follows a primary, stop it and roll back every node instead"));
+    }
+
+    let blocks = params.get("blocks")
+        .ok_or(Error::invalid_params("Missing parameter: blocks"))?
+        .as_u64()
+        .ok_or(Error::invalid_params("invalid blocks"))? as u32;
+    let operator = get_string(&params, "operator")?;
+    let return_to_mempool = match params.get("return_to_mempool") {
+        Some(v) => v.as_bool().ok_or(Error::invalid_params("invalid return_to_mempool"))?,
+        None => false
+    };
+
+    let rollback = block::rollback(storage, blocks, operator, "admin rpc", return_to_mempool, network).map_err(|e| match e {
+        storage::Error::InvalidBlock { reason, .. } => Error::invalid_params(reason),
+        e => {
+            warn!("Internal error {:?}", e);
+            Error::internal_error()
+        }
+    })?;
+    Ok(json!(rollback))
+}
+
+// Compares in constant time so the token can't be guessed byte by byte from response times.
+fn token_matches(given: &str, expected: &str) -> bool {
+    if given.len() != expected.len() {
+        return false;
+    }
+    given.bytes().zip(expected.bytes()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
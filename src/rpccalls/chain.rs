@@ -7,6 +7,11 @@ use storage::SqliteStorage;
 use storage;
 use kcoin::Bech32Address;
 use kcoin::Network;
+use block;
+use export;
+use producer::Authority;
+use multisig::Policy;
+use hex;
 
 pub fn chain_height(storage: &SqliteStorage) -> Result<Value> {
     debug!("Received call to chain_height");
@@ -39,12 +44,83 @@ pub fn chain_get_block_by_height(storage: &SqliteStorage, params: serde_json::Ma
     let result = json!({
         "height": block.height,
         "hash": block.hash,
+        "prev_hash": block.prev_hash,
+        "merkle_root": block.merkle_root,
+        "state_root": block.state_root,
         "time": block.time,
+        "signature": block.signature,
         "txs": txs
     });
     Ok(result)
 }
 
+/// The chain id clients have to put into transactions they sign with the binary format.
+pub fn chain_get_id(storage: &SqliteStorage) -> Result<Value> {
+    debug!("Received call to chain_getId");
+
+    let result = json!({"chain_id": storage.genesis_hash()});
+    Ok(result)
+}
+
+/// Derives the multisig address of `threshold` out of `keys`. The keys come back sorted, in the
+/// order the address commits to.
+pub fn chain_multisig_address(params: serde_json::Map<String, Value>, network: &Network) -> Result<Value> {
+    debug!("Received call to chain_multisigAddress");
+
+    let policy = Policy::from_json(&params).map_err(|e| Error::invalid_params(e.to_string()))?;
+    let result = json!({
+        "address": policy.address(network),
+        "threshold": policy.threshold,
+        "keys": policy.keys
+    });
+    Ok(result)
+}
+
+pub fn chain_get_producer(producer_key: &str, authority: &Authority) -> Result<Value> {
+    debug!("Received call to chain_getProducer");
+
+    let result = json!({
+        "public_key": producer_key,
+        "producers": authority.keys(),
+        "producer_timeout": authority.timeout()
+    });
+    Ok(result)
+}
+
+pub fn chain_validate(storage: &SqliteStorage, authority: &Authority) -> Result<Value> {
+    debug!("Received call to chain_validate");
+
+    let broken_at = block::validate_chain(storage, authority).map_err(internal_error)?;
+
+    let result = json!({
+        "valid": broken_at.is_none(),
+        "broken_at": broken_at
+    });
+    Ok(result)
+}
+
+/// Receives a block another producer made. It is applied if it extends the local chain and was
+/// signed by the producer whose turn it was.
+pub fn chain_propose_block(storage: &SqliteStorage, network: &Network, authority: &Authority, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to chain_proposeBlock");
+
+    let value = params.get("block").cloned().ok_or(Error::invalid_params("Missing parameter: block"))?;
+    let block = export::block_from_json(value, network, storage.genesis_hash()).map_err(|e| Error::invalid_params(e.to_string()))?;
+    // A block for a height this node has already competes with the local one
+    let height = storage.block_height().map_err(internal_error)?;
+    let applied = match block.block.height > 0 && block.block.height <= height {
+        true => block::reorg(storage, block.block.height - 1, ::std::slice::from_ref(&block), authority, network).map(|_| ()),
+        false => block::apply(storage, &block, authority)
+    };
+    applied.map_err(|e| match e {
+        storage::Error::InvalidBlock { reason, .. } => errors::block_rejected(&reason),
+        e => internal_error(e)
+    })?;
+
+    let result = json!({"height": block.block.height});
+    Ok(result)
+}
+
 pub fn chain_get_transactions(storage: &SqliteStorage, network: &Network, params: serde_json::Map<String, Value>) -> Result<Value> {
     debug!("Received call to chain_getTransactions");
 
@@ -99,6 +175,36 @@ pub fn chain_get_transaction_by_hash(storage: &SqliteStorage, network: &Network,
     Ok(result)
 }
 
+pub fn chain_get_transaction_proof(storage: &SqliteStorage, network: &Network, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to chain_getTransactionProof");
+
+    let hash = params
+        .get("hash")
+        .ok_or(Error::invalid_params("hash missing"))?
+        .as_str()
+        .ok_or(Error::invalid_params("invalid hash"))?;
+
+    let tx = storage.chain_get_transaction_by_hash(network, hash).map_err(|e| {
+        match e {
+            storage::Error::NotFound => errors::not_found(),
+            _ => Error::internal_error()
+        }
+    })?;
+
+    let block = storage.block_get_by_height(tx.block).map_err(internal_error)?;
+    let tx_hashes = storage.block_get_tx_hashes(tx.block).map_err(internal_error)?;
+    let proof = block::transaction_proof(&tx_hashes, tx.index as usize).map_err(internal_error)?;
+
+    let result = json!({
+        "hash": hash,
+        "block": tx.block,
+        "index": tx.index,
+        "merkle_root": block.merkle_root,
+        "proof": proof
+    });
+    Ok(result)
+}
+
 pub fn chain_address_info(storage: &SqliteStorage, params: serde_json::Map<String, Value>, network: &Network) -> Result<Value> {
     debug!("Received call to chain_addressInfo");
 
@@ -111,6 +217,22 @@ pub fn chain_address_info(storage: &SqliteStorage, params: serde_json::Map<Strin
         network.clone()
     ).map_err(|_| Error::invalid_params("invalid address"))?;
 
+    if let Some(v) = params.get("at_height") {
+        let at_height = v.as_u64().filter(|h| *h <= u32::max_value() as u64).ok_or(Error::invalid_params("invalid at_height"))? as u32;
+        if at_height > storage.block_height().map_err(internal_error)? {
+            return Err(errors::no_block_found());
+        }
+        let balances = storage.address_get_balances_at_height(&address, at_height).map_err(internal_error)?;
+        let mut balance_result = serde_json::Map::new();
+        for balance in balances.iter() {
+            balance_result.insert(balance.coin.clone(), json!(balance.balance));
+        }
+        return Ok(json!({
+            "at_height": at_height,
+            "balances": balance_result
+        }));
+    }
+
     let nonce_mined = storage.address_nonce_mined(&address).map_err(|_| {
         Error::internal_error()
     })?;
@@ -139,14 +261,103 @@ pub fn chain_address_info(storage: &SqliteStorage, params: serde_json::Map<Strin
         reserved_result.insert(balance.coin.clone(), json!(balance.balance));
     }
 
+    // The key that signs for the address now, which changes when the key is rotated
+    let key = match address.is_multisig() {
+        true => Value::Null,
+        false => json!(hex::encode(storage.address_key(&address).map_err(internal_error)?.to_bytes()))
+    };
+
+    // Funds in open hash time locks belong to neither side until the lock is settled
+    let htlcs = storage.address_get_htlcs(&address).map_err(internal_error)?;
+
+    let with_proof = match params.get("proof") {
+        Some(v) => v.as_bool().ok_or(Error::invalid_params("invalid proof"))?,
+        None => false
+    };
+
+    // Balances only change when a block is made, so the current balances are the state committed
+    // to by the latest block's state root. Its tree is cached, proofs don't rebuild it.
+    let state_proof = match with_proof {
+        false => Value::Null,
+        true => match storage.state_tree().map_err(internal_error)? {
+            Some((block, tree)) => {
+                let mut proofs = serde_json::Map::new();
+                for balance in tree.balances_of(&address.address) {
+                    if let Some((value, steps)) = tree.proof(&balance.address, &balance.coin) {
+                        proofs.insert(balance.coin.clone(), json!({"balance": value, "proof": steps}));
+                    }
+                }
+                json!({
+                    "height": block.height,
+                    "state_root": block.state_root,
+                    "balances": proofs
+                })
+            },
+            None => Value::Null
+        }
+    };
+
     Ok(json!({
         "next_nonce": next_nonce,
         "balances": balance_result,
-        "reserved_balances": reserved_result
+        "reserved_balances": reserved_result,
+        "locked": htlcs,
+        "key": key,
+        "state_proof": state_proof
     }))
 }
 
 fn internal_error(e: storage::Error) -> Error {
-    println!("internal error {:?}", e);
+    warn!("Internal error {:?}", e);
     Error::internal_error()
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use kcoin::Network;
+    use kcoin::Bech32Address;
+    use storage::SqliteStorage;
+    use rpccalls::chain::chain_address_info;
+    use testutil;
+    use testutil::FUNDS;
+
+    fn balances_at(storage: &SqliteStorage, address: &Bech32Address, height: u64) -> Value {
+        let params = json!({"address": address.address, "at_height": height});
+        chain_address_info(storage, params.as_object().unwrap().clone(), &Network::Mainnet).unwrap()["balances"].clone()
+    }
+
+    #[test]
+    fn balances_at_height_follow_the_blocks() {
+        let alice = testutil::keypair(10);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let bob = testutil::address(&testutil::keypair(11));
+        let carol = testutil::address(&testutil::keypair(12));
+
+        testutil::send(&storage, &testutil::transfer(chain_id, &alice, &bob, 100, 0)).unwrap();
+        testutil::mine(&storage, &producer);
+        testutil::send(&storage, &testutil::transfer(chain_id, &alice, &bob, 200, 1)).unwrap();
+        testutil::mine(&storage, &producer);
+        // Block 3 doesn't touch bob's balance
+        testutil::send(&storage, &testutil::transfer(chain_id, &alice, &carol, 400, 2)).unwrap();
+        testutil::mine(&storage, &producer);
+
+        assert_eq!(balances_at(&storage, &bob, 0), json!({}));
+        assert_eq!(balances_at(&storage, &bob, 1), json!({"KCN": 100}));
+        assert_eq!(balances_at(&storage, &bob, 2), json!({"KCN": 300}));
+        assert_eq!(balances_at(&storage, &bob, 3), json!({"KCN": 300}));
+        assert_eq!(balances_at(&storage, &carol, 2), json!({}));
+        assert_eq!(balances_at(&storage, &carol, 3), json!({"KCN": 400}));
+
+        let alice = testutil::address(&alice);
+        assert_eq!(balances_at(&storage, &alice, 0), json!({"KCN": FUNDS, "USD": FUNDS}));
+        assert_eq!(balances_at(&storage, &alice, 1), json!({"KCN": FUNDS - 1100, "USD": FUNDS}));
+        assert_eq!(balances_at(&storage, &alice, 3), json!({"KCN": FUNDS - 3 * 1000 - 700, "USD": FUNDS}));
+
+        for height in [4, u32::max_value() as u64 + 1].iter() {
+            let params = json!({"address": bob.address, "at_height": height});
+            assert!(chain_address_info(&storage, params.as_object().unwrap().clone(), &Network::Mainnet).is_err());
+        }
+    }
+}
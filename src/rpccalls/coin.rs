@@ -0,0 +1,40 @@
+extern crate jsonrpc_minihttp_server;
+
+use ::errors;
+
+use jsonrpc_minihttp_server::jsonrpc_core::*;
+use storage::SqliteStorage;
+use storage;
+use super::get_string;
+
+pub fn coin_get_info(storage: &SqliteStorage, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to coin_getInfo");
+
+    let ticker = get_string(&params, "coin")?;
+    let coin = storage.coin_get(ticker).map_err(|e| {
+        match e {
+            storage::Error::NotFound => errors::unknown_coin(ticker),
+            _ => internal_error(e)
+        }
+    })?;
+
+    let frozen = storage.coin_get_frozen(ticker).map_err(internal_error)?;
+
+    let mut result = json!(coin);
+    result["frozen"] = json!(frozen);
+    Ok(result)
+}
+
+pub fn coin_list(storage: &SqliteStorage) -> Result<Value> {
+    debug!("Received call to coin_list");
+
+    let coins = storage.coin_list().map_err(internal_error)?;
+
+    let result = json!(coins);
+    Ok(result)
+}
+
+fn internal_error(e: storage::Error) -> Error {
+    warn!("Internal error {:?}", e);
+    Error::internal_error()
+}
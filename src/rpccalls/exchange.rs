@@ -0,0 +1,90 @@
+extern crate jsonrpc_minihttp_server;
+
+use jsonrpc_minihttp_server::jsonrpc_core::*;
+use storage::{SqliteStorage, Order};
+use storage;
+use kcoin::{Bech32Address, Network};
+use exchange;
+use super::get_string;
+
+/// The open orders between `coin` and `want`, grouped by rate with the best first. Asks offer
+/// `coin`, bids offer `want`. Prices are in `want` per `coin` and amounts in `coin` on both sides.
+pub fn exchange_get_depth(storage: &SqliteStorage, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to exchange_getDepth");
+
+    let coin = get_string(&params, "coin")?;
+    let want = get_string(&params, "want")?;
+
+    let asks = storage.exchange_get_open_orders(coin, want).map_err(internal_error)?;
+    let bids = storage.exchange_get_open_orders(want, coin).map_err(internal_error)?;
+
+    let result = json!({
+        "coin": coin,
+        "want": want,
+        "asks": levels(asks, false),
+        "bids": levels(bids, true)
+    });
+    Ok(result)
+}
+
+/// The most fills a single `exchange_getFills` call returns.
+const MAX_FILLS: u32 = 1000;
+
+pub fn exchange_get_fills(storage: &SqliteStorage, network: &Network, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to exchange_getFills");
+
+    let address = Bech32Address::new(get_string(&params, "address")?, network.clone())
+        .map_err(|_| Error::invalid_params("invalid address"))?;
+
+    // Larger limits get cut down to MAX_FILLS
+    let limit = match params.get("limit") {
+        Some(v) => v.as_u64().filter(|l| *l <= u32::max_value() as u64).ok_or(Error::invalid_params("invalid limit"))? as u32,
+        None => 100
+    }.min(MAX_FILLS);
+
+    let fills = storage.exchange_get_fills(&address, limit).map_err(internal_error)?;
+
+    let result = json!(fills);
+    Ok(result)
+}
+
+/// Sums up orders of the same rate. Bids are turned around so they read like asks.
+fn levels(mut orders: Vec<Order>, bids: bool) -> Vec<Value> {
+    orders.sort_by(exchange::by_rate);
+    let mut levels: Vec<((u64, u64), u64, u32)> = Vec::new();
+    for order in orders.iter() {
+        let divisor = gcd(order.amount, order.want_amount);
+        let rate = (order.amount / divisor, order.want_amount / divisor);
+        let amount = match bids {
+            false => order.remaining(),
+            true => (order.remaining() as u128 * order.want_amount as u128 / order.amount as u128) as u64
+        };
+        if levels.last().map_or(false, |level| level.0 == rate) {
+            if let Some(level) = levels.last_mut() {
+                level.1 += amount;
+                level.2 += 1;
+            }
+        } else {
+            levels.push((rate, amount, 1));
+        }
+    }
+    levels.into_iter().map(|((offered, asked), amount, orders)| {
+        let price = match bids {
+            false => asked as f64 / offered as f64,
+            true => offered as f64 / asked as f64
+        };
+        json!({"price": price, "amount": amount, "orders": orders})
+    }).collect()
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b)
+    }
+}
+
+fn internal_error(e: storage::Error) -> Error {
+    warn!("Internal error {:?}", e);
+    Error::internal_error()
+}
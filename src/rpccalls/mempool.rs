@@ -74,7 +74,28 @@ pub fn mempool_get_transaction_by_hash(storage: &SqliteStorage, network: &Networ
     Ok(result)
 }
 
+/// Tells why a transaction was dropped from the mempool without being mined.
+pub fn mempool_get_pruned_transaction(storage: &SqliteStorage, params: serde_json::Map<String, Value>) -> Result<Value> {
+    debug!("Received call to mempool_getPrunedTransaction");
+
+    let hash = params
+        .get("hash")
+        .ok_or(Error::invalid_params("hash missing"))?
+        .as_str()
+        .ok_or(Error::invalid_params("invalid hash"))?;
+
+    let pruned = storage.mempool_get_pruned(hash).map_err(|e| {
+        match e {
+            storage::Error::NotFound => errors::not_found(),
+            _ => Error::internal_error()
+        }
+    })?;
+
+    let result = json!(pruned);
+    Ok(result)
+}
+
 fn internal_error(e: storage::Error) -> Error {
-    println!("internal error {:?}", e);
+    warn!("Internal error {:?}", e);
     Error::internal_error()
 }
@@ -4,6 +4,9 @@ pub mod regtest;
 pub mod chain;
 pub mod tx;
 pub mod mempool;
+pub mod admin;
+pub mod coin;
+pub mod exchange;
 use jsonrpc_minihttp_server::jsonrpc_core::*;
 
 fn get_string<'a>(params: &'a serde_json::Map<String, Value>, name: &str) -> Result<&'a str> {
@@ -7,13 +7,16 @@ use storage::SqliteStorage;
 
 use kcoin;
 use ::kcoin::Network;
-use ::tx::TransactionEnvelope;
+use std::collections::HashMap;
+use ::tx::{TransactionEnvelope, Kind};
 use ::storage;
 
-pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, params: serde_json::Map<String, Value>) -> Result<Value> {
+/// Adds a transaction to the mempool. Version 1 transactions are only taken if `accept_v1` is
+/// set, they can be replayed on other chains.
+pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, accept_v1: bool, params: serde_json::Map<String, Value>) -> Result<Value> {
     debug!("Received call to tx_send");
-    println!("{:?}", params);
-    let tx = TransactionEnvelope::from_json(params, network).map_err(|e| {
+    debug!("Parameters {:?}", params);
+    let tx = TransactionEnvelope::from_json(params, network, storage.genesis_hash()).map_err(|e| {
         match e {
             ::tx::Error::InvalidField {field} => {
                 Error::invalid_params(format!("invalid parameter {}", field))
@@ -21,60 +24,103 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
             ::tx::Error::MissingField {field} => {
                 Error::invalid_params(format!("missing parameter {}", field))
             },
+            ::tx::Error::HashMismatch {expected} => {
+                errors::tx_hash_mismatch(&expected)
+            },
+            ::tx::Error::ChainMismatch {..} => {
+                errors::wrong_chain(storage.genesis_hash())
+            },
             _ => Error::internal_error()
         }
     })?;
-    println!("{:?}", tx);
+    debug!("Transaction {:?}", tx);
+
+    if tx.version == ::tx::VERSION_JSON && !accept_v1 {
+        return Err(errors::version_not_accepted(tx.version));
+    }
+
+    // Keys can be rotated, so the signatures are checked against the current key of the sender
+    if !storage.transaction_verify(&tx).map_err(internal_error)? {
+        return Err(Error::invalid_params("invalid parameter signature"));
+    }
 
     if storage.mempool_exists(&tx.hash).map_err(internal_error)? == true {
         return Err(errors::tx_known());
     }
 
-    let coin_exists = storage.coin_exists(&tx.tx.coin).map_err(internal_error)?;
-    println!("coin exists {:?}", coin_exists);
-    if !coin_exists && tx.tx.fee < kcoin::NEW_COIN_FEE {
-        return Err(errors::fee_too_low());
+    let next_height = storage.block_height().map_err(internal_error)? + 1;
+    if tx.tx.is_expired(next_height) {
+        return Err(errors::tx_expired(next_height));
     }
 
-    let address_balance = match storage.address_get_balance(&tx.tx.from.address, &tx.tx.coin).map_err(internal_error)? {
-        Some(v) => v,
-        None => 0
-    };
-    println!("address balance {:?}", address_balance);
-
-    let reserved_balance = match storage.address_get_reserved_balance(&tx.tx.from.address, &tx.tx.coin).map_err(internal_error)? {
-        Some(v) => v,
-        None => 0
-    };
-    println!("reserved balance {:?}", reserved_balance);
-
-    let knc_address_balance = match tx.tx.coin.as_ref() {
-        "KCN" => address_balance,
-        _ => {
-            match storage.address_get_balance(&tx.tx.from.address, "KCN").map_err(internal_error)? {
-                Some(v) => v,
-                None => 0
+    // Coins have to be registered with a create_coin transaction before they can be sent
+    match tx.tx.kind {
+        Kind::Transfer | Kind::Burn | Kind::HtlcLock { .. } => {
+            if !storage.coin_exists_in_chain(&tx.tx.coin).map_err(internal_error)? {
+                return Err(errors::unknown_coin(&tx.tx.coin));
             }
-        }
-    };
-    println!("knc address balance {:?}", knc_address_balance);
-
-    let knc_reserved_balance = match tx.tx.coin.as_ref() {
-        "KCN" => reserved_balance,
-        _ => {
-            match storage.address_get_reserved_balance(&tx.tx.from.address, "KCN").map_err(internal_error)? {
-                Some(v) => v,
-                None => 0
+        },
+        Kind::Batch { ref outputs } => {
+            for output in outputs.iter() {
+                if !storage.coin_exists_in_chain(&output.coin).map_err(internal_error)? {
+                    return Err(errors::unknown_coin(&output.coin));
+                }
             }
-        }
-    };
-    println!("knc reserved balance {:?}", knc_reserved_balance);
+        },
+        Kind::CreateCoin { .. } => {
+            if storage.coin_exists(&tx.tx.coin).map_err(internal_error)? {
+                return Err(errors::coin_exists(&tx.tx.coin));
+            }
+            if tx.tx.fee < kcoin::NEW_COIN_FEE {
+                return Err(errors::fee_too_low());
+            }
+        },
+        Kind::Mint | Kind::Pause { .. } | Kind::Freeze { .. } => {
+            let coin = storage.coin_get(&tx.tx.coin).map_err(|e| match e {
+                storage::Error::NotFound => errors::unknown_coin(&tx.tx.coin),
+                e => internal_error(e)
+            })?;
+            // KCN has a fixed supply and can't be stopped
+            if coin.ticker == "KCN" || coin.issuer != tx.tx.from.address {
+                return Err(errors::not_issuer(&coin.issuer));
+            }
+            // Pending mints count too, they could only be mined together otherwise
+            if let Kind::Mint = tx.tx.kind {
+                let pending = storage.mempool_minted(&coin.ticker).map_err(internal_error)?;
+                if coin.mint_exceeds_supply(pending.saturating_add(tx.tx.amount)) {
+                    return Err(errors::supply_exceeded(&coin.ticker));
+                }
+            }
+        },
+        Kind::PlaceOrder { ref want, .. } => {
+            for coin in [&tx.tx.coin, want].iter() {
+                if !storage.coin_exists_in_chain(coin).map_err(internal_error)? {
+                    return Err(errors::unknown_coin(coin));
+                }
+            }
+        },
+        Kind::HtlcClaim { .. } | Kind::HtlcRefund { .. } | Kind::CancelOrder { .. } | Kind::RotateKey { .. } => {},
+    }
+
+    // Claims and refunds depend on the state of their lock. A refund may wait in the mempool
+    // for the timeout if it isn't valid before it anyway.
+    let earliest_height = tx.tx.valid_after_height.map_or(next_height, |h| next_height.max(h.saturating_add(1)));
+    if let Some(problem) = storage.htlc_problem(&tx, earliest_height).map_err(internal_error)? {
+        return Err(errors::htlc_invalid(problem));
+    }
+    if let Some(problem) = storage.order_problem(&tx).map_err(internal_error)? {
+        return Err(errors::order_invalid(problem));
+    }
+
+    if let Some(restriction) = storage.coin_restriction(&tx).map_err(internal_error)? {
+        return Err(errors::coin_restricted(&restriction));
+    }
 
     let nonce_chain = storage.address_nonce_mined(&tx.tx.from).map_err(internal_error)?;
-    println!("address chain nonce {:?}", nonce_chain);
+    debug!("Mined nonce {:?}", nonce_chain);
 
     let nonce_mempool = storage.address_nonce_mempool(&tx.tx.from).map_err(internal_error)?;
-    println!("address chain mempool {:?}", nonce_mempool);
+    debug!("Mempool nonce {:?}", nonce_mempool);
 
     let last_nonce = nonce_mempool.or(nonce_chain);
     let next_nonce = match last_nonce {
@@ -87,7 +133,7 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
         Some(v) => v + 1
     };
 
-    println!("last nonce {:?} next nonce {:?} mempool_min {:?}", last_nonce, next_nonce, mempool_min);
+    debug!("Last nonce {:?} next nonce {} mempool min {}", last_nonce, next_nonce, mempool_min);
 
     // Deny when the new tx would leave a nonce gap
     if tx.tx.nonce > next_nonce {
@@ -110,23 +156,17 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
         }
 
         // check if he has enough balance if we replace the tx with the new one.
-        if (&tx.tx.coin == "KCN" && address_balance < reserved_balance - current_tx.tx.fee - current_tx.tx.amount + tx.tx.fee + tx.tx.amount)
-                || (coin_exists && &tx.tx.coin != "KCN" && address_balance < reserved_balance - current_tx.tx.amount + tx.tx.amount
-                    || knc_address_balance < knc_reserved_balance - current_tx.tx.fee + tx.tx.fee
-                )
-             {
-            return Err(errors::insufficient_balance());
-        }
+        check_balance(storage, &tx, Some(&current_tx))?;
 
         // delete existing tx from mempool. adding the new one happens after this if statement.
         storage.mempool_remove(&current_tx.hash).map_err(internal_error)?;
         storage.mempool_add(&tx).map_err(internal_error)?;
-        println!("replaced");
+        debug!("Replaced transaction {}", current_tx.hash);
         return Ok(json!({}));
     }
 
     let mempool_count = storage.mempool_count().map_err(internal_error)?;
-    println!("mempool count {:?}", mempool_count);
+    debug!("Mempool count {}", mempool_count);
     if mempool_count >= mempool_size.into() {
         // Mempool is full. See if it's worth it to evict another tx for this one.
         // To calculate this, we take the sum of all fees per address currently in the
@@ -146,10 +186,10 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
         match res.map_err(internal_error)? {
             Some((lowest_fee_sum, tx_count, address)) => {
                 if tx.tx.fee > lowest_fee_sum {
-                    println!("evicting {:?} transactions from {:?} from mempool for {:?}. old_amount={:?} new_amount={:?}", tx_count, address, tx.tx.from.address, lowest_fee_sum, tx.tx.fee);
+                    debug!("Evicting {} transactions of {} from the mempool for {}, fee sum {} new fee {}", tx_count, address, tx.tx.from.address, lowest_fee_sum, tx.tx.fee);
                     storage.mempool_evict(&address).map_err(internal_error)?;
                 } else {
-                    println!("won't evict {:?} transactions from {:?} from mempool for {:?}. old_amount={:?} new_amount={:?}", tx_count, address, tx.tx.from.address, lowest_fee_sum, tx.tx.fee);
+                    debug!("Not evicting {} transactions of {} from the mempool for {}, fee sum {} new fee {}", tx_count, address, tx.tx.from.address, lowest_fee_sum, tx.tx.fee);
                     return Err(errors::mempool_full());
                 }
             }
@@ -163,16 +203,7 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
     }
 
     // check balance
-    if (&tx.tx.coin == "KCN" && address_balance < reserved_balance + tx.tx.fee + tx.tx.amount)
-        || (coin_exists && &tx.tx.coin != "KCN" && address_balance < reserved_balance + tx.tx.amount
-                || knc_address_balance < knc_reserved_balance + tx.tx.fee
-            )
-         {
-        return Err(errors::insufficient_balance());
-    }
-
-    println!("balance comp {:?} {:?}", &tx.tx.coin == "KCN" && address_balance < reserved_balance + tx.tx.fee + tx.tx.amount, &tx.tx.coin != "KCN" && address_balance < reserved_balance + tx.tx.amount
-        && knc_address_balance < knc_reserved_balance + tx.tx.fee);
+    check_balance(storage, &tx, None)?;
 
     storage.mempool_add(&tx).map_err(internal_error)?;
 
@@ -180,7 +211,49 @@ pub fn tx_send(storage: &SqliteStorage, network: &Network, mempool_size: u64, pa
     Ok(result)
 }
 
+/// Checks that the sender can pay for `tx` in every coin it spends, on top of what their pending
+/// transactions already reserve. `replaced` is the mempool tx that `tx` is about to replace, its
+/// amounts are freed. A separate fee payer has to be able to pay the fee the same way.
+fn check_balance(storage: &SqliteStorage, tx: &TransactionEnvelope, replaced: Option<&TransactionEnvelope>) -> Result<()> {
+    if let Some(ref fee_payer) = tx.tx.fee_payer {
+        let mut required = storage.address_get_reserved_balance(fee_payer, "KCN").map_err(internal_error)?.unwrap_or(0);
+        if let Some(replaced) = replaced {
+            if replaced.tx.fee_paid_by().address == fee_payer.address {
+                required = required.saturating_sub(replaced.tx.fee);
+            }
+        }
+        let balance = storage.address_get_balance(&fee_payer.address, "KCN").map_err(internal_error)?.unwrap_or(0);
+        debug!("Fee payer balance {} required {}", balance, required + tx.tx.fee);
+        if balance < required + tx.tx.fee {
+            return Err(errors::insufficient_balance());
+        }
+    }
+
+    let mut required: HashMap<String, u64> = HashMap::new();
+    for reserved in storage.address_get_reserved_balances(&tx.tx.from).map_err(internal_error)? {
+        required.insert(reserved.coin, reserved.balance);
+    }
+    if let Some(replaced) = replaced {
+        for (coin, amount) in replaced.tx.debits() {
+            let entry = required.entry(coin).or_insert(0);
+            *entry = entry.saturating_sub(amount);
+        }
+    }
+    for (coin, amount) in tx.tx.debits() {
+        *required.entry(coin).or_insert(0) += amount;
+    }
+
+    for coin in tx.tx.debits().into_iter().map(|(coin, _)| coin) {
+        let balance = storage.address_get_balance(&tx.tx.from.address, &coin).map_err(internal_error)?.unwrap_or(0);
+        debug!("Balance {} {} required {}", coin, balance, required[&coin]);
+        if balance < required[&coin] {
+            return Err(errors::insufficient_balance());
+        }
+    }
+    Ok(())
+}
+
 fn internal_error(e: storage::Error) -> Error {
-    println!("internal error {:?}", e);
+    warn!("Internal error {:?}", e);
     Error::internal_error()
 }
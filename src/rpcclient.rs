@@ -0,0 +1,137 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use serde_json;
+use serde_json::Value;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "invalid url {}", url)]
+    InvalidUrl {
+        url: String
+    },
+    #[fail(display = "connection failed: {}", message)]
+    Connection {
+        message: String
+    },
+    #[fail(display = "invalid response: {}", message)]
+    InvalidResponse {
+        message: String
+    },
+    #[fail(display = "rpc error {}: {}", code, message)]
+    Rpc {
+        code: i64,
+        message: String
+    },
+}
+
+/// A minimal JSON-RPC client speaking plain HTTP/1.1 to another kcoin node.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    host: String,
+    path: String
+}
+
+impl RpcClient {
+    /// Accepts urls of the form `http://host:port/path`. The path is optional.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let rest = if url.starts_with("http://") {
+            &url[7..]
+        } else {
+            return Err(Error::InvalidUrl { url: url.to_owned() });
+        };
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/")
+        };
+        if host.len() == 0 {
+            return Err(Error::InvalidUrl { url: url.to_owned() });
+        }
+        let host = match host.contains(':') {
+            true => host.to_owned(),
+            false => format!("{}:80", host)
+        };
+        Ok(RpcClient { host, path: path.to_owned() })
+    }
+
+    pub fn call(&self, method: &str, params: Value) -> Result<Value, Error> {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params
+        }).to_string();
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path, self.host, body.len(), body
+        );
+
+        let connection_error = |e: ::std::io::Error| Error::Connection { message: e.to_string() };
+        let mut stream = TcpStream::connect(&self.host).map_err(connection_error)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30))).map_err(connection_error)?;
+        stream.write_all(request.as_bytes()).map_err(connection_error)?;
+
+        let body = read_response(&mut stream)?;
+        let response: Value = serde_json::from_slice(&body)
+            .map_err(|e| Error::InvalidResponse { message: e.to_string() })?;
+        if let Some(error) = response.get("error") {
+            return Err(Error::Rpc {
+                code: error.get("code").and_then(|v| v.as_i64()).unwrap_or(0),
+                message: error.get("message").and_then(|v| v.as_str()).unwrap_or("").to_owned()
+            });
+        }
+        response.get("result").cloned().ok_or(Error::InvalidResponse { message: "no result".to_owned() })
+    }
+}
+
+/// Reads one HTTP response and returns its body. The body is read up to `Content-Length` if the
+/// server sends one, otherwise until the server closes the connection.
+fn read_response(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let invalid = |message: &str| Error::InvalidResponse { message: message.to_owned() };
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let header_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        let n = stream.read(&mut buf).map_err(|e| Error::Connection { message: e.to_string() })?;
+        if n == 0 {
+            return Err(invalid("connection closed before headers"));
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let headers = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = headers.split("\r\n");
+    let status = lines.next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(Error::InvalidResponse { message: status.to_owned() });
+    }
+    let content_length = lines
+        .filter_map(|l| {
+            let mut parts = l.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("content-length") => value.trim().parse::<usize>().ok(),
+                _ => None
+            }
+        })
+        .next();
+
+    let mut body = data.split_off(header_end + 4);
+    match content_length {
+        Some(length) => {
+            while body.len() < length {
+                let n = stream.read(&mut buf).map_err(|e| Error::Connection { message: e.to_string() })?;
+                if n == 0 {
+                    return Err(invalid("connection closed before end of body"));
+                }
+                body.extend_from_slice(&buf[..n]);
+            }
+            body.truncate(length);
+        },
+        None => {
+            stream.read_to_end(&mut body).map_err(|e| Error::Connection { message: e.to_string() })?;
+        }
+    }
+    Ok(body)
+}
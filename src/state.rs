@@ -0,0 +1,59 @@
+use storage::AddressBalance;
+use merkle;
+use hex;
+
+/// The leaf committing to one balance. Addresses and coin tickers never contain `:`.
+pub fn leaf(address: &str, coin: &str, balance: i64) -> Vec<u8> {
+    format!("{}:{}:{}", address, coin, balance).into_bytes()
+}
+
+/// Computes the state root over all balances.
+/// `balances` have to be sorted by address and coin, as returned by `balance_get_all_with_conn`.
+pub fn root(balances: &[AddressBalance]) -> String {
+    hex::encode(merkle::root(&leaves(balances)))
+}
+
+/// The state tree of one block. Built once from all balances, after that proofs cost a lookup.
+pub struct Tree {
+    balances: Vec<AddressBalance>,
+    tree: merkle::Tree
+}
+
+impl Tree {
+    /// `balances` have to be sorted like for `root`.
+    pub fn new(balances: Vec<AddressBalance>) -> Self {
+        let tree = merkle::Tree::new(&leaves(&balances));
+        Tree { balances, tree }
+    }
+
+    pub fn root(&self) -> String {
+        hex::encode(self.tree.root())
+    }
+
+    /// The balances of `address`, sorted by coin.
+    pub fn balances_of(&self, address: &str) -> Vec<&AddressBalance> {
+        let start = match self.balances.binary_search_by(|b| (b.address.as_str(), b.coin.as_str()).cmp(&(address, ""))) {
+            Ok(i) | Err(i) => i
+        };
+        self.balances[start..].iter().take_while(|b| b.address == address).collect()
+    }
+
+    /// Returns the balance of `address` in `coin` together with the sibling path to the state root.
+    pub fn proof(&self, address: &str, coin: &str) -> Option<(i64, Vec<merkle::ProofStep>)> {
+        // Sorted by the database in byte order, which is how str compares as well
+        let index = self.balances
+            .binary_search_by(|b| (b.address.as_str(), b.coin.as_str()).cmp(&(address, coin)))
+            .ok()?;
+        let steps = self.tree.proof(index)?;
+        Some((self.balances[index].balance, steps))
+    }
+}
+
+/// Checks a balance proof returned by `chain_addressInfo` against a block's state root.
+pub fn verify(address: &str, coin: &str, balance: i64, proof: &[merkle::ProofStep], root: &str) -> bool {
+    merkle::verify(&hex::encode(leaf(address, coin, balance)), proof, root)
+}
+
+fn leaves(balances: &[AddressBalance]) -> Vec<Vec<u8>> {
+    balances.iter().map(|b| leaf(&b.address, &b.coin, b.balance)).collect()
+}
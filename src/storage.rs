@@ -4,15 +4,26 @@ use rusqlite::{NO_PARAMS};
 use r2d2_sqlite::SqliteConnectionManager;
 use r2d2::{Pool, PooledConnection};
 use std::time::{SystemTime};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::sync::{Arc, Mutex};
+use kcoin;
 use kcoin::Bech32Address;
 use rusqlite;
 use rusqlite::Row;
-use tx::{TransactionEnvelope, Transaction, MinedTx};
+use serde_json;
+use tx::{TransactionEnvelope, Transaction, MinedTx, Kind};
+use tx;
+use multisig::Multisig;
 use block::Block;
+use block;
+use genesis::Genesis;
+use state;
 use ::kcoin::Network;
 use std::convert::From;
+use std::fmt;
 use time::Timespec;
+use ed25519_dalek::PublicKey;
+use hex;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -45,12 +56,23 @@ pub enum Error {
     #[fail(display = "internal error")]
     InternalError,
     #[fail(display = "not found")]
-    NotFound
+    NotFound,
+    #[fail(display = "datadir was created from a different genesis")]
+    GenesisMismatch,
+    #[fail(display = "datadir belongs to a chain signed by producer key {}", pinned)]
+    ProducerKeyMismatch {
+        pinned: String
+    },
+    #[fail(display = "invalid block {}: {}", height, reason)]
+    InvalidBlock {
+        height: u32,
+        reason: String
+    }
 }
 
 impl From<rusqlite::Error> for Error {
     fn from(error: rusqlite::Error) -> Self {
-        println!("converting {:?}", error);
+        debug!("Database error {:?}", error);
         match error {
             rusqlite::Error::QueryReturnedNoRows => Error::NotFound,
             _ => Error::QueryError {message: error.to_string()}
@@ -64,9 +86,98 @@ impl From<::kcoin::KCoinError> for Error {
     }
 }
 
+/// Schema changes since the first release, in the order they were made. A database records how
+/// many of them it has seen in `schema_version`. Only ever append to this list. Columns added to
+/// existing tables get defaults that describe the rows written before them.
+const MIGRATIONS: &[&str] = &[
+    // 1: blocks are chained, commit to their transactions and the balances and are signed
+    "ALTER TABLE `block` ADD COLUMN `prev_hash` TEXT DEFAULT '';
+    ALTER TABLE `block` ADD COLUMN `merkle_root` TEXT DEFAULT '';
+    ALTER TABLE `block` ADD COLUMN `state_root` TEXT DEFAULT '';
+    ALTER TABLE `block` ADD COLUMN `signature` TEXT DEFAULT '';",
+
+    // 2: signing versions, transaction types, multisig, validity windows and fee payers. Older
+    // transactions are version 1 transfers without any of them.
+    r#"ALTER TABLE `transaction` ADD COLUMN `version` INTEGER DEFAULT 1;
+    ALTER TABLE `transaction` ADD COLUMN `type` TEXT DEFAULT 'transfer';
+    ALTER TABLE `transaction` ADD COLUMN `data` TEXT DEFAULT '{"type":"transfer"}';
+    ALTER TABLE `transaction` ADD COLUMN `multisig` TEXT DEFAULT '';
+    ALTER TABLE `transaction` ADD COLUMN `valid_after_height` INTEGER DEFAULT 0;
+    ALTER TABLE `transaction` ADD COLUMN `valid_after_time` INTEGER DEFAULT 0;
+    ALTER TABLE `transaction` ADD COLUMN `expires_at_height` INTEGER DEFAULT 0;
+    ALTER TABLE `transaction` ADD COLUMN `fee_payer` TEXT DEFAULT '';
+    ALTER TABLE `transaction` ADD COLUMN `fee_payer_signature` TEXT DEFAULT '';
+
+    ALTER TABLE `mempool` ADD COLUMN `version` INTEGER DEFAULT 1;
+    ALTER TABLE `mempool` ADD COLUMN `type` TEXT DEFAULT 'transfer';
+    ALTER TABLE `mempool` ADD COLUMN `data` TEXT DEFAULT '{"type":"transfer"}';
+    ALTER TABLE `mempool` ADD COLUMN `multisig` TEXT DEFAULT '';
+    ALTER TABLE `mempool` ADD COLUMN `valid_after_height` INTEGER DEFAULT 0;
+    ALTER TABLE `mempool` ADD COLUMN `valid_after_time` INTEGER DEFAULT 0;
+    ALTER TABLE `mempool` ADD COLUMN `expires_at_height` INTEGER DEFAULT 0;
+    ALTER TABLE `mempool` ADD COLUMN `fee_payer` TEXT DEFAULT '';
+    ALTER TABLE `mempool` ADD COLUMN `fee_payer_signature` TEXT DEFAULT '';
+    CREATE INDEX IF NOT EXISTS `mempool_expires_at_height` ON `mempool`(`expires_at_height`);
+    CREATE INDEX IF NOT EXISTS `mempool_fee_payer` ON `mempool`(`fee_payer`);"#,
+
+    // 3: tables of the features built on top of the chain
+    "CREATE TABLE IF NOT EXISTS `mempool_pruned` (`hash` TEXT, `from` TEXT, `nonce` BIGINT, `height` INTEGER, `time` INTEGER, `reason` TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `mempool_pruned_hash` ON `mempool_pruned`(`hash`);
+
+    CREATE TABLE IF NOT EXISTS `transaction_output` (`hash` TEXT, `index` INTEGER, `to` TEXT, `coin` TEXT, `amount` BIGINT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `transaction_output_hash_index` ON `transaction_output`(`hash`, `index`);
+    CREATE INDEX IF NOT EXISTS `transaction_output_to` ON `transaction_output`(`to`);
+
+    CREATE TABLE IF NOT EXISTS `coin` (`ticker` TEXT, `name` TEXT, `issuer` TEXT, `decimals` INTEGER, `initial_supply` BIGINT, `height` INTEGER, `hash` TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `coin_ticker` ON `coin`(`ticker`);
+    CREATE INDEX IF NOT EXISTS `coin_height` ON `coin`(`height`);
+
+    CREATE TABLE IF NOT EXISTS `coin_control` (`coin` TEXT, `address` TEXT, `active` INTEGER, `height` INTEGER, `hash` TEXT);
+    CREATE INDEX IF NOT EXISTS `coin_control_coin_address` ON `coin_control`(`coin`, `address`);
+    CREATE INDEX IF NOT EXISTS `coin_control_height` ON `coin_control`(`height`);
+
+    CREATE TABLE IF NOT EXISTS `htlc` (`hash` TEXT, `from` TEXT, `to` TEXT, `coin` TEXT, `amount` BIGINT, `hashlock` TEXT, `timeout_height` INTEGER, `height` INTEGER, `state` TEXT, `settled_hash` TEXT, `settled_height` INTEGER, `preimage` TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `htlc_hash` ON `htlc`(`hash`);
+    CREATE INDEX IF NOT EXISTS `htlc_from` ON `htlc`(`from`);
+    CREATE INDEX IF NOT EXISTS `htlc_to` ON `htlc`(`to`);
+    CREATE INDEX IF NOT EXISTS `htlc_height` ON `htlc`(`height`);
+    CREATE INDEX IF NOT EXISTS `htlc_settled_height` ON `htlc`(`settled_height`);
+
+    CREATE TABLE IF NOT EXISTS `exchange_order` (`hash` TEXT, `from` TEXT, `coin` TEXT, `amount` BIGINT, `want` TEXT, `want_amount` BIGINT, `height` INTEGER, `index` INTEGER, `state` TEXT, `closed_height` INTEGER);
+    CREATE UNIQUE INDEX IF NOT EXISTS `exchange_order_hash` ON `exchange_order`(`hash`);
+    CREATE INDEX IF NOT EXISTS `exchange_order_pair_state` ON `exchange_order`(`coin`, `want`, `state`);
+    CREATE INDEX IF NOT EXISTS `exchange_order_height` ON `exchange_order`(`height`);
+    CREATE INDEX IF NOT EXISTS `exchange_order_closed_height` ON `exchange_order`(`closed_height`);
+
+    CREATE TABLE IF NOT EXISTS `exchange_fill` (`height` INTEGER, `order_hash` TEXT, `counter_hash` TEXT, `address` TEXT, `coin` TEXT, `amount` BIGINT, `want` TEXT, `received` BIGINT, `maker` INTEGER);
+    CREATE INDEX IF NOT EXISTS `exchange_fill_order_hash` ON `exchange_fill`(`order_hash`);
+    CREATE INDEX IF NOT EXISTS `exchange_fill_address` ON `exchange_fill`(`address`);
+    CREATE INDEX IF NOT EXISTS `exchange_fill_height` ON `exchange_fill`(`height`);
+
+    CREATE TABLE IF NOT EXISTS `address_key` (`address` TEXT, `key` TEXT, `height` INTEGER, `hash` TEXT);
+    CREATE INDEX IF NOT EXISTS `address_key_address` ON `address_key`(`address`);
+    CREATE INDEX IF NOT EXISTS `address_key_height` ON `address_key`(`height`);
+
+    CREATE TABLE IF NOT EXISTS `balance_history` (`address` TEXT, `coin` TEXT, `block` INTEGER, `balance` BIGINT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `balance_history_address_coin_block` ON `balance_history`(`address`, `coin`, `block`);
+    CREATE INDEX IF NOT EXISTS `balance_history_block` ON `balance_history`(`block`);
+
+    CREATE TABLE IF NOT EXISTS `rollback_log` (`time` INTEGER, `operator` TEXT, `from_height` INTEGER, `to_height` INTEGER, `tx_hashes` TEXT, `returned_to_mempool` INTEGER);",
+
+    // 4: facts about the local chain, see `chain_info_get_with_conn`
+    "CREATE TABLE IF NOT EXISTS `chain_info` (`key` TEXT, `value` TEXT);
+    CREATE UNIQUE INDEX IF NOT EXISTS `chain_info_key` ON `chain_info`(`key`);",
+
+    // 5: the operator of a rollback is only what the caller claims, the source says who could claim it
+    "ALTER TABLE `rollback_log` ADD COLUMN `source` TEXT DEFAULT '';",
+];
+
 pub struct SqliteStorage {
     pool: Pool<SqliteConnectionManager>,
-    knc_address: Bech32Address
+    knc_address: Bech32Address,
+    genesis_hash: String,
+    // The state tree of the block with this hash, shared by all clones
+    state_tree: Arc<Mutex<Option<(String, Arc<state::Tree>)>>>
 }
 
 pub struct Balance {
@@ -74,6 +185,12 @@ pub struct Balance {
     pub balance: u64
 }
 
+pub struct AddressBalance {
+    pub address: String,
+    pub coin: String,
+    pub balance: i64
+}
+
 pub struct MempoolStats {
     pub count: u32,
     pub min_fee: u32,
@@ -81,12 +198,174 @@ pub struct MempoolStats {
     pub avg_fee: u32
 }
 
+/// A registered coin. `supply` is what all addresses hold together right now, funds in open hash
+/// time locks and open orders included, that is the initial supply plus everything minted minus
+/// everything burned. The other fields are fixed when the coin gets created. Coins from the
+/// genesis have no creation transaction. While a coin is `paused` none of it moves.
+#[derive(Debug, Serialize)]
+pub struct Coin {
+    pub ticker: String,
+    pub name: String,
+    pub issuer: String,
+    pub decimals: u8,
+    pub initial_supply: u64,
+    pub minted: u64,
+    pub burned: u64,
+    pub supply: u64,
+    pub paused: bool,
+    pub height: u32,
+    pub hash: String
+}
+
+impl Coin {
+    /// Whether minting `amount` more would take the supply past what a balance can hold.
+    pub fn mint_exceeds_supply(&self, amount: u64) -> bool {
+        self.supply.checked_add(amount).map_or(true, |supply| supply > i64::max_value() as u64)
+    }
+}
+
+/// Why a transaction can't move a coin.
+#[derive(Debug)]
+pub enum Restriction {
+    Paused { coin: String },
+    Frozen { coin: String, address: String }
+}
+
+impl fmt::Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Restriction::Paused { coin } => write!(f, "{} is paused", coin),
+            Restriction::Frozen { coin, address } => write!(f, "{} is frozen for {}", coin, address)
+        }
+    }
+}
+
+// Columns read by `coin_from_row`. Supply, minted and burned are derived from the chain, so they
+// follow rollbacks without extra bookkeeping.
+const COIN_COLUMNS: &str = "c.`ticker`, c.`name`, c.`issuer`, c.`decimals`, c.`initial_supply`, c.`height`, c.`hash`, \
+    (SELECT ifnull(SUM(b.`balance`), 0) FROM `address_balance` b WHERE b.`coin` = c.`ticker`) \
+        + (SELECT ifnull(SUM(h.`amount`), 0) FROM `htlc` h WHERE h.`coin` = c.`ticker` AND h.`state` = 'locked') \
+        + (SELECT ifnull(SUM(o.`amount`), 0) FROM `exchange_order` o WHERE o.`coin` = c.`ticker` AND o.`state` = 'open') \
+        - (SELECT ifnull(SUM(f.`amount`), 0) FROM `exchange_fill` f JOIN `exchange_order` o ON o.`hash` = f.`order_hash` WHERE o.`coin` = c.`ticker` AND o.`state` = 'open'), \
+    (SELECT ifnull(SUM(t.`amount`), 0) FROM `transaction` t WHERE t.`type` = 'mint' AND t.`coin` = c.`ticker`), \
+    (SELECT ifnull(SUM(t.`amount`), 0) FROM `transaction` t WHERE t.`type` = 'burn' AND t.`coin` = c.`ticker`), \
+    ifnull((SELECT p.`active` FROM `coin_control` p WHERE p.`coin` = c.`ticker` AND p.`address` = '' ORDER BY p.`rowid` DESC LIMIT 1), 0)";
+
+/// Funds held by an `htlc_lock` transaction, named by its hash. `state` is `locked` until a claim
+/// or refund settles it. A claim reveals the `preimage`.
+#[derive(Debug, Serialize)]
+pub struct Htlc {
+    pub hash: String,
+    pub from: String,
+    pub to: String,
+    pub coin: String,
+    pub amount: u64,
+    pub hashlock: String,
+    pub timeout_height: u32,
+    pub height: u32,
+    pub state: String,
+    pub settled_hash: String,
+    pub settled_height: u32,
+    pub preimage: String
+}
+
+const HTLC_COLUMNS: &str = "`hash`, `from`, `to`, `coin`, `amount`, `hashlock`, `timeout_height`, `height`, `state`, `settled_hash`, `settled_height`, `preimage`";
+
+impl Htlc {
+    /// Tells why `tx` can't settle this lock in the block at `height`, if it can't.
+    pub fn settle_problem(&self, tx: &Transaction, height: u32) -> Option<&'static str> {
+        if self.state != "locked" {
+            return Some("lock is settled already");
+        }
+        match tx.kind {
+            Kind::HtlcClaim { ref preimage, .. } => {
+                if tx.from.address != self.to {
+                    Some("only the recipient can claim")
+                } else if height >= self.timeout_height {
+                    Some("lock has timed out")
+                } else if !tx::preimage_matches(preimage, &self.hashlock) {
+                    Some("preimage does not match the hashlock")
+                } else {
+                    None
+                }
+            },
+            Kind::HtlcRefund { .. } => {
+                if tx.from.address != self.from {
+                    Some("only the sender can refund")
+                } else if height < self.timeout_height {
+                    Some("lock has not timed out yet")
+                } else {
+                    None
+                }
+            },
+            _ => Some("not a claim or refund")
+        }
+    }
+}
+
+/// An order placed on the exchange, named by the hash of its `place_order` transaction. It offers
+/// `amount` of `coin` for `want_amount` of `want`. `filled` is how much of `amount` went to other
+/// orders so far, `received` what came back for it. Both are derived from the fills. `state` is
+/// `open` until the order is `filled` or `cancelled` at `closed_height`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Order {
+    pub hash: String,
+    pub from: String,
+    pub coin: String,
+    pub amount: u64,
+    pub want: String,
+    pub want_amount: u64,
+    pub filled: u64,
+    pub received: u64,
+    pub height: u32,
+    pub index: u32,
+    pub state: String,
+    pub closed_height: u32
+}
+
+const ORDER_COLUMNS: &str = "o.`hash`, o.`from`, o.`coin`, o.`amount`, o.`want`, o.`want_amount`, o.`height`, o.`index`, o.`state`, o.`closed_height`, \
+    (SELECT ifnull(SUM(f.`amount`), 0) FROM `exchange_fill` f WHERE f.`order_hash` = o.`hash`), \
+    (SELECT ifnull(SUM(f.`received`), 0) FROM `exchange_fill` f WHERE f.`order_hash` = o.`hash`)";
+
+impl Order {
+    /// What is left of `amount`.
+    pub fn remaining(&self) -> u64 {
+        self.amount.saturating_sub(self.filled)
+    }
+}
+
+/// One side of a match between two orders: the owner of `order_hash` gave `amount` of `coin` and
+/// received `received` of `want`. The `maker` is the older order, the match happened at its rate.
+#[derive(Debug, Serialize)]
+pub struct Fill {
+    pub height: u32,
+    pub order_hash: String,
+    pub counter_hash: String,
+    pub address: String,
+    pub coin: String,
+    pub amount: u64,
+    pub want: String,
+    pub received: u64,
+    pub maker: bool
+}
+
+/// A transaction that was dropped from the mempool without being mined.
+#[derive(Debug, Serialize)]
+pub struct PrunedTx {
+    pub hash: String,
+    pub from: String,
+    pub nonce: u64,
+    pub height: u32,
+    pub time: i64,
+    pub reason: String
+}
+
 impl SqliteStorage {
-    pub fn new(dir: &Path, regtest: bool, knc_address: Bech32Address, knc_supply: u64) -> Result<Self, Error> {
+    pub fn new(dir: &Path, regtest: bool, genesis: &Genesis) -> Result<Self, Error> {
         let manager = match regtest {
             false => {
                 if !dir.exists() {
-                    println!("{:?}", dir);
+                    debug!("Creating datadir {:?}", dir);
                     fs::create_dir(&dir).map_err(|_| Error::DataDirNotWriteable) ?;
                 }
                 let mut db_file_name = dir.to_path_buf();
@@ -97,7 +376,7 @@ impl SqliteStorage {
             },
             true => {
                 if !dir.exists() {
-                    println!("{:?}", dir);
+                    debug!("Creating datadir {:?}", dir);
                     fs::create_dir(&dir).map_err(|_| Error::DataDirNotWriteable) ?;
                 }
                 let mut db_file_name = dir.to_path_buf();
@@ -108,8 +387,21 @@ impl SqliteStorage {
             }
         };
         let pool = Pool::new(manager).map_err(|_| Error::CannotOpenDb)?;
+        SqliteStorage::init(pool, genesis)
+    }
+
+    /// Opens a throwaway database that only lives in memory. Used to replay the chain.
+    /// The pool holds a single connection since every in-memory connection is its own database.
+    pub fn new_in_memory(genesis: &Genesis) -> Result<Self, Error> {
+        let manager = SqliteConnectionManager::memory();
+        let pool = Pool::builder().max_size(1).build(manager).map_err(|_| Error::CannotOpenDb)?;
+        SqliteStorage::init(pool, genesis)
+    }
+
+    fn init(pool: Pool<SqliteConnectionManager>, genesis: &Genesis) -> Result<Self, Error> {
         let conn = pool.get().map_err(|_| Error::CannotOpenDb)?;
         // create schema
+        // These are the tables of the first release. Everything added since is in `MIGRATIONS`.
         conn.execute_batch(
             "BEGIN;
                 CREATE TABLE IF NOT EXISTS `block` (`height` INTEGER, `hash` TEXT, `time` INTEGER);
@@ -140,24 +432,329 @@ impl SqliteStorage {
 
                 CREATE TABLE IF NOT EXISTS `address_balance` (`address` TEXT, `coin` TEXT, `balance` BIGINT);
                 CREATE UNIQUE INDEX IF NOT EXISTS `address_balance_address_coin` ON `address_balance`(`address`, `coin`);
+
+                CREATE TABLE IF NOT EXISTS `schema_version` (`version` INTEGER);
                 COMMIT;",
         ).map_err(|e| Error::CannotCreateSchema{message: e.to_string()})?;
+        SqliteStorage::migrate(&conn)?;
 
-        let mut stmt = conn
-            .prepare("SELECT count(*) FROM `address_balance` WHERE `coin` = 'KCN'")
-            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+        drop(conn);
 
-        let count: u32 = stmt
-            .query_row(NO_PARAMS, |row| row.get(0))
-            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+        let storage = SqliteStorage {pool, knc_address: genesis.fee_address.clone(), genesis_hash: genesis.hash.clone(), state_tree: Arc::new(Mutex::new(None))};
+        storage.genesis_init(genesis)?;
+        Ok(storage)
+    }
+
+    /// Applies the migrations the database hasn't seen yet. Each one runs in its own db
+    /// transaction together with the update of `schema_version`.
+    fn migrate(conn: &rusqlite::Connection) -> Result<(), Error> {
+        let version: u32 = match conn.query_row("SELECT `version` FROM `schema_version` LIMIT 1", NO_PARAMS, |row| row.get(0)) {
+            Ok(v) => v,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                conn.execute("INSERT INTO `schema_version` (`version`) VALUES (0)", NO_PARAMS)
+                    .map_err(|e| Error::CannotCreateSchema{message: e.to_string()})?;
+                0
+            },
+            Err(e) => return Err(Error::CannotCreateSchema{message: e.to_string()})
+        };
+        if version as usize > MIGRATIONS.len() {
+            return Err(Error::CannotCreateSchema{message: format!("schema version {} is newer than this node", version)});
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            info!("Migrating database schema to version {}", i + 1);
+            conn.execute_batch(&format!("BEGIN; {} UPDATE `schema_version` SET `version` = {}; COMMIT;", migration, i + 1))
+                .map_err(|e| {
+                    let _ = conn.execute_batch("ROLLBACK;");
+                    Error::CannotCreateSchema{message: format!("migration {} failed: {}", i + 1, e)}
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Applies the genesis allocations and creates the genesis block on a fresh database.
+    /// A database that already has a chain has to have been created from the same genesis.
+    fn genesis_init(&self, genesis: &Genesis) -> Result<(), Error> {
+        let conn = self.get_conn()?;
+        let block_count: u32 = conn.query_row("SELECT count(*) FROM `block`", NO_PARAMS, |row| row.get(0))?;
+        let balance_count: u32 = conn.query_row("SELECT count(*) FROM `address_balance`", NO_PARAMS, |row| row.get(0))?;
+
+        if block_count > 0 || balance_count > 0 {
+            return match self.block_get_by_height_with_conn(&conn, 0) {
+                Ok(ref b) if b.prev_hash == genesis.hash => Ok(()),
+                Ok(_) => Err(Error::GenesisMismatch),
+                Err(Error::NotFound) => {
+                    self.start_transaction(&conn)?;
+                    match self.genesis_upgrade_legacy_with_conn(&conn, genesis) {
+                        Ok(_) => self.commit_transaction(&conn),
+                        Err(e) => {
+                            self.rollback_transaction(&conn)?;
+                            Err(e)
+                        }
+                    }
+                },
+                Err(e) => Err(e)
+            };
+        }
+
+        self.start_transaction(&conn)?;
+        match self.genesis_init_with_conn(&conn, genesis) {
+            Ok(_) => self.commit_transaction(&conn),
+            Err(e) => {
+                self.rollback_transaction(&conn)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn genesis_init_with_conn(&self, conn: &rusqlite::Connection, genesis: &Genesis) -> Result<(), Error> {
+        let mut balances: BTreeMap<(&str, &str), u64> = BTreeMap::new();
+        for allocation in genesis.allocations.iter() {
+            *balances.entry((allocation.address.as_str(), allocation.coin.as_str())).or_insert(0) += allocation.amount;
+        }
+        for ((address, coin), amount) in balances.iter() {
+            conn.execute(
+                "INSERT INTO `address_balance` (`address`, `coin`, `balance`) VALUES (?1, ?2, ?3)",
+                &[address, coin, &amount.to_string().as_str()]
+            )?;
+        }
+        // Every coin of the genesis is issued by the address of its first allocation. KCN always
+        // exists, even if nothing was allocated.
+        let mut coins: Vec<Coin> = Vec::new();
+        for allocation in genesis.allocations.iter() {
+            match coins.iter_mut().find(|c| c.ticker == allocation.coin) {
+                Some(coin) => coin.initial_supply += allocation.amount,
+                None => coins.push(Coin {
+                    ticker: allocation.coin.clone(),
+                    name: allocation.coin.clone(),
+                    issuer: allocation.address.clone(),
+                    decimals: kcoin::DEFAULT_DECIMALS,
+                    initial_supply: allocation.amount,
+                    minted: 0,
+                    burned: 0,
+                    supply: allocation.amount,
+                    paused: false,
+                    height: 0,
+                    hash: String::new()
+                })
+            }
+        }
+        if !coins.iter().any(|c| c.ticker == "KCN") {
+            coins.push(Coin {
+                ticker: "KCN".to_owned(),
+                name: "KCN".to_owned(),
+                issuer: genesis.fee_address.address.clone(),
+                decimals: kcoin::DEFAULT_DECIMALS,
+                initial_supply: 0,
+                minted: 0,
+                burned: 0,
+                supply: 0,
+                paused: false,
+                height: 0,
+                hash: String::new()
+            });
+        }
+        for coin in coins.iter() {
+            self.coin_insert_with_conn(&conn, coin)?;
+        }
+        // Fees get credited to an existing KCN balance
+        conn.execute(
+            "INSERT OR IGNORE INTO `address_balance` (`address`, `coin`, `balance`) VALUES (?1, 'KCN', 0)",
+            &[&genesis.fee_address.address]
+        )?;
+
+        let balances = self.balance_get_all_with_conn(&conn)?;
+        for balance in balances.iter() {
+            self.balance_history_record_with_conn(&conn, 0, &balance.address, &balance.coin)?;
+        }
+        let block = block::genesis_block(genesis, &balances)?;
+        self.block_add_with_conn(&conn, &block)
+    }
+
+    /// Datadirs from before the genesis block have a balance and maybe blocks from height 1 on,
+    /// made without a genesis file. The genesis block gets put under them and their blocks get
+    /// chained up to the tip. Their hashes and signatures can't be made up afterwards, so the tip
+    /// becomes a checkpoint: up to `legacy_height` blocks are only checked for how they link up
+    /// and their merkle roots, and the balances at the checkpoint are taken as they are.
+    fn genesis_upgrade_legacy_with_conn(&self, conn: &rusqlite::Connection, genesis: &Genesis) -> Result<(), Error> {
+        // Those nodes were started with a KCN supply, which can only have moved around since
+        let allocated: u64 = genesis.allocations.iter().filter(|a| a.coin == "KCN").map(|a| a.amount).sum();
+        let total: i64 = conn.query_row("SELECT ifnull(sum(`balance`), 0) FROM `address_balance` WHERE `coin` = 'KCN'", NO_PARAMS, |row| row.get(0))?;
+        if genesis.allocations.iter().any(|a| a.coin != "KCN") || total < 0 || total as u64 != allocated {
+            return Err(Error::GenesisMismatch);
+        }
+        let tip = self.block_height_with_conn(&conn)?;
+        info!("Upgrading datadir without a genesis block, {} blocks become a checkpoint", tip);
+
+        // The genesis block is the same as on a node started from this genesis
+        let mut genesis_balances: BTreeMap<(String, String), i64> = BTreeMap::new();
+        for allocation in genesis.allocations.iter() {
+            *genesis_balances.entry((allocation.address.clone(), allocation.coin.clone())).or_insert(0) += allocation.amount as i64;
+        }
+        genesis_balances.entry((genesis.fee_address.address.clone(), "KCN".to_owned())).or_insert(0);
+        let genesis_balances: Vec<AddressBalance> = genesis_balances.into_iter()
+            .map(|((address, coin), balance)| AddressBalance { address, coin, balance })
+            .collect();
+        let genesis_block = block::genesis_block(genesis, &genesis_balances)?;
+        self.block_add_with_conn(&conn, &genesis_block)?;
+
+        let mut prev_hash = genesis_block.hash;
+        for height in 1..tip + 1 {
+            let hash: String = conn.query_row_and_then(
+                "SELECT `hash` FROM `block` WHERE `height` = ?1",
+                &[height],
+                |row| -> Result<String, Error> { Ok(row.get_checked(0)?) })?;
+            let merkle_root = block::merkle_root(&self.block_get_tx_hashes_with_conn(&conn, height)?)?;
+            conn.execute(
+                "UPDATE `block` SET `prev_hash` = ?1, `merkle_root` = ?2 WHERE `height` = ?3",
+                &[prev_hash.as_str(), merkle_root.as_str(), height.to_string().as_str()]
+            )?;
+            prev_hash = hash;
+        }
+
+        // Coins used to come into existence by sending them. The first sender counts as issuer.
+        if !self.coin_exists_in_chain_with_conn(&conn, "KCN")? {
+            self.coin_insert_with_conn(&conn, &Coin {
+                ticker: "KCN".to_owned(),
+                name: "KCN".to_owned(),
+                issuer: genesis.allocations.iter().find(|a| a.coin == "KCN").map_or(genesis.fee_address.address.clone(), |a| a.address.clone()),
+                decimals: kcoin::DEFAULT_DECIMALS,
+                initial_supply: allocated,
+                minted: 0,
+                burned: 0,
+                supply: allocated,
+                paused: false,
+                height: 0,
+                hash: String::new()
+            })?;
+        }
+        let mut stmt = conn.prepare(
+            "SELECT b.`coin`, sum(b.`balance`), t.`from`, t.`block`, t.`hash` FROM `address_balance` b \
+             JOIN `transaction` t ON t.`hash` = (SELECT `hash` FROM `transaction` WHERE `coin` = b.`coin` ORDER BY `block`, `index` LIMIT 1) \
+             WHERE b.`coin` NOT IN (SELECT `ticker` FROM `coin`) \
+             GROUP BY b.`coin`")?;
+        let coins = stmt.query_and_then(NO_PARAMS, |row| -> Result<Coin, Error> {
+            let ticker: String = row.get_checked(0)?;
+            let supply = SqliteStorage::i64_to_u64(row.get_checked(1)?)?;
+            Ok(Coin {
+                name: ticker.clone(),
+                ticker,
+                issuer: row.get_checked(2)?,
+                decimals: kcoin::DEFAULT_DECIMALS,
+                initial_supply: supply,
+                minted: 0,
+                burned: 0,
+                supply,
+                paused: false,
+                height: row.get_checked(3)?,
+                hash: row.get_checked(4)?
+            })
+        })?.collect::<Result<Vec<Coin>, Error>>()?;
+        for coin in coins.iter() {
+            self.coin_insert_with_conn(&conn, coin)?;
+        }
+
+        // Fees get credited to an existing KCN balance
+        conn.execute(
+            "INSERT OR IGNORE INTO `address_balance` (`address`, `coin`, `balance`) VALUES (?1, 'KCN', 0)",
+            &[&genesis.fee_address.address]
+        )?;
+        for balance in self.balance_get_all_with_conn(&conn)?.iter() {
+            self.balance_history_record_with_conn(&conn, tip, &balance.address, &balance.coin)?;
+        }
+        self.chain_info_set_with_conn(&conn, "legacy_height", &tip.to_string())
+    }
 
-        if count == 0 {
-            conn.execute("INSERT INTO address_balance (`address`, `coin`, `balance`)
-                  VALUES (?1, ?2, ?3)",
-                     &[&knc_address.address, "KCN", &(knc_supply * 100000000).to_string()]).unwrap();
+    /// The highest block taken over from a datadir without a genesis block, 0 if there is none.
+    pub fn legacy_height(&self) -> Result<u32, Error> {
+        let conn = self.get_conn()?;
+        self.legacy_height_with_conn(&conn)
+    }
+
+    pub fn legacy_height_with_conn(&self, conn: &rusqlite::Connection) -> Result<u32, Error> {
+        match self.chain_info_get_with_conn(conn, "legacy_height")? {
+            Some(v) => v.parse().map_err(|_| Error::QueryError {message: format!("invalid legacy height {}", v)}),
+            None => Ok(0)
+        }
+    }
+
+    /// Makes the in-memory database `self` continue from the checkpoint of `source`: the balances
+    /// and coins at `source`'s legacy height replace those of the genesis. Used by the audit,
+    /// which can't replay the blocks before the checkpoint.
+    pub fn legacy_seed_with_conn(&self, conn: &rusqlite::Connection, source: &SqliteStorage) -> Result<(), Error> {
+        let source_conn = source.get_conn()?;
+        let height = source.legacy_height_with_conn(&source_conn)?;
+        conn.execute("DELETE FROM `address_balance`", NO_PARAMS)?;
+        conn.execute("DELETE FROM `balance_history`", NO_PARAMS)?;
+        let mut stmt = source_conn.prepare("SELECT `address`, `coin`, `balance` FROM `balance_history` WHERE `block` = ?1")?;
+        let balances = stmt.query_and_then(&[height], |row| -> Result<AddressBalance, Error> {
+            Ok(AddressBalance { address: row.get_checked(0)?, coin: row.get_checked(1)?, balance: row.get_checked(2)? })
+        })?.collect::<Result<Vec<AddressBalance>, Error>>()?;
+        for balance in balances.iter() {
+            conn.execute(
+                "INSERT INTO `address_balance` (`address`, `coin`, `balance`) VALUES (?1, ?2, ?3)",
+                &[balance.address.as_str(), balance.coin.as_str(), balance.balance.to_string().as_str()]
+            )?;
+            self.balance_history_record_with_conn(conn, height, &balance.address, &balance.coin)?;
+        }
+        let mut stmt = source_conn.prepare("SELECT `ticker`, `name`, `issuer`, `decimals`, `initial_supply`, `height`, `hash` FROM `coin` WHERE `height` > 0 AND `height` <= ?1")?;
+        let coins = stmt.query_and_then(&[height], |row| -> Result<Vec<String>, Error> {
+            let decimals: i64 = row.get_checked(3)?;
+            let initial_supply: i64 = row.get_checked(4)?;
+            let coin_height: i64 = row.get_checked(5)?;
+            Ok(vec![row.get_checked(0)?, row.get_checked(1)?, row.get_checked(2)?, decimals.to_string(), initial_supply.to_string(), coin_height.to_string(), row.get_checked(6)?])
+        })?.collect::<Result<Vec<Vec<String>>, Error>>()?;
+        for coin in coins.iter() {
+            conn.execute(
+                "INSERT INTO `coin` (`ticker`, `name`, `issuer`, `decimals`, `initial_supply`, `height`, `hash`) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                &[&coin[0], &coin[1], &coin[2], &coin[3], &coin[4], &coin[5], &coin[6]]
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The key that signs the blocks of a chain whose genesis lists no producers, once
+    /// `producer_key_pin` has been called.
+    pub fn producer_key(&self) -> Result<Option<String>, Error> {
+        let conn = self.get_conn()?;
+        self.chain_info_get_with_conn(&conn, "producer_key")
+    }
+
+    /// Remembers `key` as the block signing key of the chain in this datadir. Nothing else can
+    /// sign its blocks afterwards, pinning a different key fails.
+    pub fn producer_key_pin(&self, key: &str) -> Result<(), Error> {
+        let conn = self.get_conn()?;
+        match self.chain_info_get_with_conn(&conn, "producer_key")? {
+            Some(ref pinned) if pinned != key => Err(Error::ProducerKeyMismatch { pinned: pinned.clone() }),
+            Some(_) => Ok(()),
+            None => {
+                info!("Pinning producer key {}", key);
+                self.chain_info_set_with_conn(&conn, "producer_key", key)
+            }
+        }
+    }
+
+    /// Reads a fact about the local chain that doesn't fit any other table.
+    pub fn chain_info_get_with_conn(&self, conn: &rusqlite::Connection, key: &str) -> Result<Option<String>, Error> {
+        match conn.query_row_and_then(
+            "SELECT `value` FROM `chain_info` WHERE `key` = ?1",
+            &[key],
+            |row| -> Result<String, Error> { Ok(row.get_checked(0)?) }) {
+            Ok(v) => Ok(Some(v)),
+            Err(Error::NotFound) => Ok(None),
+            Err(e) => Err(e)
         }
+    }
+
+    pub fn chain_info_set_with_conn(&self, conn: &rusqlite::Connection, key: &str, value: &str) -> Result<(), Error> {
+        conn.execute(
+            "INSERT OR REPLACE INTO `chain_info` (`key`, `value`) VALUES (?1, ?2)",
+            &[key, value]
+        )?;
+        Ok(())
+    }
 
-        Ok(SqliteStorage {pool, knc_address: knc_address})
+    pub fn genesis_hash(&self) -> &str {
+        &self.genesis_hash
     }
 
     pub fn get_conn(&self) -> Result<PooledConnection<SqliteConnectionManager>, Error> {
@@ -167,12 +764,18 @@ impl SqliteStorage {
     pub fn clone(&self) -> Self {
         SqliteStorage {
             pool: self.pool.clone(),
-            knc_address: self.knc_address.clone()
+            knc_address: self.knc_address.clone(),
+            genesis_hash: self.genesis_hash.clone(),
+            state_tree: self.state_tree.clone()
         }
     }
 
     pub fn block_height(&self) -> Result<u32, Error> {
         let conn = self.get_conn()?;
+        self.block_height_with_conn(&conn)
+    }
+
+    pub fn block_height_with_conn(&self, conn: &rusqlite::Connection) -> Result<u32, Error> {
         let mut stmt = conn
             .prepare("SELECT `height` FROM `block` ORDER BY height DESC LIMIT 1")
             .map_err(|e| Error::QueryError {message: e.to_string()})?;
@@ -191,18 +794,22 @@ impl SqliteStorage {
 
     pub fn block_get_by_height(&self, height: u32) -> Result<Block, Error> {
         let conn = self.get_conn()?;
-        let mut stmt = conn
-            .prepare("SELECT `height`, `hash`, `time` FROM `block` WHERE `height` = ?1 LIMIT 1")
-            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+        self.block_get_by_height_with_conn(&conn, height)
+    }
 
+    pub fn block_get_by_height_with_conn(&self, conn: &rusqlite::Connection, height: u32) -> Result<Block, Error> {
         let block = conn.query_row_and_then(
-            "SELECT `height`, `hash`, `time` FROM `block` WHERE `height` = ?1 LIMIT 1",
+            "SELECT `height`, `hash`, `prev_hash`, `merkle_root`, `state_root`, `time`, `signature` FROM `block` WHERE `height` = ?1 LIMIT 1",
             &[height],
             |row| -> Result<Block, Error> {
                 Ok(Block {
                     height: row.get_checked(0)?,
                     hash: row.get_checked(1)?,
-                    time: row.get_checked(2)?
+                    prev_hash: row.get_checked(2)?,
+                    merkle_root: row.get_checked(3)?,
+                    state_root: row.get_checked(4)?,
+                    time: row.get_checked(5)?,
+                    signature: row.get_checked(6)?
                 })
             }
         )?;
@@ -210,6 +817,30 @@ impl SqliteStorage {
         Ok(block)
     }
 
+    pub fn block_get_tx_hashes(&self, height: u32) -> Result<Vec<String>, Error> {
+        let conn = self.get_conn()?;
+        self.block_get_tx_hashes_with_conn(&conn, height)
+    }
+
+    pub fn block_get_tx_hashes_with_conn(&self, conn: &rusqlite::Connection, height: u32) -> Result<Vec<String>, Error> {
+        let mut stmt = conn
+            .prepare("SELECT `hash` FROM `transaction` WHERE `block` = ?1 ORDER BY `index` ASC")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt
+            .query_and_then(
+                &[height],
+                |row| -> Result<String, Error> {
+                    Ok(row.get_checked(0)?)
+                })?;
+
+        let mut hashes = Vec::new();
+        for hash in rows {
+            hashes.push(hash?);
+        }
+        Ok(hashes)
+    }
+
     pub fn chain_get_transactions(&self, height: Option<u32>, after_height: Option<u32>, from: Option<Bech32Address>, to: Option<Bech32Address>, limit: u32, network: &Network) -> Result<Vec<MinedTx>, Error> {
         let conn = self.get_conn()?;
 
@@ -238,9 +869,10 @@ impl SqliteStorage {
             None => {}
         }
 
+        // Receivers of batch outputs are only listed in `transaction_output`
         match to {
             Some(v) => {
-                let q = format!("`to` = ?{}", whereVec.len() + 2);
+                let q = format!("(`to` = ?{0} OR `hash` IN (SELECT `hash` FROM `transaction_output` WHERE `to` = ?{0}))", whereVec.len() + 2);
                 whereVec.push(q);
                 params.push(v.address);
             },
@@ -260,9 +892,9 @@ impl SqliteStorage {
             whereVec.push("1".to_owned());
         }
 
-        // "SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to` FROM `mempool` WHERE `from` = ?1 AND `nonce` = ?2 LIMIT 1",
+        // "SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` FROM `mempool` WHERE `from` = ?1 AND `nonce` = ?2 LIMIT 1",
 
-        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `block`, `index` \
+        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature`, `block`, `index` \
                   FROM `transaction` \
                   WHERE {} \
                   ORDER BY `index` ASC \
@@ -277,9 +909,9 @@ impl SqliteStorage {
                 &params,
                 |row| -> Result<MinedTx, Error> {
                     Ok(MinedTx {
-                        block: row.get_checked(10)?,
-                        index: row.get_checked(11)?,
-                        tx_envelope: SqliteStorage::tx_from_row(row, network)?
+                        block: row.get_checked(18)?,
+                        index: row.get_checked(19)?,
+                        tx_envelope: SqliteStorage::tx_from_row(row, network, &self.genesis_hash)?
                     })
                 })?;
 
@@ -328,7 +960,7 @@ impl SqliteStorage {
             whereVec.push("1".to_owned());
         }
 
-        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to` \
+        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` \
                   FROM `mempool` \
                   WHERE {} \
                   ORDER BY `seen` ASC \
@@ -342,7 +974,7 @@ impl SqliteStorage {
             .query_and_then(
                 &params,
                 |row| -> Result<TransactionEnvelope, Error> {
-                    Ok(SqliteStorage::tx_from_row(row, network)?)
+                    Ok(SqliteStorage::tx_from_row(row, network, &self.genesis_hash)?)
                 })?;
 
         let mut txs = Vec::new();
@@ -355,7 +987,7 @@ impl SqliteStorage {
     pub fn chain_get_transaction_by_hash(&self, network: &Network, hash: &str) -> Result<MinedTx, Error> {
         let conn = self.get_conn()?;
 
-        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `block`, `index` \
+        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature`, `block`, `index` \
                   FROM `transaction` \
                   WHERE hash = ?");
 
@@ -368,9 +1000,9 @@ impl SqliteStorage {
                 &[hash],
                 |row| -> Result<MinedTx, Error> {
                     Ok(MinedTx {
-                        block: row.get_checked(10)?,
-                        index: row.get_checked(11)?,
-                        tx_envelope: SqliteStorage::tx_from_row(row, network)?
+                        block: row.get_checked(18)?,
+                        index: row.get_checked(19)?,
+                        tx_envelope: SqliteStorage::tx_from_row(row, network, &self.genesis_hash)?
                     })
                 })?;
 
@@ -383,7 +1015,7 @@ impl SqliteStorage {
     pub fn mempool_get_transaction_by_hash(&self, network: &Network, hash: &str) -> Result<TransactionEnvelope, Error> {
         let conn = self.get_conn()?;
 
-        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to` \
+        let query = format!("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` \
                   FROM `mempool` \
                   WHERE hash = ?");
 
@@ -395,7 +1027,7 @@ impl SqliteStorage {
             .query_and_then(
                 &[hash],
                 |row| -> Result<TransactionEnvelope, Error> {
-                    Ok(SqliteStorage::tx_from_row(row, network)?)
+                    Ok(SqliteStorage::tx_from_row(row, network, &self.genesis_hash)?)
                 })?;
 
         for tx in rows {
@@ -406,6 +1038,10 @@ impl SqliteStorage {
 
     pub fn address_nonce_mined(&self, address: &Bech32Address) -> Result<Option<u64>, Error> {
         let conn = self.get_conn()?;
+        self.address_nonce_mined_with_conn(&conn, address)
+    }
+
+    pub fn address_nonce_mined_with_conn(&self, conn: &rusqlite::Connection, address: &Bech32Address) -> Result<Option<u64>, Error> {
         let mut stmt = conn
             .prepare("SELECT `nonce` FROM `transaction` WHERE `from` = ?1 ORDER BY nonce DESC LIMIT 1")
             .map_err(|e| Error::QueryError {message: e.to_string()})?;
@@ -458,7 +1094,7 @@ impl SqliteStorage {
                 hash
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
             Error::QueryError {message: "delete failed".to_owned()}
         })?;
         Ok(())
@@ -471,7 +1107,23 @@ impl SqliteStorage {
                 hash
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
+            Error::QueryError {message: "delete failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    /// Removes the mined transaction and any other mempool transaction of the sender with the
+    /// same or a lower nonce, which can't be mined anymore.
+    pub fn mempool_remove_mined_with_conn(&self, conn: &rusqlite::Connection, from: &Bech32Address, nonce: u64) -> Result<(), Error> {
+        conn.execute(
+            "DELETE FROM `mempool` WHERE `from` = ?1 AND `nonce` <= ?2",
+            &[
+                &from.address,
+                &nonce.to_string()
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
             Error::QueryError {message: "delete failed".to_owned()}
         })?;
         Ok(())
@@ -485,18 +1137,275 @@ impl SqliteStorage {
                 address
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
             Error::QueryError {message: "delete failed".to_owned()}
         })?;
         Ok(())
     }
 
+    /// Drops every transaction that can't be mined at `height` anymore, together with the later
+    /// nonces of its sender which could never be mined without it. What was dropped and why is
+    /// kept in `mempool_pruned`.
+    pub fn mempool_prune_expired(&self, height: u32) -> Result<Vec<PrunedTx>, Error> {
+        let conn = self.get_conn()?;
+        self.start_transaction(&conn)?;
+        match self.mempool_prune_expired_with_conn(&conn, height) {
+            Ok(pruned) => {
+                self.commit_transaction(&conn)?;
+                Ok(pruned)
+            },
+            Err(e) => {
+                self.rollback_transaction(&conn)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn mempool_prune_expired_with_conn(&self, conn: &rusqlite::Connection, height: u32) -> Result<Vec<PrunedTx>, Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs() as i64;
+        let expired = self.mempool_select_pruned_with_conn(
+            conn,
+            "SELECT `hash`, `from`, `nonce` FROM `mempool` WHERE `expires_at_height` > 0 AND `expires_at_height` <= ?1 ORDER BY `from`, `nonce`",
+            &[&height.to_string()]
+        )?;
+
+        let mut pruned: Vec<PrunedTx> = Vec::new();
+        for (hash, from, nonce) in expired {
+            if pruned.iter().any(|p| p.hash == hash) {
+                continue;
+            }
+            let dependents = self.mempool_select_pruned_with_conn(
+                conn,
+                "SELECT `hash`, `from`, `nonce` FROM `mempool` WHERE `from` = ?1 AND `nonce` > ?2 ORDER BY `nonce`",
+                &[&from, &nonce.to_string()]
+            )?;
+            pruned.push(PrunedTx { hash: hash.clone(), from, nonce, height, time: now, reason: "expired".to_owned() });
+            for (dependent_hash, dependent_from, dependent_nonce) in dependents {
+                if pruned.iter().any(|p| p.hash == dependent_hash) {
+                    continue;
+                }
+                pruned.push(PrunedTx {
+                    hash: dependent_hash,
+                    from: dependent_from,
+                    nonce: dependent_nonce,
+                    height,
+                    time: now,
+                    reason: format!("depends on expired transaction {}", hash)
+                });
+            }
+        }
+
+        for p in pruned.iter() {
+            self.mempool_pruned_add_with_conn(conn, p)?;
+            self.mempool_remove_with_conn(conn, &p.hash)?;
+        }
+        Ok(pruned)
+    }
+
+    /// Drops the pending transactions that stopped verifying when `rotation` got mined, those
+    /// its sender signed as sender or as fee payer with the old key, together with the later
+    /// nonces of their senders. They could never be mined and would hold those nonces back.
+    fn mempool_prune_rotated_with_conn(&self, conn: &rusqlite::Connection, rotation: &TransactionEnvelope, height: u32) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs() as i64;
+        let address = &rotation.tx.from;
+        let mut stmt = conn
+            .prepare("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` \
+                      FROM `mempool` WHERE ((`from` = ?1 AND `nonce` > ?2) OR `fee_payer` = ?1) AND `hash` != ?3 ORDER BY `from`, `nonce`")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+        let rows = stmt
+            .query_and_then(
+                &[&address.address, &rotation.tx.nonce.to_string(), &rotation.hash],
+                |row| SqliteStorage::tx_from_row(row, &address.network, &self.genesis_hash))?;
+        let mut signed = Vec::new();
+        for tx in rows {
+            signed.push(tx?);
+        }
+
+        let mut pruned: Vec<PrunedTx> = Vec::new();
+        for tx in signed {
+            if pruned.iter().any(|p| p.hash == tx.hash) || tx.verify(self, conn) {
+                continue;
+            }
+            let dependents = self.mempool_select_pruned_with_conn(
+                conn,
+                "SELECT `hash`, `from`, `nonce` FROM `mempool` WHERE `from` = ?1 AND `nonce` > ?2 ORDER BY `nonce`",
+                &[&tx.tx.from.address, &tx.tx.nonce.to_string()]
+            )?;
+            pruned.push(PrunedTx { hash: tx.hash.clone(), from: tx.tx.from.address.clone(), nonce: tx.tx.nonce, height, time: now, reason: "signed with a rotated key".to_owned() });
+            for (dependent_hash, dependent_from, dependent_nonce) in dependents {
+                if pruned.iter().any(|p| p.hash == dependent_hash) {
+                    continue;
+                }
+                pruned.push(PrunedTx {
+                    hash: dependent_hash,
+                    from: dependent_from,
+                    nonce: dependent_nonce,
+                    height,
+                    time: now,
+                    reason: format!("depends on transaction {} signed with a rotated key", tx.hash)
+                });
+            }
+        }
+
+        for p in pruned.iter() {
+            self.mempool_pruned_add_with_conn(conn, p)?;
+            self.mempool_remove_with_conn(conn, &p.hash)?;
+        }
+        Ok(())
+    }
+
+    fn mempool_pruned_add_with_conn(&self, conn: &rusqlite::Connection, pruned: &PrunedTx) -> Result<(), Error> {
+        conn.execute(
+            "INSERT OR REPLACE INTO `mempool_pruned` (`hash`, `from`, `nonce`, `height`, `time`, `reason`) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            &[
+                &pruned.hash,
+                &pruned.from,
+                &pruned.nonce.to_string(),
+                &pruned.height.to_string(),
+                &pruned.time.to_string(),
+                &pruned.reason
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert pruned tx failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    /// Puts the transactions of rolled back blocks back into the mempool, in chain order, after
+    /// checking them again against the chain as it is at `height` now. Whatever can't be mined
+    /// anymore (expired, signed with a key the sender has since rotated, out of nonce order,
+    /// spending a lock or order that is gone) goes to `mempool_pruned` instead, together with the
+    /// later transactions of the same sender that depended on it. Transactions that only aren't
+    /// mature yet are kept, the block producer holds them back like any other.
+    pub fn mempool_return_with_conn(&self, conn: &rusqlite::Connection, transactions: &[TransactionEnvelope], height: u32) -> Result<Vec<PrunedTx>, Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs() as i64;
+        let next_height = height + 1;
+        let mut last_nonces: HashMap<String, Option<u64>> = HashMap::new();
+        let mut pruned: Vec<PrunedTx> = Vec::new();
+
+        for transaction in transactions.iter() {
+            // A competing branch can have mined it already
+            let mined: i64 = conn.query_row("SELECT COUNT(*) FROM `transaction` WHERE `hash` = ?1", &[&transaction.hash], |row| row.get(0))?;
+            if mined > 0 {
+                continue;
+            }
+            let from = transaction.tx.from.address.clone();
+            let reason = match pruned.iter().find(|p| p.from == from) {
+                Some(p) => Some(format!("depends on dropped transaction {}", p.hash)),
+                None => {
+                    if !last_nonces.contains_key(&from) {
+                        let mined = self.address_nonce_mined_with_conn(conn, &transaction.tx.from)?;
+                        last_nonces.insert(from.clone(), mined);
+                    }
+                    let expected = last_nonces[&from].map_or(0, |n| n + 1);
+                    self.mempool_return_problem_with_conn(conn, transaction, next_height, expected)?
+                }
+            };
+            match reason {
+                Some(reason) => pruned.push(PrunedTx { hash: transaction.hash.clone(), from, nonce: transaction.tx.nonce, height, time: now, reason }),
+                None => {
+                    self.mempool_add_with_conn(conn, transaction)?;
+                    last_nonces.insert(from, Some(transaction.tx.nonce));
+                }
+            }
+        }
+
+        // Transactions sent after the rolled back blocks continue the nonces of the dropped ones
+        let mut dependents: Vec<PrunedTx> = Vec::new();
+        for p in pruned.iter() {
+            if dependents.iter().any(|d| d.from == p.from) {
+                continue;
+            }
+            for (hash, from, nonce) in self.mempool_select_pruned_with_conn(
+                conn,
+                "SELECT `hash`, `from`, `nonce` FROM `mempool` WHERE `from` = ?1 AND `nonce` > ?2 ORDER BY `nonce`",
+                &[&p.from, &p.nonce.to_string()]
+            )? {
+                dependents.push(PrunedTx { hash, from, nonce, height, time: now, reason: format!("depends on dropped transaction {}", p.hash) });
+            }
+        }
+        for p in dependents.iter() {
+            self.mempool_remove_with_conn(conn, &p.hash)?;
+        }
+        pruned.extend(dependents);
+
+        for p in pruned.iter() {
+            self.mempool_pruned_add_with_conn(conn, p)?;
+        }
+        Ok(pruned)
+    }
+
+    fn mempool_return_problem_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope, height: u32, expected_nonce: u64) -> Result<Option<String>, Error> {
+        if transaction.tx.is_expired(height) {
+            return Ok(Some("expired".to_owned()));
+        }
+        if transaction.tx.nonce != expected_nonce {
+            return Ok(Some(format!("nonce {} does not follow the chain, expected {}", transaction.tx.nonce, expected_nonce)));
+        }
+        if !transaction.verify(self, conn) {
+            return Ok(Some("signature does not match the current key of the sender".to_owned()));
+        }
+        let earliest_height = transaction.tx.valid_after_height.map_or(height, |h| height.max(h.saturating_add(1)));
+        if let Some(problem) = self.htlc_problem_with_conn(conn, transaction, earliest_height)? {
+            return Ok(Some(problem.to_owned()));
+        }
+        if let Some(problem) = self.order_problem_with_conn(conn, transaction)? {
+            return Ok(Some(problem.to_owned()));
+        }
+        Ok(None)
+    }
+
+    fn mempool_select_pruned_with_conn(&self, conn: &rusqlite::Connection, query: &str, params: &[&String]) -> Result<Vec<(String, String, u64)>, Error> {
+        let mut stmt = conn
+            .prepare(query)
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt
+            .query_and_then(
+                params,
+                |row| -> Result<(String, String, u64), Error> {
+                    Ok((row.get_checked(0)?, row.get_checked(1)?, SqliteStorage::i64_to_u64(row.get_checked(2)?)?))
+                })?;
+
+        let mut results = Vec::new();
+        for result in rows {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    pub fn mempool_get_pruned(&self, hash: &str) -> Result<PrunedTx, Error> {
+        let conn = self.get_conn()?;
+
+        let pruned = conn
+            .query_row_and_then(
+                "SELECT `hash`, `from`, `nonce`, `height`, `time`, `reason` FROM `mempool_pruned` WHERE `hash` = ?1",
+                &[hash],
+                |row| -> Result<PrunedTx, Error> {
+                    Ok(PrunedTx {
+                        hash: row.get_checked(0)?,
+                        from: row.get_checked(1)?,
+                        nonce: SqliteStorage::i64_to_u64(row.get_checked(2)?)?,
+                        height: row.get_checked(3)?,
+                        time: row.get_checked(4)?,
+                        reason: row.get_checked(5)?
+                    })
+                })?;
+
+        Ok(pruned)
+    }
+
     pub fn mempool_add(&self, transaction: &TransactionEnvelope) -> Result<(), Error> {
         let conn = self.get_conn()?;
+        self.mempool_add_with_conn(&conn, transaction)
+    }
+
+    pub fn mempool_add_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope) -> Result<(), Error> {
         let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs();
         conn.execute(
-            "INSERT INTO mempool (`amount`, `coin`, `fee`, `from`, `hash`, `nonce`, `memo`, `seen`, `signature`, `to`)
-                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            "INSERT INTO mempool (`amount`, `coin`, `fee`, `from`, `hash`, `nonce`, `memo`, `seen`, `signature`, `to`, `version`, `type`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
             &[
                 &transaction.tx.amount.to_string(),
                 &transaction.tx.coin,
@@ -507,10 +1416,19 @@ impl SqliteStorage {
                 &transaction.tx.memo,
                 &now.to_string(),
                 &transaction.signature,
-                &transaction.tx.to.address
+                &transaction.tx.to.address,
+                &transaction.version.to_string(),
+                &transaction.tx.kind.name().to_owned(),
+                &json!(transaction.tx.kind).to_string(),
+                &SqliteStorage::multisig_to_column(transaction),
+                &transaction.tx.valid_after_height.unwrap_or(0).to_string(),
+                &transaction.tx.valid_after_time.unwrap_or(0).to_string(),
+                &transaction.tx.expires_at_height.unwrap_or(0).to_string(),
+                &SqliteStorage::fee_payer_to_column(transaction),
+                &transaction.fee_payer_signature.clone().unwrap_or(String::new())
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
             Error::QueryError {message: "insert failed".to_owned()}
         })?;
         Ok(())
@@ -558,31 +1476,106 @@ impl SqliteStorage {
 
         let tx = conn
             .query_row_and_then(
-                "SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to` FROM `mempool` WHERE `from` = ?1 AND `nonce` = ?2 LIMIT 1",
+                "SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` FROM `mempool` WHERE `from` = ?1 AND `nonce` = ?2 LIMIT 1",
                 &[&from.address, &nonce.to_string()],
                 |row| {
-                SqliteStorage::tx_from_row(row, network)
+                SqliteStorage::tx_from_row(row, network, &self.genesis_hash)
             })?;
 
         Ok(tx)
     }
 
-    fn tx_from_row(row: &Row, network: &Network) -> Result<TransactionEnvelope, Error> {
-        let from: String = row.get_checked(3)?;
-        let to: String = row.get_checked(9)?;
-        let memo: String = row.get_checked(5)?;
-        Ok(TransactionEnvelope {
-            hash: row.get_checked(4)?,
-            signature: row.get_checked(8)?,
-            seen: row.get_checked(7)?,
-            tx: Transaction {
-                amount: SqliteStorage::i64_to_u64(row.get_checked(0)?)?,
-                coin: row.get_checked(1)?,
-                fee: SqliteStorage::i64_to_u64(row.get_checked(2)?)?,
-                memo,
-                from: Bech32Address::new(&from, network.clone())?,
-                to: Bech32Address::new(&to, network.clone())?,
-                nonce: SqliteStorage::i64_to_u64(row.get_checked(6)?)?
+    fn mempool_get_by_sender(&self, from: &Bech32Address) -> Result<Vec<TransactionEnvelope>, Error> {
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn
+            .prepare("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` FROM `mempool` WHERE `from` = ?1")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt
+            .query_and_then(
+                &[&from.address],
+                |row| SqliteStorage::tx_from_row(row, &from.network, &self.genesis_hash))?;
+
+        let mut results = Vec::new();
+        for result in rows {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
+    /// Sums the fees `address` pays for pending transactions of others.
+    fn mempool_fees_paid_by(&self, address: &Bech32Address) -> Result<u64, Error> {
+        let conn = self.get_conn()?;
+        let fees: i64 = conn.query_row("SELECT ifnull(SUM(`fee`), 0) FROM `mempool` WHERE `fee_payer` = ?1", &[&address.address], |row| row.get(0))?;
+        SqliteStorage::i64_to_u64(fees)
+    }
+
+    /// Sums what pending transactions mint of `coin`.
+    pub fn mempool_minted(&self, coin: &str) -> Result<u64, Error> {
+        let conn = self.get_conn()?;
+        let minted: i64 = conn.query_row("SELECT ifnull(SUM(`amount`), 0) FROM `mempool` WHERE `type` = 'mint' AND `coin` = ?1", &[&coin], |row| row.get(0))?;
+        SqliteStorage::i64_to_u64(minted)
+    }
+
+    /// Transactions without a fee payer store an empty string.
+    fn fee_payer_to_column(transaction: &TransactionEnvelope) -> String {
+        match transaction.tx.fee_payer {
+            Some(ref fee_payer) => fee_payer.address.clone(),
+            None => String::new()
+        }
+    }
+
+    /// Transactions from single key addresses store an empty string.
+    fn multisig_to_column(transaction: &TransactionEnvelope) -> String {
+        match transaction.multisig {
+            Some(ref multisig) => json!(multisig).to_string(),
+            None => String::new()
+        }
+    }
+
+    fn tx_from_row(row: &Row, network: &Network, chain_id: &str) -> Result<TransactionEnvelope, Error> {
+        let from: String = row.get_checked(3)?;
+        let to: String = row.get_checked(9)?;
+        let memo: String = row.get_checked(5)?;
+        let version: u32 = row.get_checked(10)?;
+        let data: String = row.get_checked(11)?;
+        let multisig: String = row.get_checked(12)?;
+        let valid_after_height: u32 = row.get_checked(13)?;
+        let valid_after_time: i64 = row.get_checked(14)?;
+        let expires_at_height: u32 = row.get_checked(15)?;
+        let fee_payer: String = row.get_checked(16)?;
+        let fee_payer_signature: String = row.get_checked(17)?;
+        let kind = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&data).ok()
+            .and_then(|data| Kind::from_json(&data, network).ok())
+            .ok_or(Error::QueryError {message: format!("invalid transaction data {}", data)})?;
+        let multisig = match multisig.as_str() {
+            "" => None,
+            _ => Some(serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&multisig).ok()
+                .and_then(|multisig| Multisig::from_json(&multisig).ok())
+                .ok_or(Error::QueryError {message: format!("invalid transaction multisig {}", multisig)})?)
+        };
+        Ok(TransactionEnvelope {
+            hash: row.get_checked(4)?,
+            signature: row.get_checked(8)?,
+            multisig,
+            fee_payer_signature: if fee_payer_signature.is_empty() { None } else { Some(fee_payer_signature) },
+            seen: row.get_checked(7)?,
+            version: version as u8,
+            tx: Transaction {
+                kind,
+                amount: SqliteStorage::i64_to_u64(row.get_checked(0)?)?,
+                coin: row.get_checked(1)?,
+                fee: SqliteStorage::i64_to_u64(row.get_checked(2)?)?,
+                memo,
+                from: Bech32Address::new(&from, network.clone())?,
+                to: Bech32Address::new(&to, network.clone())?,
+                nonce: SqliteStorage::i64_to_u64(row.get_checked(6)?)?,
+                chain_id: chain_id.to_owned(),
+                valid_after_height: if valid_after_height > 0 { Some(valid_after_height) } else { None },
+                valid_after_time: if valid_after_time > 0 { Some(valid_after_time) } else { None },
+                expires_at_height: if expires_at_height > 0 { Some(expires_at_height) } else { None },
+                fee_payer: if fee_payer.is_empty() { None } else { Some(Bech32Address::new(&fee_payer, network.clone())?) }
             }
         })
     }
@@ -624,11 +1617,17 @@ impl SqliteStorage {
         }
     }
 
-    pub fn mempool_get_block_candidates(&self, block_size: u64, network: &Network) -> Result<Vec<TransactionEnvelope>, Error> {
+    /// The transactions to put into the block at `height` made at `time`. Transactions that can't
+    /// be mined yet are left out, together with the later nonces of their sender. That includes
+    /// claims and refunds their lock doesn't allow at `height`, cancels of closed orders and
+    /// transactions signed with a key that was rotated away since they were sent.
+    pub fn mempool_get_block_candidates(&self, block_size: u64, height: u32, time: i64, network: &Network) -> Result<Vec<TransactionEnvelope>, Error> {
         let conn = self.get_conn()?;
         let mut stmt = conn
-            .prepare("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to` \
+            .prepare("SELECT `amount`, `coin`, `fee`, `from`, `hash`, `memo`, `nonce`, `seen`, `signature`, `to`, `version`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature` \
                       FROM `mempool` m \
+                      WHERE NOT EXISTS (SELECT 1 FROM `mempool` l WHERE l.`from` = m.`from` AND l.`nonce` <= m.`nonce` \
+                          AND (l.`valid_after_height` >= ?2 OR l.`valid_after_time` >= ?3)) \
                       ORDER BY (nonce - ifnull((select nonce from `transaction` t where m.`from` = t.`from` order by nonce desc limit 1), 0)) ASC, fee DESC \
                       LIMIT ?1")
             .map_err(|e| Error::QueryError {message: e.to_string()})?;
@@ -636,14 +1635,57 @@ impl SqliteStorage {
         //select * from mempool m order by (nonce - ifnull((select nonce from `transaction` t where m."from" = t."from" order by nonce desc limit 1), 0)) ASC, fee desc;
         let rows = stmt
             .query_and_then(
-                &[block_size as u32],
+                &[&block_size.to_string(), &height.to_string(), &time.to_string()],
                 |row| {
-                    SqliteStorage::tx_from_row(row, network)
+                    SqliteStorage::tx_from_row(row, network, &self.genesis_hash)
                 })?;
 
-        let mut txs = Vec::new();
+        let mut candidates = Vec::new();
         for tx in rows {
-            txs.push(tx?);
+            candidates.push(tx?);
+        }
+
+        // Coins that get paused or frozen by a candidate don't move in the same block, the rule
+        // would otherwise depend on the order of the transactions.
+        let controlled: Vec<String> = candidates.iter()
+            .filter(|tx| match tx.tx.kind { Kind::Pause { .. } | Kind::Freeze { .. } => true, _ => false })
+            .map(|tx| tx.tx.coin.clone())
+            .collect();
+        // A sender whose transaction is held back can't have its later nonces mined either
+        let mut held_back: Vec<String> = Vec::new();
+        // Locks and orders settled by an earlier candidate. Those made by a candidate can't be
+        // settled before the next block.
+        let mut settled: Vec<String> = Vec::new();
+        // Addresses rotating their key in this block. Whatever else they sign waits for the next
+        // block, when it's clear which key it has to match.
+        let mut rotated: Vec<String> = Vec::new();
+        let mut txs = Vec::new();
+        for tx in candidates {
+            let settles = match tx.tx.kind {
+                Kind::HtlcClaim { ref lock, .. } | Kind::HtlcRefund { ref lock } => Some(lock.clone()),
+                Kind::CancelOrder { ref order } => Some(order.clone()),
+                _ => None
+            };
+            let restricted = held_back.contains(&tx.tx.from.address)
+                || rotated.contains(&tx.tx.from.address)
+                || tx.tx.fee_payer.as_ref().map_or(false, |p| rotated.contains(&p.address))
+                || !tx.verify(self, &conn)
+                || settles.as_ref().map_or(false, |hash| settled.contains(hash))
+                || self.coin_parties_with_conn(&conn, &tx)?.iter().any(|(coin, _)| controlled.contains(coin))
+                || self.coin_restriction_with_conn(&conn, &tx)?.is_some()
+                || self.htlc_problem_with_conn(&conn, &tx, height)?.is_some()
+                || self.order_problem_with_conn(&conn, &tx)?.is_some();
+            if restricted {
+                held_back.push(tx.tx.from.address.clone());
+            } else {
+                if let Some(hash) = settles {
+                    settled.push(hash);
+                }
+                if let Kind::RotateKey { .. } = tx.tx.kind {
+                    rotated.push(tx.tx.from.address.clone());
+                }
+                txs.push(tx);
+            }
         }
         Ok(txs)
     }
@@ -666,10 +1708,7 @@ impl SqliteStorage {
     }
 
     pub fn start_transaction(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
-        println!("start tx {:?}", conn.is_autocommit());
-        let res = conn.execute_batch("BEGIN DEFERRED").map_err(|e| Error::CannotStartTransaction { message: e.to_string() });
-        println!("start tx {:?}", conn.is_autocommit());
-        res
+        conn.execute_batch("BEGIN DEFERRED").map_err(|e| Error::CannotStartTransaction { message: e.to_string() })
     }
 
     pub fn commit_transaction(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
@@ -682,13 +1721,12 @@ impl SqliteStorage {
 
     pub fn transaction_insert_with_conn(&self, conn: &rusqlite::Connection, block: u32, index: u32, transaction: &TransactionEnvelope) -> Result<(), Error> {
         // `hash` TEXT, `signature` TEXT, `block` INTEGER, `seen` INTEGER, `from` TEXT, `to` TEXT,
-        //`coin` TEXT, `amount` BIGINT, `nonce` BIGINT, `fee` BIGINT, `memo` TEXT);
+        //`coin` TEXT, `amount` BIGINT, `nonce` BIGINT, `fee` BIGINT, `memo` TEXT, `version` INTEGER, `type` TEXT, `data` TEXT, `multisig` TEXT, `valid_after_height` INTEGER, `valid_after_time` INTEGER, `expires_at_height` INTEGER, `fee_payer` TEXT, `fee_payer_signature` TEXT);
 
-        println!("inserting tx {:?}", transaction);
-        println!("autocommit {:?}", conn.is_autocommit());
+        debug!("Inserting transaction {:?}", transaction);
         let tx_inserted_rows = conn.execute(
-            "INSERT INTO `transaction` (`hash`, `signature`, `block`, `index`, `seen`, `from`, `to`, `coin`, `amount`, `nonce`, `fee`, `memo`)
-                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            "INSERT INTO `transaction` (`hash`, `signature`, `block`, `index`, `seen`, `from`, `to`, `coin`, `amount`, `nonce`, `fee`, `memo`, `version`, `type`, `data`, `multisig`, `valid_after_height`, `valid_after_time`, `expires_at_height`, `fee_payer`, `fee_payer_signature`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21)",
             &[
                 &transaction.hash,
                 &transaction.signature,
@@ -701,111 +1739,379 @@ impl SqliteStorage {
                 &transaction.tx.amount.to_string(),
                 &transaction.tx.nonce.to_string(),
                 &transaction.tx.fee.to_string(),
-                &transaction.tx.memo.to_string()
+                &transaction.tx.memo.to_string(),
+                &transaction.version.to_string(),
+                &transaction.tx.kind.name().to_owned(),
+                &json!(transaction.tx.kind).to_string(),
+                &SqliteStorage::multisig_to_column(transaction),
+                &transaction.tx.valid_after_height.unwrap_or(0).to_string(),
+                &transaction.tx.valid_after_time.unwrap_or(0).to_string(),
+                &transaction.tx.expires_at_height.unwrap_or(0).to_string(),
+                &SqliteStorage::fee_payer_to_column(transaction),
+                &transaction.fee_payer_signature.clone().unwrap_or(String::new())
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
             Error::QueryError {message: "insert tx failed".to_owned()}
         })?;
         if tx_inserted_rows == 0 {
             return Err(Error::QueryError {message: "unable to insert tx".to_owned()});
         }
 
-        println!("autocommit after inserting tx {:?}", conn.is_autocommit());
-
-        // Deduct fee from balance of sender
+        // Deduct fee from balance of sender, or of the fee payer
+        let fee_payer = transaction.tx.fee_paid_by();
         let fee_from_changed = conn.execute(
             "UPDATE `address_balance` SET `balance` = `balance` - ?1 WHERE `address` = ?2 AND `coin` = 'KCN'",
             &[
                 &(transaction.tx.fee).to_string(),
-                &transaction.tx.from.address.to_string()
+                &fee_payer.address.to_string()
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
-            Error::QueryError {message: "unable to deduct fee from senders balance".to_owned()}
+            warn!("{:?}", e);
+            Error::QueryError {message: "unable to deduct fee from payers balance".to_owned()}
         })?;
         if fee_from_changed == 0 && transaction.tx.fee > 0 {
-            return Err(Error::QueryError {message: "unable to deduct fee from senders balance".to_owned()});
+            return Err(Error::QueryError {message: "unable to deduct fee from payers balance".to_owned()});
         }
 
-        // Deduct amount from balance of sender
-        let from_changed = conn.execute(
-            "UPDATE `address_balance` SET `balance` = `balance` - ?1 WHERE `address` = ?2 AND `coin` = ?3",
+        if let Some(restriction) = self.coin_restriction_with_conn(&conn, transaction)? {
+            return Err(Error::QueryError {message: restriction.to_string()});
+        }
+
+        // Move the amounts from the sender to the receivers
+        let mut touched = vec![
+            (fee_payer.address.clone(), "KCN".to_owned()),
+            (self.knc_address.address.clone(), "KCN".to_owned())
+        ];
+        match transaction.tx.kind {
+            Kind::Transfer => {
+                // Deduct amount from balance of sender
+                let from_changed = self.balance_sub_with_conn(&conn, &transaction.tx.from.address, &transaction.tx.coin, transaction.tx.amount)?;
+                if from_changed == 0 {
+                    if self.coin_exists_in_chain_with_conn(&conn, &transaction.tx.coin)? {
+                        return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+                    }
+                    // Before the coin registry the first transfer of a ticker created the coin.
+                    // Such transactions are still in old chains.
+                    let coin = Coin {
+                        ticker: transaction.tx.coin.clone(),
+                        name: transaction.tx.coin.clone(),
+                        issuer: transaction.tx.from.address.clone(),
+                        decimals: kcoin::DEFAULT_DECIMALS,
+                        initial_supply: transaction.tx.amount,
+                        minted: 0,
+                        burned: 0,
+                        supply: transaction.tx.amount,
+                        paused: false,
+                        height: block,
+                        hash: transaction.hash.clone()
+                    };
+                    self.coin_insert_with_conn(&conn, &coin)?;
+                }
+
+                // Add amount to receivers balance
+                self.balance_add_with_conn(&conn, &transaction.tx.to.address, &transaction.tx.coin, transaction.tx.amount)?;
+
+                touched.push((transaction.tx.from.address.clone(), transaction.tx.coin.clone()));
+                touched.push((transaction.tx.to.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::Batch { ref outputs } => {
+                // Batches can't create coins, every output needs an existing balance to come from
+                for (i, output) in outputs.iter().enumerate() {
+                    if self.balance_sub_with_conn(&conn, &transaction.tx.from.address, &output.coin, output.amount)? == 0 {
+                        return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+                    }
+                    self.balance_add_with_conn(&conn, &output.to.address, &output.coin, output.amount)?;
+                    self.transaction_output_insert_with_conn(&conn, &transaction.hash, i as u32, &output.to.address, &output.coin, output.amount)?;
+                    touched.push((transaction.tx.from.address.clone(), output.coin.clone()));
+                    touched.push((output.to.address.clone(), output.coin.clone()));
+                }
+            },
+            Kind::CreateCoin { ref name, decimals } => {
+                if self.coin_exists_in_chain_with_conn(&conn, &transaction.tx.coin)? {
+                    return Err(Error::QueryError {message: "coin exists already".to_owned()});
+                }
+                let coin = Coin {
+                    ticker: transaction.tx.coin.clone(),
+                    name: name.clone(),
+                    issuer: transaction.tx.from.address.clone(),
+                    decimals,
+                    initial_supply: transaction.tx.amount,
+                    minted: 0,
+                    burned: 0,
+                    supply: transaction.tx.amount,
+                    paused: false,
+                    height: block,
+                    hash: transaction.hash.clone()
+                };
+                self.coin_insert_with_conn(&conn, &coin)?;
+                self.balance_add_with_conn(&conn, &transaction.tx.from.address, &transaction.tx.coin, transaction.tx.amount)?;
+                touched.push((transaction.tx.from.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::Mint => {
+                // KCN has a fixed supply, only coins created by users can be minted
+                let coin = self.coin_get_with_conn(&conn, &transaction.tx.coin).map_err(|e| match e {
+                    Error::NotFound => Error::QueryError {message: "unknown coin".to_owned()},
+                    e => e
+                })?;
+                if coin.ticker == "KCN" || coin.issuer != transaction.tx.from.address {
+                    return Err(Error::QueryError {message: "only the issuer can mint".to_owned()});
+                }
+                if coin.mint_exceeds_supply(transaction.tx.amount) {
+                    return Err(Error::QueryError {message: "mint exceeds the maximum supply".to_owned()});
+                }
+                self.balance_add_with_conn(&conn, &transaction.tx.to.address, &transaction.tx.coin, transaction.tx.amount)?;
+                touched.push((transaction.tx.to.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::Burn => {
+                if self.balance_sub_with_conn(&conn, &transaction.tx.from.address, &transaction.tx.coin, transaction.tx.amount)? == 0 {
+                    return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+                }
+                touched.push((transaction.tx.from.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::Pause { paused } => {
+                self.coin_control_check_issuer_with_conn(&conn, transaction)?;
+                self.coin_control_insert_with_conn(&conn, &transaction.tx.coin, "", paused, block, &transaction.hash)?;
+            },
+            Kind::Freeze { ref address, frozen } => {
+                self.coin_control_check_issuer_with_conn(&conn, transaction)?;
+                self.coin_control_insert_with_conn(&conn, &transaction.tx.coin, &address.address, frozen, block, &transaction.hash)?;
+            },
+            Kind::HtlcLock { .. } => {
+                if let Some(problem) = self.htlc_problem_with_conn(&conn, transaction, block)? {
+                    return Err(Error::QueryError {message: problem.to_owned()});
+                }
+                if self.balance_sub_with_conn(&conn, &transaction.tx.from.address, &transaction.tx.coin, transaction.tx.amount)? == 0 {
+                    return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+                }
+                self.htlc_insert_with_conn(&conn, block, transaction)?;
+                touched.push((transaction.tx.from.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::HtlcClaim { ref lock, .. } | Kind::HtlcRefund { ref lock } => {
+                if let Some(problem) = self.htlc_problem_with_conn(&conn, transaction, block)? {
+                    return Err(Error::QueryError {message: problem.to_owned()});
+                }
+                let htlc = self.htlc_get_with_conn(&conn, lock)?;
+                self.balance_add_with_conn(&conn, &transaction.tx.from.address, &htlc.coin, htlc.amount)?;
+                self.htlc_settle_with_conn(&conn, block, lock, transaction)?;
+                touched.push((transaction.tx.from.address.clone(), htlc.coin.clone()));
+            },
+            Kind::PlaceOrder { .. } => {
+                if let Some(problem) = self.order_problem_with_conn(&conn, transaction)? {
+                    return Err(Error::QueryError {message: problem.to_owned()});
+                }
+                if self.balance_sub_with_conn(&conn, &transaction.tx.from.address, &transaction.tx.coin, transaction.tx.amount)? == 0 {
+                    return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+                }
+                self.order_insert_with_conn(&conn, block, index, transaction)?;
+                touched.push((transaction.tx.from.address.clone(), transaction.tx.coin.clone()));
+            },
+            Kind::CancelOrder { ref order } => {
+                if let Some(problem) = self.order_problem_with_conn(&conn, transaction)? {
+                    return Err(Error::QueryError {message: problem.to_owned()});
+                }
+                let order = self.order_get_with_conn(&conn, order)?;
+                self.balance_add_with_conn(&conn, &transaction.tx.from.address, &order.coin, order.remaining())?;
+                self.order_close_with_conn(&conn, block, &order.hash, "cancelled")?;
+                touched.push((transaction.tx.from.address.clone(), order.coin.clone()));
+            },
+            Kind::RotateKey { ref key } => {
+                self.address_key_insert_with_conn(&conn, block, &transaction.tx.from.address, key, &transaction.hash)?;
+                self.mempool_prune_rotated_with_conn(&conn, transaction, block)?;
+            },
+        }
+
+        let master_changed = conn.execute(
+            "UPDATE `address_balance` SET `balance` = `balance` + ?1 WHERE `address` = ?2 AND `coin` = 'KCN'",
             &[
-                &(transaction.tx.amount).to_string(),
-                &transaction.tx.from.address.to_string(),
-                &transaction.tx.coin.to_string()
+                &transaction.tx.fee.to_string(),
+                &self.knc_address.address
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
-            Error::QueryError {message: "insert tx failed".to_owned()}
+            warn!("{:?}", e);
+            Error::QueryError {message: "update balance for master failed".to_owned()}
         })?;
-        if from_changed == 0 && self.coin_exists_in_chain_with_conn(&conn, &transaction.tx.coin)? {
-            return Err(Error::QueryError {message: "sender has not enough balance".to_owned()});
+
+        if master_changed == 0 {
+            return Err(Error::QueryError {message: "Unable to update kcn owner balance".to_owned()});
         }
 
-        println!("autocommit after updating sender balance {:?}", conn.is_autocommit());
+        for (address, coin) in touched.iter() {
+            self.balance_history_record_with_conn(&conn, block, address, coin)?;
+        }
+
+        Ok(())
+    }
 
-        // Add amount to receivers balance
-        match self.address_get_balance_with_conn(&conn, &transaction.tx.to.address, &transaction.tx.coin)? {
+    /// Subtracts `amount` from an existing balance. Returns the number of balances changed, 0 if
+    /// the address has no balance in `coin`.
+    pub fn balance_sub_with_conn(&self, conn: &rusqlite::Connection, address: &str, coin: &str, amount: u64) -> Result<usize, Error> {
+        conn.execute(
+            "UPDATE `address_balance` SET `balance` = `balance` - ?1 WHERE `address` = ?2 AND `coin` = ?3",
+            &[
+                amount.to_string().as_str(),
+                address,
+                coin
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "update sender balance failed".to_owned()}
+        })
+    }
+
+    /// Adds `amount` to a balance, creating it if the address didn't hold `coin` yet.
+    pub fn balance_add_with_conn(&self, conn: &rusqlite::Connection, address: &str, coin: &str, amount: u64) -> Result<(), Error> {
+        let changed = match self.address_get_balance_with_conn(&conn, address, coin)? {
             Some(_) => {
-                let to_changed = conn.execute(
+                conn.execute(
                     "UPDATE `address_balance` SET `balance` = `balance` + ?1 WHERE `address` = ?2 AND `coin` = ?3",
                     &[
-                        &transaction.tx.amount.to_string(),
-                        &transaction.tx.to.address.to_string(),
-                        &transaction.tx.coin.to_string()
+                        amount.to_string().as_str(),
+                        address,
+                        coin
                     ],
                 ).map_err(|e| {
-                    println!("{:?}", e);
-                    Error::QueryError {message: "insert tx failed".to_owned()}
-                })?;
-
-                if to_changed == 0 {
-                    return Err(Error::QueryError {message: "Unable to update receiver balance".to_owned()});
-                }
+                    warn!("{:?}", e);
+                    Error::QueryError {message: "update receiver balance failed".to_owned()}
+                })?
             },
             None => {
-                let to_changed = conn.execute(
+                conn.execute(
                     "INSERT INTO `address_balance` (`address`, `coin`, `balance`) VALUES (?1, ?2, ?3)",
                     &[
-                        &transaction.tx.to.address,
-                        &transaction.tx.coin,
-                        &transaction.tx.amount.to_string()
+                        address,
+                        coin,
+                        amount.to_string().as_str()
                     ],
                 ).map_err(|e| {
-                    println!("{:?}", e);
+                    warn!("{:?}", e);
                     Error::QueryError {message: "insert receiver balance failed".to_owned()}
-                })?;
-
-                if to_changed == 0 {
-                    return Err(Error::QueryError {message: "Unable to insert receiver balance".to_owned()});
-                }
+                })?
             }
+        };
+        if changed == 0 {
+            return Err(Error::QueryError {message: "Unable to update receiver balance".to_owned()});
         }
+        Ok(())
+    }
 
-        println!("autocommit after updating receiver balance {:?}", conn.is_autocommit());
-
-        let master_changed = conn.execute(
-            "UPDATE `address_balance` SET `balance` = `balance` + ?1 WHERE `address` = ?2 AND `coin` = 'KCN'",
+    fn transaction_output_insert_with_conn(&self, conn: &rusqlite::Connection, hash: &str, index: u32, to: &str, coin: &str, amount: u64) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO `transaction_output` (`hash`, `index`, `to`, `coin`, `amount`) VALUES (?1, ?2, ?3, ?4, ?5)",
             &[
-                &transaction.tx.fee.to_string(),
-                &self.knc_address.address
+                hash,
+                index.to_string().as_str(),
+                to,
+                coin,
+                amount.to_string().as_str()
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
-            Error::QueryError {message: "update balance for master failed".to_owned()}
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert transaction output failed".to_owned()}
         })?;
+        Ok(())
+    }
 
-        if master_changed == 0 {
-            return Err(Error::QueryError {message: "Unable to update kcn owner balance".to_owned()});
-        }
+    /// Remembers the current balance of `address` in `coin` as its balance at the end of `block`.
+    /// Has to be called whenever a balance changes.
+    pub fn balance_history_record_with_conn(&self, conn: &rusqlite::Connection, block: u32, address: &str, coin: &str) -> Result<(), Error> {
+        let block = block.to_string();
+        conn.execute(
+            "INSERT OR REPLACE INTO `balance_history` (`address`, `coin`, `block`, `balance`)
+                  SELECT `address`, `coin`, ?3, `balance` FROM `address_balance` WHERE `address` = ?1 AND `coin` = ?2",
+            &[address, coin, block.as_str()],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "recording balance history failed".to_owned()}
+        })?;
+        Ok(())
+    }
 
-        println!("autocommit after updating master balance {:?}", conn.is_autocommit());
+    /// Removes every block above `height` together with its transactions and restores all
+    /// balances to what they were at the end of block `height`, using the balance history.
+    pub fn rollback_to_height_with_conn(&self, conn: &rusqlite::Connection, height: u32) -> Result<(), Error> {
+        let height = height.to_string();
+        conn.execute(
+            "UPDATE `address_balance` SET `balance` = ( \
+                 SELECT h.`balance` FROM `balance_history` h \
+                 WHERE h.`address` = `address_balance`.`address` AND h.`coin` = `address_balance`.`coin` AND h.`block` <= ?1 \
+                 ORDER BY h.`block` DESC LIMIT 1) \
+             WHERE EXISTS ( \
+                 SELECT 1 FROM `balance_history` c \
+                 WHERE c.`address` = `address_balance`.`address` AND c.`coin` = `address_balance`.`coin` AND c.`block` > ?1)",
+            &[&height],
+        )?;
+        // Balances that didn't exist yet at that height come back as NULL
+        conn.execute("DELETE FROM `address_balance` WHERE `balance` IS NULL", NO_PARAMS)?;
+        conn.execute("DELETE FROM `balance_history` WHERE `block` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `coin_control` WHERE `height` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `htlc` WHERE `height` > ?1", &[&height])?;
+        conn.execute(
+            "UPDATE `htlc` SET `state` = 'locked', `settled_hash` = '', `settled_height` = 0, `preimage` = '' WHERE `settled_height` > ?1",
+            &[&height],
+        )?;
+        conn.execute("DELETE FROM `address_key` WHERE `height` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `exchange_fill` WHERE `height` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `exchange_order` WHERE `height` > ?1", &[&height])?;
+        conn.execute("UPDATE `exchange_order` SET `state` = 'open', `closed_height` = 0 WHERE `closed_height` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `coin` WHERE `height` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `transaction_output` WHERE `hash` IN (SELECT `hash` FROM `transaction` WHERE `block` > ?1)", &[&height])?;
+        conn.execute("DELETE FROM `transaction` WHERE `block` > ?1", &[&height])?;
+        conn.execute("DELETE FROM `block` WHERE `height` > ?1", &[&height])?;
+        Ok(())
+    }
 
+    /// Records a rollback. `operator` is unverified, it's whatever name the caller gave. `source`
+    /// is how the rollback was requested, which tells who could have given that name.
+    pub fn rollback_log_add_with_conn(&self, conn: &rusqlite::Connection, operator: &str, source: &str, from_height: u32, to_height: u32, tx_hashes: &[String], returned_to_mempool: bool) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs();
+        conn.execute(
+            "INSERT INTO `rollback_log` (`time`, `operator`, `source`, `from_height`, `to_height`, `tx_hashes`, `returned_to_mempool`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[
+                &now.to_string(),
+                operator,
+                source,
+                &from_height.to_string(),
+                &to_height.to_string(),
+                &json!(tx_hashes).to_string(),
+                &(returned_to_mempool as u8).to_string()
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert rollback log failed".to_owned()}
+        })?;
         Ok(())
     }
 
+    /// Returns the balances of `address` as they were at the end of block `height`.
+    pub fn address_get_balances_at_height(&self, address: &Bech32Address, height: u32) -> Result<Vec<Balance>, Error> {
+        let conn = self.get_conn()?;
+
+        let mut stmt = conn
+            .prepare("SELECT h.`coin`, h.`balance` FROM `balance_history` h \
+                      WHERE h.`address` = ?1 AND h.`block` = ( \
+                          SELECT MAX(`block`) FROM `balance_history` l \
+                          WHERE l.`address` = h.`address` AND l.`coin` = h.`coin` AND l.`block` <= ?2)")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt
+            .query_and_then(
+                &[&address.address, &height.to_string()],
+                |row| -> Result<Balance, Error> {
+                    Ok(Balance {
+                        coin: row.get_checked(0)?,
+                        balance: SqliteStorage::i64_to_u64(row.get_checked(1)?)?
+                    })
+                })?;
+
+        let mut results = Vec::new();
+        for result in rows {
+            results.push(result?);
+        }
+        Ok(results)
+    }
+
     pub fn address_get_balance(&self, address: &str, coin: &str) -> Result<Option<u64>, Error> {
         let conn = self.get_conn()?;
         self.address_get_balance_with_conn(&conn, address, coin)
@@ -853,20 +2159,19 @@ impl SqliteStorage {
         Ok(results)
     }
 
-    pub fn address_get_reserved_balances(&self, address: &Bech32Address) -> Result<Vec<Balance>, Error> {
-        let conn = self.get_conn()?;
-
+    pub fn balance_get_all_with_conn(&self, conn: &rusqlite::Connection) -> Result<Vec<AddressBalance>, Error> {
         let mut stmt = conn
-            .prepare("SELECT `coin`, SUM(`amount`) + SUM(`fee`) as `reserved` FROM `mempool` where `from` = ?1 GROUP BY `coin`")
+            .prepare("SELECT `address`, `coin`, `balance` FROM `address_balance` ORDER BY `address`, `coin`")
             .map_err(|e| Error::QueryError {message: e.to_string()})?;
 
         let rows = stmt
             .query_and_then(
-                &[&address.address],
-                |row| -> Result<Balance, Error> {
-                    Ok(Balance {
-                        coin: row.get_checked(0)?,
-                        balance: SqliteStorage::i64_to_u64(row.get_checked(1)?)?
+                NO_PARAMS,
+                |row| -> Result<AddressBalance, Error> {
+                    Ok(AddressBalance {
+                        address: row.get_checked(0)?,
+                        coin: row.get_checked(1)?,
+                        balance: row.get_checked(2)?
                     })
                 })?;
 
@@ -877,47 +2182,73 @@ impl SqliteStorage {
         Ok(results)
     }
 
-    pub fn address_get_reserved_balance(&self, address: &str, coin: &str) -> Result<Option<u64>, Error> {
+    /// Returns the latest block together with its state tree. The tree is built once per block,
+    /// either when the block is made or applied or by the first caller after that, and then shared.
+    /// Block and balances are read in one transaction so that a new block can't slip in between.
+    pub fn state_tree(&self) -> Result<Option<(Block, Arc<state::Tree>)>, Error> {
         let conn = self.get_conn()?;
+        self.start_transaction(&conn)?;
+        let tree = self.state_tree_with_conn(&conn);
+        self.commit_transaction(&conn)?;
+        tree
+    }
 
-        match conn
-            .query_row_and_then(
-                "SELECT SUM(`amount`) + SUM(`fee`) as `reserved` FROM `mempool` where `from` = ?1 and `coin` = ?2 GROUP BY `from`, `coin` LIMIT 1",
-                &[address, coin],
-                |row| {
-                    SqliteStorage::i64_to_u64(row.get_checked(0)?)
-                }) {
-            Ok(v) => Ok(Some(v)),
-            Err(e) => {
-                match e {
-                    Error::NotFound => Ok(None),
-                    _ => Err(Error::QueryError {message: e.to_string()})
-                }
+    fn state_tree_with_conn(&self, conn: &rusqlite::Connection) -> Result<Option<(Block, Arc<state::Tree>)>, Error> {
+        let height = self.block_height_with_conn(&conn)?;
+        if height == 0 {
+            return Ok(None);
+        }
+        let block = self.block_get_by_height_with_conn(&conn, height)?;
+        if let Some((ref hash, ref tree)) = *self.state_tree.lock().map_err(|_| Error::InternalError)? {
+            if hash == &block.hash {
+                return Ok(Some((block, tree.clone())));
             }
         }
+        let tree = self.state_tree_put(&block.hash, state::Tree::new(self.balance_get_all_with_conn(&conn)?))?;
+        Ok(Some((block, tree)))
+    }
+
+    /// Remembers `tree` as the state tree of the block with hash `block_hash`. The hash commits to
+    /// the state root, so a tree stored for a block that doesn't make it into the chain is never
+    /// handed out for another one.
+    pub fn state_tree_put(&self, block_hash: &str, tree: state::Tree) -> Result<Arc<state::Tree>, Error> {
+        let tree = Arc::new(tree);
+        *self.state_tree.lock().map_err(|_| Error::InternalError)? = Some((block_hash.to_owned(), tree.clone()));
+        Ok(tree)
+    }
+
+    /// Sums what the pending transactions of `address` take out of each of its balances, fees
+    /// included. A batch reserves every coin it sends. Transactions that can't be mined yet reserve
+    /// their amounts as well. Fees `address` pays for others' transactions are reserved too.
+    pub fn address_get_reserved_balances(&self, address: &Bech32Address) -> Result<Vec<Balance>, Error> {
+        let mut reserved = BTreeMap::new();
+        for tx in self.mempool_get_by_sender(address)? {
+            for (coin, amount) in tx.tx.debits() {
+                *reserved.entry(coin).or_insert(0u64) += amount;
+            }
+        }
+        let sponsored = self.mempool_fees_paid_by(address)?;
+        if sponsored > 0 {
+            *reserved.entry("KCN".to_owned()).or_insert(0u64) += sponsored;
+        }
+        Ok(reserved.into_iter().map(|(coin, balance)| Balance { coin, balance }).collect())
+    }
+
+    pub fn address_get_reserved_balance(&self, address: &Bech32Address, coin: &str) -> Result<Option<u64>, Error> {
+        Ok(self.address_get_reserved_balances(address)?
+            .into_iter()
+            .find(|b| b.coin == coin)
+            .map(|b| b.balance))
     }
 
     pub fn coin_exists(&self, coin: &str) -> Result<bool, Error> {
         Ok(self.coin_exists_in_chain(coin)? || self.coin_exists_in_mempool(coin)?)
     }
 
+    /// Whether `coin` is in the coin registry.
     pub fn coin_exists_in_chain_with_conn(&self, conn: &rusqlite::Connection, coin: &str) -> Result<bool, Error> {
-        match conn
-            .query_row_and_then(
-                "SELECT `balance` from `address_balance` a where a.coin = ?1 LIMIT 1",
-                &[coin],
-                |row| {
-                    // value doesn't matter
-                    Ok(false)
-                }) {
-            Ok(v) => Ok(true),
-            Err(e) => {
-                match e {
-                    Error::NotFound => Ok(false),
-                    _ => Err(Error::QueryError {message: e.to_string()})
-                }
-            }
-        }
+        let count: u32 = conn.query_row("SELECT count(*) FROM `coin` WHERE `ticker` = ?1", &[coin], |row| row.get(0))?;
+        Ok(count > 0)
     }
 
     pub fn coin_exists_in_chain(&self, coin: &str) -> Result<bool, Error> {
@@ -925,39 +2256,566 @@ impl SqliteStorage {
         self.coin_exists_in_chain_with_conn(&conn, coin)
     }
 
+    /// Whether a transaction creating `coin` is waiting in the mempool.
     pub fn coin_exists_in_mempool(&self, coin: &str) -> Result<bool, Error> {
         let conn = self.get_conn()?;
+        let count: u32 = conn.query_row("SELECT count(*) FROM `mempool` WHERE `type` = 'create_coin' AND `coin` = ?1", &[coin], |row| row.get(0))?;
+        Ok(count > 0)
+    }
 
-        match conn
-            .query_row_and_then(
-                "SELECT `amount` from `mempool` m where m.coin = ?1 LIMIT 1",
-                &[coin],
-                |row| {
-                    // value doesn't matter
-                    Ok(false)
-                }) {
-            Ok(v) => Ok(true),
-            Err(e) => {
-                match e {
-                    Error::NotFound => Ok(false),
-                    _ => Err(Error::QueryError {message: e.to_string()})
+    fn coin_insert_with_conn(&self, conn: &rusqlite::Connection, coin: &Coin) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO `coin` (`ticker`, `name`, `issuer`, `decimals`, `initial_supply`, `height`, `hash`) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[
+                &coin.ticker,
+                &coin.name,
+                &coin.issuer,
+                &coin.decimals.to_string(),
+                &coin.initial_supply.to_string(),
+                &coin.height.to_string(),
+                &coin.hash
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert coin failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    pub fn coin_get(&self, ticker: &str) -> Result<Coin, Error> {
+        let conn = self.get_conn()?;
+        self.coin_get_with_conn(&conn, ticker)
+    }
+
+    pub fn coin_get_with_conn(&self, conn: &rusqlite::Connection, ticker: &str) -> Result<Coin, Error> {
+        let query = format!("SELECT {} FROM `coin` c WHERE c.`ticker` = ?1", COIN_COLUMNS);
+        let coin = conn.query_row_and_then(&query, &[ticker], SqliteStorage::coin_from_row)?;
+        Ok(coin)
+    }
+
+    pub fn coin_list(&self) -> Result<Vec<Coin>, Error> {
+        let conn = self.get_conn()?;
+        let query = format!("SELECT {} FROM `coin` c ORDER BY c.`ticker` ASC", COIN_COLUMNS);
+        let mut stmt = conn
+            .prepare(&query)
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(NO_PARAMS, SqliteStorage::coin_from_row)?;
+
+        let mut coins = Vec::new();
+        for coin in rows {
+            coins.push(coin?);
+        }
+        Ok(coins)
+    }
+
+    /// Whether the latest pause or freeze of `coin` for `address` is still in force. Pauses are
+    /// recorded with an empty address.
+    fn coin_control_active_with_conn(&self, conn: &rusqlite::Connection, coin: &str, address: &str) -> Result<bool, Error> {
+        match conn.query_row_and_then(
+            "SELECT `active` FROM `coin_control` WHERE `coin` = ?1 AND `address` = ?2 ORDER BY `rowid` DESC LIMIT 1",
+            &[coin, address],
+            |row| -> Result<bool, Error> {
+                let active: u32 = row.get_checked(0)?;
+                Ok(active > 0)
+            }) {
+            Ok(v) => Ok(v),
+            Err(Error::NotFound) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Tells why `transaction` can't be mined right now, if a coin it moves is paused or one of
+    /// the addresses involved is frozen for that coin.
+    pub fn coin_restriction_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope) -> Result<Option<Restriction>, Error> {
+        for (coin, addresses) in self.coin_parties_with_conn(conn, transaction)? {
+            if self.coin_control_active_with_conn(conn, &coin, "")? {
+                return Ok(Some(Restriction::Paused { coin }));
+            }
+            for address in addresses {
+                if self.coin_control_active_with_conn(conn, &coin, &address)? {
+                    return Ok(Some(Restriction::Frozen { coin, address }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn coin_restriction(&self, transaction: &TransactionEnvelope) -> Result<Option<Restriction>, Error> {
+        let conn = self.get_conn()?;
+        self.coin_restriction_with_conn(&conn, transaction)
+    }
+
+    /// `Transaction::coin_parties` plus what a claim or refund takes out of its lock.
+    fn coin_parties_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope) -> Result<Vec<(String, Vec<String>)>, Error> {
+        let mut parties = transaction.tx.coin_parties();
+        match transaction.tx.kind {
+            Kind::HtlcClaim { ref lock, .. } | Kind::HtlcRefund { ref lock } => {
+                match self.htlc_get_with_conn(conn, lock) {
+                    Ok(htlc) => parties.push((htlc.coin, vec![transaction.tx.from.address.clone()])),
+                    Err(Error::NotFound) => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            Kind::CancelOrder { ref order } => {
+                match self.order_get_with_conn(conn, order) {
+                    Ok(order) => parties.push((order.coin, vec![transaction.tx.from.address.clone()])),
+                    Err(Error::NotFound) => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            _ => {}
+        }
+        Ok(parties)
+    }
+
+    /// Addresses currently frozen for `coin`.
+    pub fn coin_get_frozen(&self, coin: &str) -> Result<Vec<String>, Error> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn
+            .prepare("SELECT c.`address` FROM `coin_control` c WHERE c.`coin` = ?1 AND c.`address` != '' AND c.`active` = 1 \
+                      AND c.`rowid` = (SELECT MAX(l.`rowid`) FROM `coin_control` l WHERE l.`coin` = c.`coin` AND l.`address` = c.`address`) \
+                      ORDER BY c.`address` ASC")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(&[coin], |row| -> Result<String, Error> { Ok(row.get_checked(0)?) })?;
+
+        let mut addresses = Vec::new();
+        for address in rows {
+            addresses.push(address?);
+        }
+        Ok(addresses)
+    }
+
+    fn coin_control_check_issuer_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope) -> Result<(), Error> {
+        let coin = self.coin_get_with_conn(&conn, &transaction.tx.coin).map_err(|e| match e {
+            Error::NotFound => Error::QueryError {message: "unknown coin".to_owned()},
+            e => e
+        })?;
+        if coin.ticker == "KCN" || coin.issuer != transaction.tx.from.address {
+            return Err(Error::QueryError {message: "only the issuer can pause or freeze".to_owned()});
+        }
+        Ok(())
+    }
+
+    fn coin_control_insert_with_conn(&self, conn: &rusqlite::Connection, coin: &str, address: &str, active: bool, block: u32, hash: &str) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO `coin_control` (`coin`, `address`, `active`, `height`, `hash`) VALUES (?1, ?2, ?3, ?4, ?5)",
+            &[
+                coin,
+                address,
+                (active as u8).to_string().as_str(),
+                block.to_string().as_str(),
+                hash
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert coin control failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    /// Tells why the lock, claim or refund `transaction` can't go into the block at `height`.
+    /// Other transactions never have a problem here.
+    pub fn htlc_problem_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope, height: u32) -> Result<Option<&'static str>, Error> {
+        match transaction.tx.kind {
+            Kind::HtlcLock { timeout_height, .. } => {
+                if timeout_height <= height {
+                    return Ok(Some("lock would time out before it is mined"));
+                }
+                Ok(None)
+            },
+            Kind::HtlcClaim { ref lock, .. } | Kind::HtlcRefund { ref lock } => {
+                match self.htlc_get_with_conn(conn, lock) {
+                    Ok(htlc) => Ok(htlc.settle_problem(&transaction.tx, height)),
+                    Err(Error::NotFound) => Ok(Some("unknown lock")),
+                    Err(e) => Err(e)
+                }
+            },
+            _ => Ok(None)
+        }
+    }
+
+    pub fn htlc_problem(&self, transaction: &TransactionEnvelope, height: u32) -> Result<Option<&'static str>, Error> {
+        let conn = self.get_conn()?;
+        self.htlc_problem_with_conn(&conn, transaction, height)
+    }
+
+    pub fn htlc_get_with_conn(&self, conn: &rusqlite::Connection, hash: &str) -> Result<Htlc, Error> {
+        let query = format!("SELECT {} FROM `htlc` WHERE `hash` = ?1", HTLC_COLUMNS);
+        let htlc = conn.query_row_and_then(&query, &[hash], SqliteStorage::htlc_from_row)?;
+        Ok(htlc)
+    }
+
+    pub fn htlc_get(&self, hash: &str) -> Result<Htlc, Error> {
+        let conn = self.get_conn()?;
+        self.htlc_get_with_conn(&conn, hash)
+    }
+
+    /// The open locks `address` has funds in, as sender or as recipient.
+    pub fn address_get_htlcs(&self, address: &Bech32Address) -> Result<Vec<Htlc>, Error> {
+        let conn = self.get_conn()?;
+        let query = format!("SELECT {} FROM `htlc` WHERE (`from` = ?1 OR `to` = ?1) AND `state` = 'locked' ORDER BY `height` ASC, `rowid` ASC", HTLC_COLUMNS);
+        let mut stmt = conn
+            .prepare(&query)
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(&[&address.address], SqliteStorage::htlc_from_row)?;
+
+        let mut htlcs = Vec::new();
+        for htlc in rows {
+            htlcs.push(htlc?);
+        }
+        Ok(htlcs)
+    }
+
+    fn htlc_insert_with_conn(&self, conn: &rusqlite::Connection, block: u32, transaction: &TransactionEnvelope) -> Result<(), Error> {
+        let (hashlock, timeout_height) = match transaction.tx.kind {
+            Kind::HtlcLock { ref hashlock, timeout_height } => (hashlock, timeout_height),
+            _ => return Err(Error::InternalError)
+        };
+        conn.execute(
+            "INSERT INTO `htlc` (`hash`, `from`, `to`, `coin`, `amount`, `hashlock`, `timeout_height`, `height`, `state`, `settled_hash`, `settled_height`, `preimage`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'locked', '', 0, '')",
+            &[
+                &transaction.hash,
+                &transaction.tx.from.address,
+                &transaction.tx.to.address,
+                &transaction.tx.coin,
+                &transaction.tx.amount.to_string(),
+                hashlock,
+                &timeout_height.to_string(),
+                &block.to_string()
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert htlc failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    fn htlc_settle_with_conn(&self, conn: &rusqlite::Connection, block: u32, lock: &str, transaction: &TransactionEnvelope) -> Result<(), Error> {
+        let (state, preimage) = match transaction.tx.kind {
+            Kind::HtlcClaim { ref preimage, .. } => ("claimed", preimage.as_str()),
+            Kind::HtlcRefund { .. } => ("refunded", ""),
+            _ => return Err(Error::InternalError)
+        };
+        let changed = conn.execute(
+            "UPDATE `htlc` SET `state` = ?1, `settled_hash` = ?2, `settled_height` = ?3, `preimage` = ?4 WHERE `hash` = ?5 AND `state` = 'locked'",
+            &[
+                state,
+                transaction.hash.as_str(),
+                block.to_string().as_str(),
+                preimage,
+                lock
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "settle htlc failed".to_owned()}
+        })?;
+        if changed == 0 {
+            return Err(Error::QueryError {message: "lock is settled already".to_owned()});
+        }
+        Ok(())
+    }
+
+    fn htlc_from_row(row: &Row) -> Result<Htlc, Error> {
+        Ok(Htlc {
+            hash: row.get_checked(0)?,
+            from: row.get_checked(1)?,
+            to: row.get_checked(2)?,
+            coin: row.get_checked(3)?,
+            amount: SqliteStorage::i64_to_u64(row.get_checked(4)?)?,
+            hashlock: row.get_checked(5)?,
+            timeout_height: row.get_checked(6)?,
+            height: row.get_checked(7)?,
+            state: row.get_checked(8)?,
+            settled_hash: row.get_checked(9)?,
+            settled_height: row.get_checked(10)?,
+            preimage: row.get_checked(11)?
+        })
+    }
+
+    /// The key that signs for the single key `address` right now, the latest rotated to or else
+    /// the one the address was derived from.
+    pub fn address_key_with_conn(&self, conn: &rusqlite::Connection, address: &Bech32Address) -> Result<PublicKey, Error> {
+        match conn.query_row_and_then(
+            "SELECT `key` FROM `address_key` WHERE `address` = ?1 ORDER BY `rowid` DESC LIMIT 1",
+            &[&address.address],
+            |row| -> Result<String, Error> { Ok(row.get_checked(0)?) }) {
+            Ok(key) => hex::decode(&key).ok()
+                .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+                .ok_or(Error::QueryError {message: format!("invalid key {}", key)}),
+            Err(Error::NotFound) => Ok(address.public_key()?),
+            Err(e) => Err(e)
+        }
+    }
+
+    pub fn address_key(&self, address: &Bech32Address) -> Result<PublicKey, Error> {
+        let conn = self.get_conn()?;
+        self.address_key_with_conn(&conn, address)
+    }
+
+    fn address_key_insert_with_conn(&self, conn: &rusqlite::Connection, block: u32, address: &str, key: &str, hash: &str) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO `address_key` (`address`, `key`, `height`, `hash`) VALUES (?1, ?2, ?3, ?4)",
+            &[
+                address,
+                key,
+                block.to_string().as_str(),
+                hash
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert address key failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    /// Checks the signatures of `transaction` against the current keys.
+    pub fn transaction_verify(&self, transaction: &TransactionEnvelope) -> Result<bool, Error> {
+        let conn = self.get_conn()?;
+        Ok(transaction.verify(self, &conn))
+    }
+
+    /// Tells why the order or cancel `transaction` can't be mined. Other transactions never have
+    /// a problem here.
+    pub fn order_problem_with_conn(&self, conn: &rusqlite::Connection, transaction: &TransactionEnvelope) -> Result<Option<&'static str>, Error> {
+        match transaction.tx.kind {
+            Kind::PlaceOrder { ref want, .. } => {
+                if !self.coin_exists_in_chain_with_conn(conn, want)? {
+                    return Ok(Some("unknown coin"));
+                }
+                Ok(None)
+            },
+            Kind::CancelOrder { ref order } => {
+                let order = match self.order_get_with_conn(conn, order) {
+                    Ok(order) => order,
+                    Err(Error::NotFound) => return Ok(Some("unknown order")),
+                    Err(e) => return Err(e)
+                };
+                if order.from != transaction.tx.from.address {
+                    Ok(Some("only the owner can cancel the order"))
+                } else if order.state != "open" {
+                    Ok(Some("order is closed already"))
+                } else {
+                    Ok(None)
                 }
+            },
+            _ => Ok(None)
+        }
+    }
+
+    pub fn order_problem(&self, transaction: &TransactionEnvelope) -> Result<Option<&'static str>, Error> {
+        let conn = self.get_conn()?;
+        self.order_problem_with_conn(&conn, transaction)
+    }
+
+    pub fn order_get_with_conn(&self, conn: &rusqlite::Connection, hash: &str) -> Result<Order, Error> {
+        let query = format!("SELECT {} FROM `exchange_order` o WHERE o.`hash` = ?1", ORDER_COLUMNS);
+        let order = conn.query_row_and_then(&query, &[hash], SqliteStorage::order_from_row)?;
+        Ok(order)
+    }
+
+    /// The open orders offering `coin` for `want`, oldest first.
+    pub fn exchange_get_open_orders_with_conn(&self, conn: &rusqlite::Connection, coin: &str, want: &str) -> Result<Vec<Order>, Error> {
+        let query = format!("SELECT {} FROM `exchange_order` o WHERE o.`coin` = ?1 AND o.`want` = ?2 AND o.`state` = 'open' ORDER BY o.`height` ASC, o.`index` ASC", ORDER_COLUMNS);
+        let mut stmt = conn
+            .prepare(&query)
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(&[coin, want], SqliteStorage::order_from_row)?;
+
+        let mut orders = Vec::new();
+        for order in rows {
+            orders.push(order?);
+        }
+        Ok(orders)
+    }
+
+    pub fn exchange_get_open_orders(&self, coin: &str, want: &str) -> Result<Vec<Order>, Error> {
+        let conn = self.get_conn()?;
+        self.exchange_get_open_orders_with_conn(&conn, coin, want)
+    }
+
+    /// The open orders of `coin` for `want` that may trade right now. Orders of paused coins and
+    /// of owners frozen for either coin wait.
+    pub fn exchange_get_tradable_orders_with_conn(&self, conn: &rusqlite::Connection, coin: &str, want: &str) -> Result<Vec<Order>, Error> {
+        if self.coin_control_active_with_conn(conn, coin, "")? || self.coin_control_active_with_conn(conn, want, "")? {
+            return Ok(Vec::new());
+        }
+        let mut orders = Vec::new();
+        for order in self.exchange_get_open_orders_with_conn(conn, coin, want)? {
+            if !self.coin_control_active_with_conn(conn, coin, &order.from)? && !self.coin_control_active_with_conn(conn, want, &order.from)? {
+                orders.push(order);
             }
         }
+        Ok(orders)
+    }
+
+    /// Every `(coin, want)` with open orders, sorted.
+    pub fn exchange_get_pairs_with_conn(&self, conn: &rusqlite::Connection) -> Result<Vec<(String, String)>, Error> {
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT `coin`, `want` FROM `exchange_order` WHERE `state` = 'open' ORDER BY `coin` ASC, `want` ASC")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<(String, String), Error> {
+            Ok((row.get_checked(0)?, row.get_checked(1)?))
+        })?;
+
+        let mut pairs = Vec::new();
+        for pair in rows {
+            pairs.push(pair?);
+        }
+        Ok(pairs)
+    }
+
+    /// The latest fills of orders placed by `address`, newest first.
+    pub fn exchange_get_fills(&self, address: &Bech32Address, limit: u32) -> Result<Vec<Fill>, Error> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn
+            .prepare("SELECT `height`, `order_hash`, `counter_hash`, `address`, `coin`, `amount`, `want`, `received`, `maker` \
+                      FROM `exchange_fill` WHERE `address` = ?1 ORDER BY `rowid` DESC LIMIT ?2")
+            .map_err(|e| Error::QueryError {message: e.to_string()})?;
+
+        let rows = stmt.query_and_then(&[&address.address, &limit.to_string()], |row| -> Result<Fill, Error> {
+            let maker: u32 = row.get_checked(8)?;
+            Ok(Fill {
+                height: row.get_checked(0)?,
+                order_hash: row.get_checked(1)?,
+                counter_hash: row.get_checked(2)?,
+                address: row.get_checked(3)?,
+                coin: row.get_checked(4)?,
+                amount: SqliteStorage::i64_to_u64(row.get_checked(5)?)?,
+                want: row.get_checked(6)?,
+                received: SqliteStorage::i64_to_u64(row.get_checked(7)?)?,
+                maker: maker > 0
+            })
+        })?;
+
+        let mut fills = Vec::new();
+        for fill in rows {
+            fills.push(fill?);
+        }
+        Ok(fills)
+    }
+
+    fn order_insert_with_conn(&self, conn: &rusqlite::Connection, block: u32, index: u32, transaction: &TransactionEnvelope) -> Result<(), Error> {
+        let (want, want_amount) = match transaction.tx.kind {
+            Kind::PlaceOrder { ref want, want_amount } => (want, want_amount),
+            _ => return Err(Error::InternalError)
+        };
+        conn.execute(
+            "INSERT INTO `exchange_order` (`hash`, `from`, `coin`, `amount`, `want`, `want_amount`, `height`, `index`, `state`, `closed_height`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 'open', 0)",
+            &[
+                &transaction.hash,
+                &transaction.tx.from.address,
+                &transaction.tx.coin,
+                &transaction.tx.amount.to_string(),
+                want,
+                &want_amount.to_string(),
+                &block.to_string(),
+                &index.to_string()
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert order failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    pub fn order_close_with_conn(&self, conn: &rusqlite::Connection, block: u32, hash: &str, state: &str) -> Result<(), Error> {
+        let changed = conn.execute(
+            "UPDATE `exchange_order` SET `state` = ?1, `closed_height` = ?2 WHERE `hash` = ?3 AND `state` = 'open'",
+            &[
+                state,
+                block.to_string().as_str(),
+                hash
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "close order failed".to_owned()}
+        })?;
+        if changed == 0 {
+            return Err(Error::QueryError {message: "order is closed already".to_owned()});
+        }
+        Ok(())
+    }
+
+    pub fn fill_insert_with_conn(&self, conn: &rusqlite::Connection, fill: &Fill) -> Result<(), Error> {
+        conn.execute(
+            "INSERT INTO `exchange_fill` (`height`, `order_hash`, `counter_hash`, `address`, `coin`, `amount`, `want`, `received`, `maker`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            &[
+                &fill.height.to_string(),
+                &fill.order_hash,
+                &fill.counter_hash,
+                &fill.address,
+                &fill.coin,
+                &fill.amount.to_string(),
+                &fill.want,
+                &fill.received.to_string(),
+                &(fill.maker as u8).to_string()
+            ],
+        ).map_err(|e| {
+            warn!("{:?}", e);
+            Error::QueryError {message: "insert fill failed".to_owned()}
+        })?;
+        Ok(())
+    }
+
+    fn order_from_row(row: &Row) -> Result<Order, Error> {
+        Ok(Order {
+            hash: row.get_checked(0)?,
+            from: row.get_checked(1)?,
+            coin: row.get_checked(2)?,
+            amount: SqliteStorage::i64_to_u64(row.get_checked(3)?)?,
+            want: row.get_checked(4)?,
+            want_amount: SqliteStorage::i64_to_u64(row.get_checked(5)?)?,
+            height: row.get_checked(6)?,
+            index: row.get_checked(7)?,
+            state: row.get_checked(8)?,
+            closed_height: row.get_checked(9)?,
+            filled: SqliteStorage::i64_to_u64(row.get_checked(10)?)?,
+            received: SqliteStorage::i64_to_u64(row.get_checked(11)?)?
+        })
+    }
+
+    fn coin_from_row(row: &Row) -> Result<Coin, Error> {
+        let decimals: u32 = row.get_checked(3)?;
+        let paused: u32 = row.get_checked(10)?;
+        Ok(Coin {
+            ticker: row.get_checked(0)?,
+            name: row.get_checked(1)?,
+            issuer: row.get_checked(2)?,
+            decimals: decimals as u8,
+            initial_supply: SqliteStorage::i64_to_u64(row.get_checked(4)?)?,
+            height: row.get_checked(5)?,
+            hash: row.get_checked(6)?,
+            supply: SqliteStorage::i64_to_u64(row.get_checked(7)?)?,
+            minted: SqliteStorage::i64_to_u64(row.get_checked(8)?)?,
+            burned: SqliteStorage::i64_to_u64(row.get_checked(9)?)?,
+            paused: paused > 0
+        })
     }
 
     pub fn block_add_with_conn(&self, conn: &rusqlite::Connection, block: &Block) -> Result<(), Error> {
         let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_err(|_| Error::InternalError)?.as_secs();
         conn.execute(
-            "INSERT INTO `block` (`height`, `hash`, `time`)
-                  VALUES (?1, ?2, ?3)",
+            "INSERT INTO `block` (`height`, `hash`, `prev_hash`, `merkle_root`, `state_root`, `time`, `signature`)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
             &[
                 &block.height.to_string(),
                 &block.hash,
-                &block.time.to_string()
+                &block.prev_hash,
+                &block.merkle_root,
+                &block.state_root,
+                &block.time.to_string(),
+                &block.signature
             ],
         ).map_err(|e| {
-            println!("{:?}", e);
+            warn!("{:?}", e);
             Error::QueryError {message: "insert block failed".to_owned()}
         })?;
         Ok(())
@@ -0,0 +1,160 @@
+//! Helpers for the tests: keys, genesis files, throwaway datadirs and signed transactions.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use ed25519_dalek::{Keypair, SecretKey, PublicKey};
+use sha2::Sha512;
+use bech32::{Bech32, convert_bits};
+use hex;
+use jsonrpc_minihttp_server::jsonrpc_core;
+use serde_json::Value;
+use time;
+use kcoin::{Bech32Address, Network};
+use genesis::Genesis;
+use storage::SqliteStorage;
+use tx::{TransactionEnvelope, VERSION_BINARY};
+use producer::Producer;
+use rpccalls;
+use block;
+use block::BlockWithTransactions;
+
+static DATADIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// The key with the secret key `seed` repeated 32 times.
+pub fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = PublicKey::from_secret::<Sha512>(&secret);
+    Keypair { secret, public }
+}
+
+/// The hex encoded public key of `keypair`.
+pub fn public_key(keypair: &Keypair) -> String {
+    hex::encode(&keypair.public.to_bytes()[..])
+}
+
+pub fn producer(seed: u8) -> Producer {
+    Producer::from_secret_hex(&hex::encode(&[seed; 32])).unwrap()
+}
+
+/// The mainnet address of `keypair`.
+pub fn address(keypair: &Keypair) -> Bech32Address {
+    let data = convert_bits(&keypair.public.to_bytes(), 8, 5, true).unwrap();
+    let address = Bech32::new_check_data(Network::Mainnet.prefix(), data).unwrap();
+    Bech32Address { address: address.to_string(), network: Network::Mainnet }
+}
+
+/// A genesis file giving `amount` KCN base units to each of `owners`. Fees go to the first owner.
+pub fn genesis_json(owners: &[&Bech32Address], amount: u64, producers: &[&Producer], producer_timeout: u64, timestamp: i64) -> String {
+    let allocations: Vec<Value> = owners.iter()
+        .map(|o| json!({"address": o.address, "coin": "KCN", "amount": amount}))
+        .collect();
+    let producers: Vec<String> = producers.iter().map(|p| p.public_key()).collect();
+    json!({
+        "network": "kcn",
+        "timestamp": timestamp,
+        "fee_address": owners[0].address,
+        "allocations": allocations,
+        "producers": producers,
+        "producer_timeout": producer_timeout
+    }).to_string()
+}
+
+pub fn genesis(owners: &[&Bech32Address], amount: u64, producers: &[&Producer], producer_timeout: u64, timestamp: i64) -> Genesis {
+    Genesis::from_bytes(genesis_json(owners, amount, producers, producer_timeout, timestamp).as_bytes(), &Network::Mainnet).unwrap()
+}
+
+/// A directory under the system temp dir that no other test uses.
+pub fn datadir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("kcoin-test-{}-{}-{}", process::id(), name, DATADIRS.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A node's storage in a fresh datadir.
+pub fn storage(genesis: &Genesis) -> SqliteStorage {
+    SqliteStorage::new(&datadir("node").join("db"), false, genesis).unwrap()
+}
+
+/// Parses `tx`, given with the fields `tx_send` takes, into a binary envelope without
+/// signatures. `fields` go into the envelope next to `tx`, e.g. a multisig policy.
+pub fn unsigned(chain_id: &str, tx: Value, fields: Value) -> TransactionEnvelope {
+    let mut envelope = json!({"version": VERSION_BINARY, "signature": "", "tx": tx});
+    envelope["tx"]["chain_id"] = json!(chain_id);
+    for (key, value) in fields.as_object().unwrap().iter() {
+        envelope[key.as_str()] = value.clone();
+    }
+    TransactionEnvelope::from_json(envelope.as_object().unwrap().clone(), &Network::Mainnet, chain_id).unwrap()
+}
+
+/// The signature of `keypair` over the data `envelope` is signed with.
+pub fn signature(keypair: &Keypair, envelope: &TransactionEnvelope) -> String {
+    let data = envelope.tx.signature_data(envelope.version).unwrap();
+    hex::encode(&keypair.sign::<Sha512>(&data).to_bytes()[..])
+}
+
+pub fn sign(chain_id: &str, keypair: &Keypair, tx: Value) -> TransactionEnvelope {
+    let mut envelope = unsigned(chain_id, tx, json!({}));
+    envelope.signature = signature(keypair, &envelope);
+    envelope
+}
+
+pub fn transfer(chain_id: &str, keypair: &Keypair, to: &Bech32Address, amount: u64, nonce: u64) -> TransactionEnvelope {
+    sign(chain_id, keypair, json!({
+        "from": address(keypair).address,
+        "to": to.address,
+        "coin": "KCN",
+        "amount": amount,
+        "fee": 1000,
+        "memo": "",
+        "nonce": nonce
+    }))
+}
+
+/// What every account of `chain` starts with, in KCN and in USD.
+pub const FUNDS: u64 = 1000000000000;
+
+/// A chain with a single producer where each of `accounts` starts with `FUNDS` KCN and `FUNDS`
+/// USD. The fees go to an address of their own.
+pub fn chain(accounts: &[&Keypair]) -> (SqliteStorage, Producer) {
+    let mut allocations = Vec::new();
+    for account in accounts.iter() {
+        for coin in ["KCN", "USD"].iter() {
+            allocations.push(json!({"address": address(account).address, "coin": coin, "amount": FUNDS}));
+        }
+    }
+    let genesis = json!({
+        "network": "kcn",
+        "timestamp": 0,
+        "fee_address": address(&keypair(99)).address,
+        "allocations": allocations
+    }).to_string();
+    (storage(&Genesis::from_bytes(genesis.as_bytes(), &Network::Mainnet).unwrap()), producer(1))
+}
+
+/// Sends `tx` to the mempool of `storage` like a client would.
+pub fn send(storage: &SqliteStorage, tx: &TransactionEnvelope) -> jsonrpc_core::Result<Value> {
+    rpccalls::tx::tx_send(storage, &Network::Mainnet, 1000, false, json!(tx).as_object().unwrap().clone())
+}
+
+/// Makes the next block out of the mempool.
+pub fn mine(storage: &SqliteStorage, producer: &Producer) -> BlockWithTransactions {
+    block::generate(storage, 100, &Network::Mainnet, producer).unwrap().expect("nothing to mine")
+}
+
+pub fn balance(storage: &SqliteStorage, address: &Bech32Address, coin: &str) -> u64 {
+    storage.address_get_balance(&address.address, coin).unwrap().unwrap_or(0)
+}
+
+/// Puts `tx` into the mempool of `storage` without the checks of `tx_send`.
+pub fn mempool_add(storage: &SqliteStorage, tx: &TransactionEnvelope) {
+    let conn = storage.get_conn().unwrap();
+    storage.mempool_add_with_conn(&conn, tx).unwrap();
+}
+
+pub fn now() -> i64 {
+    time::get_time().sec
+}
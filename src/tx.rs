@@ -1,4 +1,4 @@
-use ed25519_dalek::{Signature};
+use ed25519_dalek::{Signature, PublicKey};
 use sha2::{Sha512, Sha256, Digest};
 use serde_json;
 use serde_json::{Value, Map};
@@ -8,6 +8,12 @@ use time::Timespec;
 use hex;
 use time;
 use regex::Regex;
+use codec;
+use std::collections::HashMap;
+use std::io::Read;
+use multisig::Multisig;
+use storage::SqliteStorage;
+use rusqlite;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -21,10 +27,340 @@ pub enum Error {
     MissingField {
         field: String
     },
+    #[fail(display = "hash does not match transaction, expected {}", expected)]
+    HashMismatch {
+        expected: String
+    },
+    #[fail(display = "transaction was signed for chain {}", chain_id)]
+    ChainMismatch {
+        chain_id: String
+    },
+}
+
+/// Most transactions can be described by the common fields alone. The others carry extra fields
+/// depending on their type.
+///
+/// Types other than `transfer` can only be sent with `VERSION_BINARY`. For batch transactions the
+/// top level `amount`, `coin` and `to` aren't used. They are stored as 0, KCN and the sender.
+///
+/// `create_coin` registers the ticker `coin` with the sender as issuer and credits the sender with
+/// an initial supply of `amount`. Its `to` is the sender.
+///
+/// `mint` lets the issuer of `coin` create `amount` new units for `to`. `burn` destroys `amount`
+/// of the sender's `coin`, KCN included. Its `to` is the sender.
+///
+/// With `pause` the issuer stops or resumes all movements of `coin`, with `freeze` only those from
+/// and to `address`. Both have an `amount` of 0 and the sender as `to`.
+///
+/// `htlc_lock` takes `amount` of `coin` from the sender and holds it for `to` under a hash time
+/// lock. Until block `timeout_height` the recipient can get it with an `htlc_claim` that reveals
+/// the preimage of `hashlock`, SHA-256 of the preimage. From then on the sender can take it back
+/// with an `htlc_refund`. Claims and refunds name the lock by the hash of its transaction. Their
+/// `amount` is 0, their `coin` KCN and their `to` the sender, the locked funds go to whoever
+/// settles the lock.
+///
+/// `place_order` offers `amount` of `coin` for at least `want_amount` of `want` on the exchange.
+/// The offered amount is held until the order is filled or cancelled. Orders can be filled in
+/// parts, each at no worse a rate. `cancel_order` closes the order with the hash `order` and gives
+/// back what wasn't filled. Its `amount` is 0, its `coin` KCN. Both have the sender as `to`.
+///
+/// `rotate_key` makes `key` the key that signs for the sender from now on, in place of the key
+/// the address was derived from or the one of an earlier rotation. Multisig addresses can't
+/// rotate. Its `amount` is 0, its `coin` KCN and its `to` the sender.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Kind {
+    Transfer,
+    Batch {
+        outputs: Vec<Output>
+    },
+    CreateCoin {
+        name: String,
+        decimals: u8
+    },
+    Mint,
+    Burn,
+    Pause {
+        paused: bool
+    },
+    Freeze {
+        address: Bech32Address,
+        frozen: bool
+    },
+    HtlcLock {
+        hashlock: String,
+        timeout_height: u32
+    },
+    HtlcClaim {
+        lock: String,
+        preimage: String
+    },
+    HtlcRefund {
+        lock: String
+    },
+    PlaceOrder {
+        want: String,
+        want_amount: u64
+    },
+    CancelOrder {
+        order: String
+    },
+    RotateKey {
+        key: String
+    },
+}
+
+/// One recipient of a batch transaction.
+#[derive(Debug, Serialize)]
+pub struct Output {
+    pub to: Bech32Address,
+    pub coin: String,
+    pub amount: u64
+}
+
+/// How many outputs a batch transaction may have.
+pub const MAX_OUTPUTS: usize = 100;
+
+/// The most decimals a coin can have.
+pub const MAX_DECIMALS: u8 = 18;
+
+/// How many bytes the preimage of a hashlock may have.
+pub const MAX_PREIMAGE: usize = 64;
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Transfer => "transfer",
+            Kind::Batch { .. } => "batch",
+            Kind::CreateCoin { .. } => "create_coin",
+            Kind::Mint => "mint",
+            Kind::Burn => "burn",
+            Kind::Pause { .. } => "pause",
+            Kind::Freeze { .. } => "freeze",
+            Kind::HtlcLock { .. } => "htlc_lock",
+            Kind::HtlcClaim { .. } => "htlc_claim",
+            Kind::HtlcRefund { .. } => "htlc_refund",
+            Kind::PlaceOrder { .. } => "place_order",
+            Kind::CancelOrder { .. } => "cancel_order",
+            Kind::RotateKey { .. } => "rotate_key",
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Kind::Transfer => 0,
+            Kind::Batch { .. } => 1,
+            Kind::CreateCoin { .. } => 2,
+            Kind::Mint => 3,
+            Kind::Burn => 4,
+            Kind::Pause { .. } => 5,
+            Kind::Freeze { .. } => 6,
+            Kind::HtlcLock { .. } => 7,
+            Kind::HtlcClaim { .. } => 8,
+            Kind::HtlcRefund { .. } => 9,
+            Kind::PlaceOrder { .. } => 10,
+            Kind::CancelOrder { .. } => 11,
+            Kind::RotateKey { .. } => 12,
+        }
+    }
+
+    /// Parses the type and its extra fields from a `tx` object. A missing type means transfer.
+    pub fn from_json(tx: &Map<String, Value>, network: &Network) -> Result<Self, Error> {
+        let name = match tx.get("type") {
+            Some(v) => v.as_str().ok_or(Error::InvalidField { field: "type".to_owned() })?,
+            None => "transfer"
+        };
+        match name {
+            "transfer" => Ok(Kind::Transfer),
+            "batch" => {
+                let values = tx.get("outputs")
+                    .ok_or(Error::MissingField { field: "outputs".to_owned() })?
+                    .as_array()
+                    .ok_or(Error::InvalidField { field: "outputs".to_owned() })?;
+                if values.len() == 0 || values.len() > MAX_OUTPUTS {
+                    return Err(Error::InvalidField { field: "outputs".to_owned() });
+                }
+                let mut outputs = Vec::new();
+                let mut totals: HashMap<String, u64> = HashMap::new();
+                for value in values.iter() {
+                    let output = value.as_object().ok_or(Error::InvalidField { field: "outputs".to_owned() })?;
+                    let to = TransactionEnvelope::field_as_address(output, network, "to")?;
+                    let coin = TransactionEnvelope::field_as_str(output, "coin")?.to_owned();
+                    if !valid_coin(&coin) {
+                        return Err(Error::InvalidField { field: "coin".to_owned() });
+                    }
+                    let amount = TransactionEnvelope::field_as_u64(output, "amount")?;
+                    let total = totals.entry(coin.clone()).or_insert(0);
+                    *total = total.checked_add(amount)
+                        .filter(|t| amount > 0 && *t <= i64::max_value() as u64)
+                        .ok_or(Error::InvalidField { field: "amount".to_owned() })?;
+                    outputs.push(Output { to, coin, amount });
+                }
+                Ok(Kind::Batch { outputs })
+            },
+            "create_coin" => {
+                lazy_static! {
+                    static ref name_regex: Regex = Regex::new(r"^[ -~]{1,64}$").unwrap();
+                }
+                let name = TransactionEnvelope::field_as_str(tx, "name")?.to_owned();
+                if !name_regex.is_match(&name) {
+                    return Err(Error::InvalidField { field: "name".to_owned() });
+                }
+                let decimals = TransactionEnvelope::field_as_u64(tx, "decimals")?;
+                if decimals > MAX_DECIMALS as u64 {
+                    return Err(Error::InvalidField { field: "decimals".to_owned() });
+                }
+                Ok(Kind::CreateCoin { name, decimals: decimals as u8 })
+            },
+            "mint" => Ok(Kind::Mint),
+            "burn" => Ok(Kind::Burn),
+            "pause" => {
+                let paused = tx.get("paused").and_then(|v| v.as_bool()).ok_or(Error::InvalidField { field: "paused".to_owned() })?;
+                Ok(Kind::Pause { paused })
+            },
+            "freeze" => {
+                let address = TransactionEnvelope::field_as_address(tx, network, "address")?;
+                let frozen = tx.get("frozen").and_then(|v| v.as_bool()).ok_or(Error::InvalidField { field: "frozen".to_owned() })?;
+                Ok(Kind::Freeze { address, frozen })
+            },
+            "htlc_lock" => {
+                let hashlock = TransactionEnvelope::field_as_str(tx, "hashlock")?.to_lowercase();
+                if !valid_hash(&hashlock) {
+                    return Err(Error::InvalidField { field: "hashlock".to_owned() });
+                }
+                let timeout_height = TransactionEnvelope::field_as_u64(tx, "timeout_height")?;
+                if timeout_height == 0 || timeout_height > u32::max_value() as u64 {
+                    return Err(Error::InvalidField { field: "timeout_height".to_owned() });
+                }
+                Ok(Kind::HtlcLock { hashlock, timeout_height: timeout_height as u32 })
+            },
+            "htlc_claim" => {
+                let lock = TransactionEnvelope::field_as_str(tx, "lock")?.to_lowercase();
+                if !valid_hash(&lock) {
+                    return Err(Error::InvalidField { field: "lock".to_owned() });
+                }
+                let preimage = TransactionEnvelope::field_as_str(tx, "preimage")?.to_lowercase();
+                match hex::decode(&preimage) {
+                    Ok(ref bytes) if bytes.len() > 0 && bytes.len() <= MAX_PREIMAGE => {},
+                    _ => return Err(Error::InvalidField { field: "preimage".to_owned() })
+                }
+                Ok(Kind::HtlcClaim { lock, preimage })
+            },
+            "htlc_refund" => {
+                let lock = TransactionEnvelope::field_as_str(tx, "lock")?.to_lowercase();
+                if !valid_hash(&lock) {
+                    return Err(Error::InvalidField { field: "lock".to_owned() });
+                }
+                Ok(Kind::HtlcRefund { lock })
+            },
+            "place_order" => {
+                let want = TransactionEnvelope::field_as_str(tx, "want")?.to_owned();
+                if !valid_coin(&want) || tx.get("coin").and_then(|v| v.as_str()) == Some(want.as_str()) {
+                    return Err(Error::InvalidField { field: "want".to_owned() });
+                }
+                let want_amount = TransactionEnvelope::field_as_u64(tx, "want_amount")?;
+                if want_amount == 0 || want_amount > i64::max_value() as u64 {
+                    return Err(Error::InvalidField { field: "want_amount".to_owned() });
+                }
+                Ok(Kind::PlaceOrder { want, want_amount })
+            },
+            "cancel_order" => {
+                let order = TransactionEnvelope::field_as_str(tx, "order")?.to_lowercase();
+                if !valid_hash(&order) {
+                    return Err(Error::InvalidField { field: "order".to_owned() });
+                }
+                Ok(Kind::CancelOrder { order })
+            },
+            "rotate_key" => {
+                let key = TransactionEnvelope::field_as_str(tx, "key")?.to_lowercase();
+                if hex::decode(&key).ok().and_then(|bytes| PublicKey::from_bytes(&bytes).ok()).is_none() {
+                    return Err(Error::InvalidField { field: "key".to_owned() });
+                }
+                Ok(Kind::RotateKey { key })
+            },
+            _ => Err(Error::InvalidField { field: "type".to_owned() })
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Kind::Transfer => {},
+            Kind::Batch { outputs } => {
+                codec::put_u32(buf, outputs.len() as u32);
+                for output in outputs.iter() {
+                    codec::put_str(buf, &output.to.address);
+                    codec::put_str(buf, &output.coin);
+                    codec::put_u64(buf, output.amount);
+                }
+            },
+            Kind::CreateCoin { name, decimals } => {
+                codec::put_str(buf, name);
+                codec::put_u8(buf, *decimals);
+            },
+            Kind::Mint | Kind::Burn => {},
+            Kind::Pause { paused } => {
+                codec::put_u8(buf, *paused as u8);
+            },
+            Kind::Freeze { address, frozen } => {
+                codec::put_str(buf, &address.address);
+                codec::put_u8(buf, *frozen as u8);
+            },
+            Kind::HtlcLock { hashlock, timeout_height } => {
+                codec::put_str(buf, hashlock);
+                codec::put_u64(buf, *timeout_height as u64);
+            },
+            Kind::HtlcClaim { lock, preimage } => {
+                codec::put_str(buf, lock);
+                codec::put_str(buf, preimage);
+            },
+            Kind::HtlcRefund { lock } => {
+                codec::put_str(buf, lock);
+            },
+            Kind::PlaceOrder { want, want_amount } => {
+                codec::put_str(buf, want);
+                codec::put_u64(buf, *want_amount);
+            },
+            Kind::CancelOrder { order } => {
+                codec::put_str(buf, order);
+            },
+            Kind::RotateKey { key } => {
+                codec::put_str(buf, key);
+            },
+        }
+    }
+}
+
+fn valid_coin(coin: &str) -> bool {
+    lazy_static! {
+        static ref coin_regex: Regex = Regex::new(r"^[A-Z]+$").unwrap();
+    }
+    coin.len() >= 3 && coin.len() <= 4 && coin_regex.is_match(coin)
+}
+
+/// Whether `hash` is a hex encoded SHA-256 hash in lower case.
+fn valid_hash(hash: &str) -> bool {
+    lazy_static! {
+        static ref hash_regex: Regex = Regex::new(r"^[0-9a-f]{64}$").unwrap();
+    }
+    hash_regex.is_match(hash)
+}
+
+/// Whether `preimage`, hex encoded, hashes to `hashlock`.
+pub fn preimage_matches(preimage: &str, hashlock: &str) -> bool {
+    match hex::decode(preimage) {
+        Ok(bytes) => {
+            let mut hasher = Sha256::default();
+            hasher.input(&bytes);
+            hex::encode(hasher.result()) == hashlock
+        },
+        Err(_) => false
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct Transaction {
+    #[serde(flatten)]
+    pub kind: Kind,
     pub amount: u64,
     pub coin: String,
     pub fee: u64,
@@ -32,10 +368,56 @@ pub struct Transaction {
     pub memo: String,
     pub nonce: u64,
     pub to: Bech32Address,
+    /// Hash of the genesis of the chain the transaction is meant for. Only signed in version 2.
+    pub chain_id: String,
+    /// The transaction can only be mined in blocks above this height.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_after_height: Option<u32>,
+    /// The transaction can only be mined in blocks made after this unix time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_after_time: Option<i64>,
+    /// The transaction can only be mined in blocks below this height. Afterwards it gets pruned
+    /// from the mempool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at_height: Option<u32>,
+    /// Pays the fee instead of `from` and signs the transaction as well.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_payer: Option<Bech32Address>,
 }
 
+/// Transactions signed over SHA-256 of their `serde_json` representation. The signed data has no
+/// chain id, so they can be replayed on every chain the sender has funds on. Nodes only take new
+/// ones with `--accept-v1-transactions`, older blocks can still have them.
+pub const VERSION_JSON: u8 = 1;
+
+/// Transactions signed over SHA-256 of `Transaction::encode`. They commit to the chain id so they
+/// can't be replayed on another chain.
+pub const VERSION_BINARY: u8 = 2;
+
 impl Transaction {
-    pub fn signature_data(&self) -> Result<Vec<u8>, Error> {
+    /// The 32 bytes that get signed. How they are derived depends on the envelope's version.
+    pub fn signature_data(&self, version: u8) -> Result<Vec<u8>, Error> {
+        let bytes = match version {
+            VERSION_JSON => self.json_payload()?,
+            VERSION_BINARY => self.encode(VERSION_BINARY),
+            _ => return Err(Error::InvalidField { field: "version".to_owned() })
+        };
+        let mut hasher = Sha256::default();
+        hasher.input(&bytes);
+        let result = hasher.result();
+        Ok(
+            result.to_vec()
+        )
+    }
+
+    fn json_payload(&self) -> Result<Vec<u8>, Error> {
+        match self.kind {
+            Kind::Transfer => {},
+            _ => return Err(Error::InvalidField { field: "version".to_owned() })
+        }
+        if self.valid_after_height.is_some() || self.valid_after_time.is_some() || self.expires_at_height.is_some() || self.fee_payer.is_some() {
+            return Err(Error::InvalidField { field: "version".to_owned() });
+        }
         let json = json!({
         "amount": self.amount,
         "coin": self.coin,
@@ -46,20 +428,263 @@ impl Transaction {
         "to": self.to.address
     });
         let str = serde_json::to_string(&json).map_err(|_| Error::InternalError)?;
-        let bytes = str.as_bytes();
-        let mut hasher = Sha256::default();
-        hasher.input(bytes);
-        let result = hasher.result();
-        Ok(
-            result.to_vec()
-        )
+        Ok(str.into_bytes())
+    }
+
+    /// Canonical binary encoding of a transaction. Integers are big endian, strings are UTF-8
+    /// prefixed with their byte length as u32. The chain id is the hex encoded genesis hash:
+    ///
+    /// ```text
+    /// u8 version | str chain_id | u8 type | u64 amount | str coin | u64 fee | str from | str memo
+    ///     | u64 nonce | str to | u64 valid_after_height | u64 valid_after_time | u64 expires_at_height
+    ///     | str fee_payer | fields of the type
+    /// ```
+    ///
+    /// Unset optional fields are written as 0, or as an empty string.
+    ///
+    /// Types are `0` transfer, without further fields, `1` batch, followed by the number of
+    /// outputs as u32 and `str to | str coin | u64 amount` for every output, `2` create coin,
+    /// followed by `str name | u8 decimals`, `3` mint and `4` burn without further fields, `5` pause,
+    /// followed by `u8 paused`, `6` freeze, followed by `str address | u8 frozen`, `7` htlc lock,
+    /// followed by `str hashlock | u64 timeout_height`, `8` htlc claim, followed by
+    /// `str lock | str preimage`, `9` htlc refund, followed by `str lock`, `10` place order,
+    /// followed by `str want | u64 want_amount`, `11` cancel order, followed by `str order`, and
+    /// `12` rotate key, followed by `str key`. Hashes, keys and the preimage are hex encoded.
+    ///
+    /// Test vector for a transfer, signed with the secret key `0101...01` (32 times `01`):
+    ///
+    /// ```text
+    /// chain_id  c495b6eeece15924a047355ef1e41e650c4d285074bf8000adddb57a1eb9e88b
+    /// amount    100000000
+    /// coin      KCN
+    /// fee       1000
+    /// from      kcn132yw8ht5p8cetl2jmvknewjawt9xwzdlrk2pyxlnwjyqrdq0dawq2uqh5h
+    /// memo      hello
+    /// nonce     1
+    /// to        kcn1syuhwr4g05t4744r23nvxnr7en9cmz53knhr0gja7c84hr7fkw2qk5d6jp
+    ///
+    /// encoded   020000004063343935623665656563653135393234613034373335356566316534316536
+    ///           353063346432383530373462663830303061646464623537613165623965383862000000
+    ///           000005f5e100000000034b434e00000000000003e80000003e6b636e3133327977386874
+    ///           3570386365746c326a6d766b6e65776a6177743978777a646c726b327079786c6e776a79
+    ///           7172647130646177713275716835680000000568656c6c6f00000000000000010000003e
+    ///           6b636e317379756877723467303574343734347232336e76786e7237656e39636d7a3533
+    ///           6b6e687230676a6137633834687237666b7732716b3564366a7000000000000000000000
+    ///           000000000000000000000000000000000000
+    /// hash      83ab57b90bf1a9d306dfacd41479d45ccb0464f8b8c25f2221e065ac3e0c447d
+    /// signature c8566ea493bfa0f687186dc12f06aecfb4a1c3615ba074735436a544e3c60829
+    ///           d75a40dd02ec00cf7079dc8db2795cced63b8d5e6b92c6cd3506fdc0cb499a0d
+    /// ```
+    pub fn encode(&self, version: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        codec::put_u8(&mut buf, version);
+        codec::put_str(&mut buf, &self.chain_id);
+        codec::put_u8(&mut buf, self.kind.code());
+        codec::put_u64(&mut buf, self.amount);
+        codec::put_str(&mut buf, &self.coin);
+        codec::put_u64(&mut buf, self.fee);
+        codec::put_str(&mut buf, &self.from.address);
+        codec::put_str(&mut buf, &self.memo);
+        codec::put_u64(&mut buf, self.nonce);
+        codec::put_str(&mut buf, &self.to.address);
+        codec::put_u64(&mut buf, self.valid_after_height.unwrap_or(0) as u64);
+        codec::put_u64(&mut buf, self.valid_after_time.unwrap_or(0) as u64);
+        codec::put_u64(&mut buf, self.expires_at_height.unwrap_or(0) as u64);
+        codec::put_str(&mut buf, self.fee_payer.as_ref().map_or("", |p| p.address.as_str()));
+        self.kind.encode(&mut buf);
+        buf
+    }
+
+    /// Reads a transaction written by `encode` and returns its version together with the
+    /// transaction as it would be sent to the node, so it can go through `from_json`.
+    pub fn read_json<R: Read>(reader: &mut codec::Reader<R>) -> Result<(u8, Value), codec::Error> {
+        let version = reader.u8()?;
+        let chain_id = reader.string()?;
+        let code = reader.u8()?;
+        let amount = reader.u64()?;
+        let coin = reader.string()?;
+        let fee = reader.u64()?;
+        let from = reader.string()?;
+        let memo = reader.string()?;
+        let nonce = reader.u64()?;
+        let to = reader.string()?;
+        let valid_after_height = reader.u64()?;
+        let valid_after_time = reader.u64()?;
+        let expires_at_height = reader.u64()?;
+        let fee_payer = reader.string()?;
+        let mut tx = json!({
+            "chain_id": chain_id,
+            "amount": amount,
+            "coin": coin,
+            "fee": fee,
+            "from": from,
+            "memo": memo,
+            "nonce": nonce,
+            "to": to
+        });
+        if valid_after_height > 0 {
+            tx["valid_after_height"] = json!(valid_after_height);
+        }
+        if valid_after_time > 0 {
+            tx["valid_after_time"] = json!(valid_after_time);
+        }
+        if expires_at_height > 0 {
+            tx["expires_at_height"] = json!(expires_at_height);
+        }
+        if !fee_payer.is_empty() {
+            tx["fee_payer"] = json!(fee_payer);
+        }
+        match code {
+            0 => {
+                tx["type"] = json!("transfer");
+            },
+            1 => {
+                let count = reader.u32()?;
+                let mut outputs = Vec::new();
+                for _ in 0..count.min(MAX_OUTPUTS as u32 + 1) {
+                    let to = reader.string()?;
+                    let coin = reader.string()?;
+                    let amount = reader.u64()?;
+                    outputs.push(json!({"to": to, "coin": coin, "amount": amount}));
+                }
+                tx["type"] = json!("batch");
+                tx["outputs"] = json!(outputs);
+            },
+            2 => {
+                tx["type"] = json!("create_coin");
+                tx["name"] = json!(reader.string()?);
+                tx["decimals"] = json!(reader.u8()?);
+            },
+            3 => {
+                tx["type"] = json!("mint");
+            },
+            4 => {
+                tx["type"] = json!("burn");
+            },
+            5 => {
+                tx["type"] = json!("pause");
+                tx["paused"] = json!(reader.u8()? != 0);
+            },
+            6 => {
+                tx["type"] = json!("freeze");
+                tx["address"] = json!(reader.string()?);
+                tx["frozen"] = json!(reader.u8()? != 0);
+            },
+            7 => {
+                tx["type"] = json!("htlc_lock");
+                tx["hashlock"] = json!(reader.string()?);
+                tx["timeout_height"] = json!(reader.u64()?);
+            },
+            8 => {
+                tx["type"] = json!("htlc_claim");
+                tx["lock"] = json!(reader.string()?);
+                tx["preimage"] = json!(reader.string()?);
+            },
+            9 => {
+                tx["type"] = json!("htlc_refund");
+                tx["lock"] = json!(reader.string()?);
+            },
+            10 => {
+                tx["type"] = json!("place_order");
+                tx["want"] = json!(reader.string()?);
+                tx["want_amount"] = json!(reader.u64()?);
+            },
+            11 => {
+                tx["type"] = json!("cancel_order");
+                tx["order"] = json!(reader.string()?);
+            },
+            12 => {
+                tx["type"] = json!("rotate_key");
+                tx["key"] = json!(reader.string()?);
+            },
+            // Unknown types are left for from_json to reject
+            _ => {
+                tx["type"] = json!(code);
+            }
+        }
+        Ok((version, tx))
+    }
+
+    /// Whether the transaction may go into a block at `height` made at `time`.
+    pub fn is_mature(&self, height: u32, time: i64) -> bool {
+        self.valid_after_height.map_or(true, |h| height > h) && self.valid_after_time.map_or(true, |t| time > t)
+    }
+
+    /// Whether the transaction can't go into a block at `height` or later anymore.
+    pub fn is_expired(&self, height: u32) -> bool {
+        self.expires_at_height.map_or(false, |h| height >= h)
+    }
+
+    /// The coins the transaction moves, each with the addresses it moves them from and to. Nothing
+    /// listed here may be paused or frozen when the transaction gets mined. Claims, refunds and
+    /// cancelled orders give back what is held for them, which only storage knows.
+    pub fn coin_parties(&self) -> Vec<(String, Vec<String>)> {
+        match self.kind {
+            Kind::Transfer | Kind::HtlcLock { .. } => vec![(self.coin.clone(), vec![self.from.address.clone(), self.to.address.clone()])],
+            Kind::Batch { ref outputs } => outputs.iter()
+                .map(|o| (o.coin.clone(), vec![self.from.address.clone(), o.to.address.clone()]))
+                .collect(),
+            Kind::Mint => vec![(self.coin.clone(), vec![self.to.address.clone()])],
+            Kind::Burn | Kind::PlaceOrder { .. } => vec![(self.coin.clone(), vec![self.from.address.clone()])],
+            Kind::CreateCoin { .. } | Kind::Pause { .. } | Kind::Freeze { .. }
+                | Kind::HtlcClaim { .. } | Kind::HtlcRefund { .. } | Kind::CancelOrder { .. }
+                | Kind::RotateKey { .. } => Vec::new(),
+        }
+    }
+
+    /// Who pays the fee, the fee payer if there is one and the sender otherwise.
+    pub fn fee_paid_by(&self) -> &Bech32Address {
+        self.fee_payer.as_ref().unwrap_or(&self.from)
+    }
+
+    /// Everything the sender spends with this transaction, summed up per coin. The fee is paid
+    /// in KCN and only counts if the sender pays it.
+    pub fn debits(&self) -> Vec<(String, u64)> {
+        let mut debits: Vec<(String, u64)> = Vec::new();
+        {
+            let mut add = |coin: &str, amount: u64| {
+                match debits.iter_mut().find(|d| d.0 == coin) {
+                    Some(d) => d.1 += amount,
+                    None => debits.push((coin.to_owned(), amount))
+                }
+            };
+            if self.fee_payer.is_none() {
+                add("KCN", self.fee);
+            }
+            match self.kind {
+                Kind::Transfer | Kind::Burn | Kind::HtlcLock { .. } | Kind::PlaceOrder { .. } => add(&self.coin, self.amount),
+                Kind::Batch { ref outputs } => {
+                    for output in outputs.iter() {
+                        add(&output.coin, output.amount);
+                    }
+                },
+                // Only the fee is taken from the sender
+                Kind::CreateCoin { .. } | Kind::Mint | Kind::Pause { .. } | Kind::Freeze { .. }
+                    | Kind::HtlcClaim { .. } | Kind::HtlcRefund { .. } | Kind::CancelOrder { .. }
+                    | Kind::RotateKey { .. } => {},
+            }
+        }
+        debits
+    }
+
+    /// The canonical transaction hash. It is derived from the signed data so that every envelope
+    /// carrying the same transaction ends up with the same hash.
+    pub fn hash(&self, version: u8) -> Result<String, Error> {
+        Ok(hex::encode(self.signature_data(version)?))
     }
 }
 
 #[derive(Debug, Serialize)]
 pub struct TransactionEnvelope {
+    pub version: u8,
     pub hash: String,
+    /// Empty for transactions from multisig addresses, they are signed with `multisig` instead.
     pub signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<Multisig>,
+    /// Signature of the fee payer over the same data the sender signs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee_payer_signature: Option<String>,
     pub seen: i64,
     pub tx: Transaction
 }
@@ -94,6 +719,17 @@ impl TransactionEnvelope {
             .ok_or(Error::InvalidField { field: field.to_owned() })
     }
 
+    /// Reads an optional u64 field. Missing, `null` and `0` all mean the field is unset.
+    fn optional_u64(json: &Map<String, Value>, field: &str) -> Result<Option<u64>, Error> {
+        match json.get(field) {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => {
+                let v = v.as_u64().ok_or(Error::InvalidField { field: field.to_owned() })?;
+                Ok(if v == 0 { None } else { Some(v) })
+            }
+        }
+    }
+
     fn field_as_address(json: &Map<String, Value>, network: &Network, field: &str) -> Result<Bech32Address, Error> {
         Bech32Address::new(
             TransactionEnvelope::field_as_str(&json, field)?,
@@ -101,27 +737,77 @@ impl TransactionEnvelope {
         ).map_err(|_| Error::InvalidField { field: field.to_owned() })
     }
 
-    pub fn from_json(json: serde_json::Map<String, Value>, network: &Network) -> Result<Self, Error> {
+    /// Parses an envelope for the chain with the given id. The signatures aren't checked here, the
+    /// keys they have to match depend on the chain state. See `verify`.
+    pub fn from_json(json: serde_json::Map<String, Value>, network: &Network, chain_id: &str) -> Result<Self, Error> {
         lazy_static! {
             static ref memo_regex: Regex = Regex::new(r"^[ -~]*$").unwrap();
-            static ref coin_regex: Regex = Regex::new(r"^[A-Z]+$").unwrap();
         }
-        let hash = TransactionEnvelope::field_as_str(&json, "hash")?.to_owned();
-        let signature = TransactionEnvelope::field_as_str(&json, "signature")?.to_owned();
-        let tx = TransactionEnvelope::field_as_object(&json, "tx")?;
-        let amount = TransactionEnvelope::field_as_u64(&tx, "amount")?;
-        if amount == 0 || amount > i64::max_value() as u64 {
-            return Err(Error::InvalidField {field: "amount".to_owned()});
+        // Envelopes without a version are signed the old way
+        let version = match json.get("version") {
+            Some(v) => v.as_u64().ok_or(Error::InvalidField { field: "version".to_owned() })?,
+            None => VERSION_JSON as u64
+        };
+        if version != VERSION_JSON as u64 && version != VERSION_BINARY as u64 {
+            return Err(Error::InvalidField { field: "version".to_owned() });
         }
-        let coin = TransactionEnvelope::field_as_str(&tx, "coin")?.to_owned();
-        if coin.len() < 3 || coin.len() > 4 || !coin_regex.is_match(&coin) {
-            return Err(Error::InvalidField {field: "coin".to_owned()});
+        let version = version as u8;
+        let supplied_hash = match json.get("hash") {
+            Some(v) => Some(v.as_str().ok_or(Error::InvalidField { field: "hash".to_owned() })?.to_owned()),
+            None => None
+        };
+        let tx = TransactionEnvelope::field_as_object(&json, "tx")?;
+        let kind = Kind::from_json(&tx, network)?;
+        if version == VERSION_JSON && kind.code() != Kind::Transfer.code() {
+            return Err(Error::InvalidField { field: "version".to_owned() });
         }
         let fee = TransactionEnvelope::field_as_u64(&tx, "fee")?;
         if fee > i64::max_value() as u64 {
             return Err(Error::InvalidField {field: "fee".to_owned()});
         }
         let from = TransactionEnvelope::field_as_address(&tx, network, "from")?;
+        if let Kind::RotateKey { .. } = kind {
+            if from.is_multisig() {
+                return Err(Error::InvalidField { field: "from".to_owned() });
+            }
+        }
+        // Multisig addresses sign with several keys. They are only supported by binary transactions.
+        let (signature, multisig) = match from.is_multisig() {
+            true => {
+                if version != VERSION_BINARY {
+                    return Err(Error::InvalidField { field: "version".to_owned() });
+                }
+                let multisig = TransactionEnvelope::field_as_object(&json, "multisig")?;
+                let multisig = Multisig::from_json(multisig).map_err(|e| {
+                    debug!("{}", e);
+                    Error::InvalidField { field: "multisig".to_owned() }
+                })?;
+                (String::new(), Some(multisig))
+            },
+            false => {
+                if json.contains_key("multisig") {
+                    return Err(Error::InvalidField { field: "multisig".to_owned() });
+                }
+                (TransactionEnvelope::field_as_str(&json, "signature")?.to_owned(), None)
+            }
+        };
+        // Someone else may pay the fee. They sign with a single key, next to the sender.
+        let (fee_payer, fee_payer_signature) = match tx.get("fee_payer") {
+            Some(_) => {
+                let fee_payer = TransactionEnvelope::field_as_address(&tx, network, "fee_payer")?;
+                if version != VERSION_BINARY || fee_payer.is_multisig() || fee_payer.address == from.address {
+                    return Err(Error::InvalidField { field: "fee_payer".to_owned() });
+                }
+                let fee_payer_signature = TransactionEnvelope::field_as_str(&json, "fee_payer_signature")?.to_owned();
+                (Some(fee_payer), Some(fee_payer_signature))
+            },
+            None => {
+                if json.contains_key("fee_payer_signature") {
+                    return Err(Error::InvalidField { field: "fee_payer_signature".to_owned() });
+                }
+                (None, None)
+            }
+        };
         let memo = TransactionEnvelope::field_as_str(&tx, "memo")?.to_owned();
         if memo.len() > 64 || !memo_regex.is_match(&memo) {
             return Err(Error::InvalidField {field: "memo".to_owned()});
@@ -130,39 +816,127 @@ impl TransactionEnvelope {
         if nonce > i64::max_value() as u64 {
             return Err(Error::InvalidField {field: "nonce".to_owned()});
         }
-        let to = TransactionEnvelope::field_as_address(&tx, network, "to")?;
+        // 0 means unset, like in the binary encoding
+        let valid_after_height = match TransactionEnvelope::optional_u64(&tx, "valid_after_height")? {
+            Some(v) if v > u32::max_value() as u64 => return Err(Error::InvalidField { field: "valid_after_height".to_owned() }),
+            v => v.map(|v| v as u32)
+        };
+        let valid_after_time = match TransactionEnvelope::optional_u64(&tx, "valid_after_time")? {
+            Some(v) if v > i64::max_value() as u64 => return Err(Error::InvalidField { field: "valid_after_time".to_owned() }),
+            v => v.map(|v| v as i64)
+        };
+        let expires_at_height = match TransactionEnvelope::optional_u64(&tx, "expires_at_height")? {
+            Some(v) if v > u32::max_value() as u64 => return Err(Error::InvalidField { field: "expires_at_height".to_owned() }),
+            v => v.map(|v| v as u32)
+        };
+        let (amount, coin, to) = match kind {
+            Kind::Transfer | Kind::CreateCoin { .. } | Kind::Mint | Kind::Burn | Kind::HtlcLock { .. } | Kind::PlaceOrder { .. } => {
+                let amount = TransactionEnvelope::field_as_u64(&tx, "amount")?;
+                if amount == 0 || amount > i64::max_value() as u64 {
+                    return Err(Error::InvalidField {field: "amount".to_owned()});
+                }
+                let coin = TransactionEnvelope::field_as_str(&tx, "coin")?.to_owned();
+                if !valid_coin(&coin) {
+                    return Err(Error::InvalidField {field: "coin".to_owned()});
+                }
+                let to = match kind {
+                    Kind::CreateCoin { .. } | Kind::Burn | Kind::PlaceOrder { .. } => from.clone(),
+                    _ => TransactionEnvelope::field_as_address(&tx, network, "to")?
+                };
+                (amount, coin, to)
+            },
+            Kind::Pause { .. } | Kind::Freeze { .. } => {
+                let coin = TransactionEnvelope::field_as_str(&tx, "coin")?.to_owned();
+                if !valid_coin(&coin) {
+                    return Err(Error::InvalidField {field: "coin".to_owned()});
+                }
+                (0, coin, from.clone())
+            },
+            Kind::Batch { .. } | Kind::HtlcClaim { .. } | Kind::HtlcRefund { .. } | Kind::CancelOrder { .. }
+                | Kind::RotateKey { .. } => (0, "KCN".to_owned(), from.clone())
+        };
+        // Version 1 transactions don't sign the chain id. Checking it only catches mistakes, it
+        // doesn't keep them from being replayed. Whether they are taken at all is up to `tx_send`.
+        match tx.get("chain_id") {
+            Some(v) => {
+                let supplied = v.as_str().ok_or(Error::InvalidField { field: "chain_id".to_owned() })?;
+                if supplied != chain_id {
+                    return Err(Error::ChainMismatch { chain_id: supplied.to_owned() });
+                }
+            },
+            None if version == VERSION_JSON => {},
+            None => return Err(Error::MissingField { field: "chain_id".to_owned() })
+        }
+        let tx = Transaction {
+            kind,
+            amount,
+            coin,
+            fee,
+            from,
+            memo,
+            nonce,
+            to,
+            chain_id: chain_id.to_owned(),
+            valid_after_height,
+            valid_after_time,
+            expires_at_height,
+            fee_payer,
+        };
+
+        // The hash is optional for clients. If it is given, it has to match.
+        let hash = tx.hash(version)?;
+        if let Some(supplied_hash) = supplied_hash {
+            if supplied_hash != hash {
+                return Err(Error::HashMismatch { expected: hash });
+            }
+        }
+
         let envelope = TransactionEnvelope {
+            version: version,
             hash: hash,
             signature: signature,
+            multisig: multisig,
+            fee_payer_signature: fee_payer_signature,
             seen: time::get_time().sec,
-            tx: Transaction {
-                amount,
-                coin,
-                fee,
-                from,
-                memo,
-                nonce,
-                to,
-            },
+            tx: tx,
         };
 
-        match envelope.verify() {
-            true => Ok(envelope),
-            false => Err(Error::InvalidField {field: "signature".to_owned()})
+        Ok(envelope)
+    }
+
+    /// Checks the signature of the sender and, if someone else pays the fee, theirs, against the
+    /// keys in `storage` as seen through `conn`.
+    pub fn verify(&self, storage: &SqliteStorage, conn: &rusqlite::Connection) -> bool {
+        let sender_signed = match self.tx.from.is_multisig() {
+            true => match (&self.multisig, self.tx.signature_data(self.version)) {
+                (Some(multisig), Ok(signature_data)) => multisig.verify(&self.tx.from, &signature_data),
+                _ => false
+            },
+            false => self.verify_key(storage, conn, &self.tx.from, &self.signature)
+        };
+        if !sender_signed {
+            return false;
+        }
+        match (&self.tx.fee_payer, &self.fee_payer_signature) {
+            (None, None) => true,
+            (Some(fee_payer), Some(signature)) => self.verify_key(storage, conn, fee_payer, signature),
+            _ => false
         }
     }
 
-    pub fn verify(&self) -> bool {
-        let public_key = match self.tx.from.public_key() {
+    /// Checks `signature` against the current key of the single key `address`. That is the key
+    /// the address was derived from until it gets rotated.
+    fn verify_key(&self, storage: &SqliteStorage, conn: &rusqlite::Connection, address: &Bech32Address, signature: &str) -> bool {
+        let public_key = match storage.address_key_with_conn(conn, address) {
             Ok(t) => t,
-            Err(_) => {
-                println!("couldn't fetch pub key from from");
+            Err(e) => {
+                debug!("Couldn't resolve the key of {}: {}", address.address, e);
                 return false;
             }
         };
         //println!("public key {:?}", public_key);
-        //println!("signature str {:?}", self.signature);
-        let signature_as_bytes = match hex::decode(&self.signature) {
+        //println!("signature str {:?}", signature);
+        let signature_as_bytes = match hex::decode(signature) {
             Ok(t) => t,
             Err(_) => {
                 return false;
@@ -172,15 +946,15 @@ impl TransactionEnvelope {
         let signature = match Signature::from_bytes(&signature_as_bytes) {
             Ok(t) => t,
             Err(e) => {
-                println!("{:?}", e);
+                debug!("{:?}", e);
                 return false;
             }
         };
         //println!("signature parsed {:?}", signature);
-        let signature_data = match self.tx.signature_data() {
+        let signature_data = match self.tx.signature_data(self.version) {
             Ok(t) => t,
             Err(e) => {
-                println!("{:?}", e);
+                debug!("{:?}", e);
                 return false;
             }
         };
@@ -188,9 +962,574 @@ impl TransactionEnvelope {
         match public_key.verify::<Sha512>(&signature_data, &signature) {
             Ok(_) => true,
             Err(e) => {
-                println!("{:?}", e);
+                debug!("{:?}", e);
                 false
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use hex;
+    use ed25519_dalek::Keypair;
+    use sha2::{Sha256, Digest};
+    use serde_json::Value;
+    use kcoin::Bech32Address;
+    use kcoin;
+    use kcoin::Network;
+    use tx::{TransactionEnvelope, VERSION_BINARY};
+    use block;
+    use block::BlockWithTransactions;
+    use producer::{Producer, Authority};
+    use storage::SqliteStorage;
+    use rpccalls;
+    use testutil;
+    use testutil::FUNDS;
+
+    // The test vector in the docs of `Transaction::encode`
+    #[test]
+    fn encode_test_vector() {
+        let chain_id = "c495b6eeece15924a047355ef1e41e650c4d285074bf8000adddb57a1eb9e88b";
+        let keypair = testutil::keypair(1);
+        assert_eq!(testutil::address(&keypair).address, "kcn132yw8ht5p8cetl2jmvknewjawt9xwzdlrk2pyxlnwjyqrdq0dawq2uqh5h");
+        let to = Bech32Address::new("kcn1syuhwr4g05t4744r23nvxnr7en9cmz53knhr0gja7c84hr7fkw2qk5d6jp", Network::Mainnet).unwrap();
+        let envelope = testutil::sign(chain_id, &keypair, json!({
+            "from": testutil::address(&keypair).address,
+            "to": to.address,
+            "coin": "KCN",
+            "amount": 100000000,
+            "fee": 1000,
+            "memo": "hello",
+            "nonce": 1
+        }));
+
+        let encoded = concat!(
+            "020000004063343935623665656563653135393234613034373335356566316534316536",
+            "353063346432383530373462663830303061646464623537613165623965383862000000",
+            "000005f5e100000000034b434e00000000000003e80000003e6b636e3133327977386874",
+            "3570386365746c326a6d766b6e65776a6177743978777a646c726b327079786c6e776a79",
+            "7172647130646177713275716835680000000568656c6c6f00000000000000010000003e",
+            "6b636e317379756877723467303574343734347232336e76786e7237656e39636d7a3533",
+            "6b6e687230676a6137633834687237666b7732716b3564366a7000000000000000000000",
+            "000000000000000000000000000000000000"
+        );
+        assert_eq!(hex::encode(envelope.tx.encode(VERSION_BINARY)), encoded);
+        assert_eq!(envelope.hash, "83ab57b90bf1a9d306dfacd41479d45ccb0464f8b8c25f2221e065ac3e0c447d");
+        assert_eq!(envelope.signature, concat!(
+            "c8566ea493bfa0f687186dc12f06aecfb4a1c3615ba074735436a544e3c60829",
+            "d75a40dd02ec00cf7079dc8db2795cced63b8d5e6b92c6cd3506fdc0cb499a0d"
+        ));
+    }
+
+    #[test]
+    fn batch_pays_every_output() {
+        let alice = testutil::keypair(10);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let bob = testutil::address(&testutil::keypair(11));
+        let carol = testutil::address(&testutil::keypair(12));
+        let batch = |outputs: Value| json!({
+            "type": "batch",
+            "from": testutil::address(&alice).address,
+            "outputs": outputs,
+            "fee": 1000,
+            "memo": "",
+            "nonce": 0
+        });
+
+        let empty = json!({"version": VERSION_BINARY, "signature": "", "tx": batch(json!([]))});
+        assert!(TransactionEnvelope::from_json(empty.as_object().unwrap().clone(), &Network::Mainnet, storage.genesis_hash()).is_err());
+
+        // Each output is covered, both together are not
+        let too_much = testutil::sign(storage.genesis_hash(), &alice, batch(json!([
+            {"to": bob.address, "coin": "USD", "amount": FUNDS},
+            {"to": carol.address, "coin": "USD", "amount": 1}
+        ])));
+        assert!(testutil::send(&storage, &too_much).is_err());
+
+        let tx = testutil::sign(storage.genesis_hash(), &alice, batch(json!([
+            {"to": bob.address, "coin": "KCN", "amount": 100},
+            {"to": carol.address, "coin": "KCN", "amount": 200},
+            {"to": carol.address, "coin": "USD", "amount": 300}
+        ])));
+        testutil::send(&storage, &tx).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs[0].hash, tx.hash);
+
+        let alice = testutil::address(&alice);
+        assert_eq!(testutil::balance(&storage, &alice, "KCN"), FUNDS - 300 - 1000);
+        assert_eq!(testutil::balance(&storage, &alice, "USD"), FUNDS - 300);
+        assert_eq!(testutil::balance(&storage, &bob, "KCN"), 100);
+        assert_eq!(testutil::balance(&storage, &carol, "KCN"), 200);
+        assert_eq!(testutil::balance(&storage, &carol, "USD"), 300);
+    }
+
+    // A transfer of 100 KCN with the extra `fields`, e.g. a time lock
+    fn transfer_with(chain_id: &str, from: &Keypair, to: &Bech32Address, nonce: u64, fields: Value) -> TransactionEnvelope {
+        let mut tx = json!({
+            "from": testutil::address(from).address,
+            "to": to.address,
+            "coin": "KCN",
+            "amount": 100,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        });
+        for (key, value) in fields.as_object().unwrap().iter() {
+            tx[key.as_str()] = value.clone();
+        }
+        testutil::sign(chain_id, from, tx)
+    }
+
+    fn hashes(block: &BlockWithTransactions) -> Vec<String> {
+        block.txs.iter().map(|tx| tx.hash.clone()).collect()
+    }
+
+    #[test]
+    fn height_lock_holds_back_the_later_nonces() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+        let carol = testutil::address(&testutil::keypair(12));
+
+        let locked = transfer_with(chain_id, &alice, &carol, 0, json!({"valid_after_height": 2}));
+        assert!(!locked.tx.is_mature(2, testutil::now()));
+        assert!(locked.tx.is_mature(3, testutil::now()));
+        let next = testutil::transfer(chain_id, &alice, &carol, 100, 1);
+        testutil::send(&storage, &locked).unwrap();
+        testutil::send(&storage, &next).unwrap();
+
+        // Blocks 1 and 2 only carry bob's transfers
+        for nonce in 0..2 {
+            let other = testutil::transfer(chain_id, &bob, &carol, 1, nonce);
+            testutil::send(&storage, &other).unwrap();
+            assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![other.hash.clone()]);
+        }
+        assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![locked.hash.clone(), next.hash.clone()]);
+    }
+
+    #[test]
+    fn time_lock_waits_for_a_later_block_time() {
+        let alice = testutil::keypair(10);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let authority = Authority::single(&producer.public_key());
+        let carol = testutil::address(&testutil::keypair(12));
+        let time = testutil::now() + 1000;
+
+        let locked = transfer_with(chain_id, &alice, &carol, 0, json!({"valid_after_time": time}));
+        assert!(!locked.tx.is_mature(1, time));
+        assert!(locked.tx.is_mature(1, time + 1));
+        testutil::send(&storage, &locked).unwrap();
+
+        let generate = |time| block::generate_at(&storage, 100, &Network::Mainnet, &producer, &authority, time).unwrap();
+        assert!(generate(time).is_none());
+        assert_eq!(hashes(&generate(time + 1).unwrap()), vec![locked.hash.clone()]);
+    }
+
+    #[test]
+    fn expired_transaction_is_pruned_with_the_later_nonces() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+        let carol = testutil::address(&testutil::keypair(12));
+
+        // Can't go into block 1 and expires with block 2
+        let expiring = transfer_with(chain_id, &alice, &carol, 0, json!({"valid_after_height": 1, "expires_at_height": 2}));
+        let next = testutil::transfer(chain_id, &alice, &carol, 100, 1);
+        testutil::send(&storage, &expiring).unwrap();
+        testutil::send(&storage, &next).unwrap();
+        assert!(testutil::send(&storage, &transfer_with(chain_id, &bob, &carol, 0, json!({"expires_at_height": 1}))).is_err());
+
+        for nonce in 0..2 {
+            let other = testutil::transfer(chain_id, &bob, &carol, 1, nonce);
+            testutil::send(&storage, &other).unwrap();
+            assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![other.hash.clone()]);
+        }
+        assert!(!storage.mempool_exists(&expiring.hash).unwrap());
+        assert!(!storage.mempool_exists(&next.hash).unwrap());
+
+        let pruned = |hash: &str| rpccalls::mempool::mempool_get_pruned_transaction(&storage, json!({"hash": hash}).as_object().unwrap().clone()).unwrap();
+        let expired = pruned(&expiring.hash);
+        assert_eq!(expired["reason"], "expired");
+        assert_eq!(expired["height"], 2);
+        assert_eq!(pruned(&next.hash)["reason"], format!("depends on expired transaction {}", expiring.hash));
+        assert_eq!(storage.address_nonce_mined(&testutil::address(&alice)).unwrap(), None);
+    }
+
+    // Registers `coin` with `from` as issuer and an initial supply of `amount`
+    fn create_coin(chain_id: &str, from: &Keypair, coin: &str, amount: u64, nonce: u64) -> TransactionEnvelope {
+        testutil::sign(chain_id, from, json!({
+            "type": "create_coin",
+            "from": testutil::address(from).address,
+            "coin": coin,
+            "amount": amount,
+            "name": coin,
+            "decimals": 2,
+            "fee": kcoin::NEW_COIN_FEE,
+            "memo": "",
+            "nonce": nonce
+        }))
+    }
+
+    fn mint(chain_id: &str, from: &Keypair, to: &Bech32Address, coin: &str, amount: u64, nonce: u64) -> TransactionEnvelope {
+        testutil::sign(chain_id, from, json!({
+            "type": "mint",
+            "from": testutil::address(from).address,
+            "to": to.address,
+            "coin": coin,
+            "amount": amount,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        }))
+    }
+
+    #[test]
+    fn mint_cannot_exceed_the_maximum_supply() {
+        let alice = testutil::keypair(10);
+        let (storage, producer) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let bob = testutil::address(&testutil::keypair(11));
+        let max = i64::max_value() as u64;
+
+        testutil::send(&storage, &create_coin(chain_id, &alice, "GLD", 1000, 0)).unwrap();
+        testutil::mine(&storage, &producer);
+
+        // Each mint fits on its own, the pending one counts against the second
+        testutil::send(&storage, &mint(chain_id, &alice, &bob, "GLD", max - 2000, 1)).unwrap();
+        assert!(testutil::send(&storage, &mint(chain_id, &alice, &bob, "GLD", 1001, 2)).is_err());
+        testutil::send(&storage, &mint(chain_id, &alice, &bob, "GLD", 1000, 2)).unwrap();
+        testutil::mine(&storage, &producer);
+        assert_eq!(storage.coin_get("GLD").unwrap().supply, max);
+        assert_eq!(testutil::balance(&storage, &bob, "GLD"), max - 1000);
+
+        let overflow = mint(chain_id, &alice, &bob, "GLD", 1, 3);
+        assert!(testutil::send(&storage, &overflow).is_err());
+
+        // A block from another producer can't carry it either
+        let conn = storage.get_conn().unwrap();
+        storage.start_transaction(&conn).unwrap();
+        assert!(storage.transaction_insert_with_conn(&conn, 3, 0, &overflow).is_err());
+        storage.rollback_transaction(&conn).unwrap();
+        assert_eq!(storage.coin_get("GLD").unwrap().supply, max);
+    }
+
+    // Old chains created a coin with the first transfer of its ticker
+    #[test]
+    fn legacy_transfer_creates_the_coin() {
+        let alice = testutil::keypair(10);
+        let (storage, _) = testutil::chain(&[&alice]);
+        let chain_id = storage.genesis_hash();
+        let bob = testutil::address(&testutil::keypair(11));
+        assert!(!storage.coin_exists("OLD").unwrap());
+
+        let first = transfer_with(chain_id, &alice, &bob, 0, json!({"coin": "OLD", "amount": 500}));
+        let conn = storage.get_conn().unwrap();
+        storage.start_transaction(&conn).unwrap();
+        storage.transaction_insert_with_conn(&conn, 1, 0, &first).unwrap();
+        storage.commit_transaction(&conn).unwrap();
+
+        let coin = storage.coin_get("OLD").unwrap();
+        assert_eq!(coin.name, "OLD");
+        assert_eq!(coin.issuer, testutil::address(&alice).address);
+        assert_eq!(coin.decimals, kcoin::DEFAULT_DECIMALS);
+        assert_eq!((coin.initial_supply, coin.minted, coin.burned, coin.supply), (500, 0, 0, 500));
+        assert_eq!((coin.height, coin.hash), (1, first.hash.clone()));
+        assert!(!coin.paused);
+        assert_eq!(testutil::balance(&storage, &bob, "OLD"), 500);
+        assert_eq!(testutil::balance(&storage, &testutil::address(&alice), "OLD"), 0);
+
+        // From then on the coin exists and the sender has nothing of it
+        let again = transfer_with(chain_id, &alice, &bob, 1, json!({"coin": "OLD", "amount": 500}));
+        storage.start_transaction(&conn).unwrap();
+        assert!(storage.transaction_insert_with_conn(&conn, 1, 1, &again).is_err());
+        storage.rollback_transaction(&conn).unwrap();
+        assert_eq!(storage.coin_get("OLD").unwrap().supply, 500);
+    }
+
+    // Alice registers GLD and gives bob 100 of it, in blocks 1 and 2
+    fn gold(storage: &SqliteStorage, producer: &Producer, alice: &Keypair, bob: &Bech32Address) {
+        let chain_id = storage.genesis_hash();
+        testutil::send(storage, &create_coin(chain_id, alice, "GLD", 1000, 0)).unwrap();
+        testutil::mine(storage, producer);
+        testutil::send(storage, &transfer_with(chain_id, alice, bob, 1, json!({"coin": "GLD"}))).unwrap();
+        testutil::mine(storage, producer);
+    }
+
+    fn pause(chain_id: &str, issuer: &Keypair, paused: bool, nonce: u64) -> TransactionEnvelope {
+        testutil::sign(chain_id, issuer, json!({
+            "type": "pause",
+            "from": testutil::address(issuer).address,
+            "coin": "GLD",
+            "paused": paused,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        }))
+    }
+
+    fn freeze(chain_id: &str, issuer: &Keypair, address: &Bech32Address, frozen: bool, nonce: u64) -> TransactionEnvelope {
+        testutil::sign(chain_id, issuer, json!({
+            "type": "freeze",
+            "from": testutil::address(issuer).address,
+            "coin": "GLD",
+            "address": address.address,
+            "frozen": frozen,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce
+        }))
+    }
+
+    #[test]
+    fn paused_coin_does_not_move() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+        let carol = testutil::address(&testutil::keypair(12));
+        gold(&storage, &producer, &alice, &testutil::address(&bob));
+
+        assert!(testutil::send(&storage, &pause(chain_id, &bob, true, 0)).is_err());
+
+        // The block with the pause leaves the pending transfer for later
+        let transfer = transfer_with(chain_id, &bob, &carol, 0, json!({"coin": "GLD", "amount": 10}));
+        testutil::send(&storage, &transfer).unwrap();
+        let paused = pause(chain_id, &alice, true, 2);
+        testutil::send(&storage, &paused).unwrap();
+        assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![paused.hash.clone()]);
+        assert!(storage.coin_get("GLD").unwrap().paused);
+
+        assert!(block::generate(&storage, 100, &Network::Mainnet, &producer).unwrap().is_none());
+        assert!(testutil::send(&storage, &transfer_with(chain_id, &alice, &carol, 3, json!({"coin": "GLD", "amount": 10}))).is_err());
+        // Other coins still move
+        testutil::send(&storage, &testutil::transfer(chain_id, &alice, &carol, 10, 3)).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs.len(), 1);
+
+        // Resuming takes a block of its own too
+        let resumed = pause(chain_id, &alice, false, 4);
+        testutil::send(&storage, &resumed).unwrap();
+        assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![resumed.hash.clone()]);
+        assert!(!storage.coin_get("GLD").unwrap().paused);
+        assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![transfer.hash.clone()]);
+        assert_eq!(testutil::balance(&storage, &carol, "GLD"), 10);
+    }
+
+    #[test]
+    fn frozen_address_neither_sends_nor_receives() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+        let carol = testutil::address(&testutil::keypair(12));
+        let address = testutil::address(&bob);
+        gold(&storage, &producer, &alice, &address);
+
+        let frozen = freeze(chain_id, &alice, &address, true, 2);
+        testutil::send(&storage, &frozen).unwrap();
+        testutil::mine(&storage, &producer);
+        assert_eq!(storage.coin_get_frozen("GLD").unwrap(), vec![address.address.clone()]);
+
+        assert!(testutil::send(&storage, &transfer_with(chain_id, &bob, &carol, 0, json!({"coin": "GLD"}))).is_err());
+        assert!(testutil::send(&storage, &transfer_with(chain_id, &alice, &address, 3, json!({"coin": "GLD"}))).is_err());
+        // Only GLD is frozen, and only for bob
+        testutil::send(&storage, &testutil::transfer(chain_id, &bob, &carol, 10, 0)).unwrap();
+        testutil::send(&storage, &transfer_with(chain_id, &alice, &carol, 3, json!({"coin": "GLD"}))).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs.len(), 2);
+
+        testutil::send(&storage, &freeze(chain_id, &alice, &address, false, 4)).unwrap();
+        testutil::mine(&storage, &producer);
+        assert!(storage.coin_get_frozen("GLD").unwrap().is_empty());
+        let transfer = transfer_with(chain_id, &bob, &carol, 1, json!({"coin": "GLD"}));
+        testutil::send(&storage, &transfer).unwrap();
+        assert_eq!(hashes(&testutil::mine(&storage, &producer)), vec![transfer.hash.clone()]);
+        assert_eq!(testutil::balance(&storage, &carol, "GLD"), 200);
+        assert_eq!(testutil::balance(&storage, &address, "GLD"), 0);
+    }
+
+    // Locks 500 USD of `from` for `to` until `timeout_height`
+    fn htlc_lock(chain_id: &str, from: &Keypair, to: &Bech32Address, preimage: &[u8], timeout_height: u32) -> TransactionEnvelope {
+        testutil::sign(chain_id, from, json!({
+            "type": "htlc_lock",
+            "from": testutil::address(from).address,
+            "to": to.address,
+            "coin": "USD",
+            "amount": 500,
+            "fee": 1000,
+            "memo": "",
+            "nonce": 0,
+            "hashlock": hex::encode(Sha256::digest(preimage)),
+            "timeout_height": timeout_height
+        }))
+    }
+
+    fn htlc_settle(chain_id: &str, from: &Keypair, lock: &str, preimage: Option<&[u8]>, nonce: u64, valid_after_height: u32) -> TransactionEnvelope {
+        let mut tx = json!({
+            "type": "htlc_refund",
+            "from": testutil::address(from).address,
+            "fee": 1000,
+            "memo": "",
+            "nonce": nonce,
+            "lock": lock,
+            "valid_after_height": valid_after_height
+        });
+        if let Some(preimage) = preimage {
+            tx["type"] = json!("htlc_claim");
+            tx["preimage"] = json!(hex::encode(preimage));
+        }
+        testutil::sign(chain_id, from, tx)
+    }
+
+    #[test]
+    fn htlc_is_claimed_with_the_preimage() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+
+        let lock = htlc_lock(chain_id, &alice, &testutil::address(&bob), b"secret", 10);
+        testutil::send(&storage, &lock).unwrap();
+        testutil::mine(&storage, &producer);
+        assert_eq!(testutil::balance(&storage, &testutil::address(&alice), "USD"), FUNDS - 500);
+        assert_eq!(storage.htlc_get(&lock.hash).unwrap().state, "locked");
+
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &bob, &lock.hash, Some(b"guess"), 0, 0)).is_err());
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &alice, &lock.hash, None, 1, 0)).is_err());
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &alice, &lock.hash, Some(b"secret"), 1, 0)).is_err());
+
+        let claim = htlc_settle(chain_id, &bob, &lock.hash, Some(b"secret"), 0, 0);
+        testutil::send(&storage, &claim).unwrap();
+        testutil::mine(&storage, &producer);
+        assert_eq!(testutil::balance(&storage, &testutil::address(&bob), "USD"), FUNDS + 500);
+        let htlc = storage.htlc_get(&lock.hash).unwrap();
+        assert_eq!(htlc.state, "claimed");
+        assert_eq!(htlc.settled_hash, claim.hash);
+        assert_eq!(htlc.preimage, hex::encode(b"secret"));
+
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &bob, &lock.hash, Some(b"secret"), 1, 0)).is_err());
+    }
+
+    #[test]
+    fn htlc_is_refunded_after_the_timeout() {
+        let alice = testutil::keypair(10);
+        let bob = testutil::keypair(11);
+        let (storage, producer) = testutil::chain(&[&alice, &bob]);
+        let chain_id = storage.genesis_hash();
+
+        let lock = htlc_lock(chain_id, &alice, &testutil::address(&bob), b"secret", 3);
+        testutil::send(&storage, &lock).unwrap();
+        testutil::mine(&storage, &producer);
+
+        // Block 2 is too early, the refund waits for block 3
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &alice, &lock.hash, None, 1, 0)).is_err());
+        let refund = htlc_settle(chain_id, &alice, &lock.hash, None, 1, 2);
+        testutil::send(&storage, &refund).unwrap();
+        testutil::send(&storage, &testutil::transfer(chain_id, &bob, &testutil::address(&alice), 1, 0)).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs.len(), 1);
+        assert_eq!(storage.htlc_get(&lock.hash).unwrap().state, "locked");
+
+        assert_eq!(testutil::mine(&storage, &producer).txs[0].hash, refund.hash);
+        assert_eq!(testutil::balance(&storage, &testutil::address(&alice), "USD"), FUNDS);
+        assert_eq!(storage.htlc_get(&lock.hash).unwrap().state, "refunded");
+
+        // Too late to claim
+        assert!(testutil::send(&storage, &htlc_settle(chain_id, &bob, &lock.hash, Some(b"secret"), 1, 0)).is_err());
+    }
+
+    // 100 USD from `from` to `to`, the fee paid by `fee_payer` if `fee_payer_signer` signs
+    fn sponsored(chain_id: &str, from: &Keypair, to: &Bech32Address, fee_payer: &Bech32Address, fee_payer_signer: Option<&Keypair>) -> TransactionEnvelope {
+        let mut tx = testutil::unsigned(chain_id, json!({
+            "from": testutil::address(from).address,
+            "to": to.address,
+            "coin": "USD",
+            "amount": 100,
+            "fee": 1000,
+            "memo": "",
+            "nonce": 0,
+            "fee_payer": fee_payer.address
+        }), json!({"fee_payer_signature": ""}));
+        tx.signature = testutil::signature(from, &tx);
+        let fee_payer_signature = fee_payer_signer.map(|signer| testutil::signature(signer, &tx));
+        tx.fee_payer_signature = fee_payer_signature;
+        tx
+    }
+
+    #[test]
+    fn fee_payer_pays_the_fee() {
+        let alice = testutil::keypair(10);
+        let carol = testutil::keypair(12);
+        let dave = testutil::keypair(13);
+        let (storage, producer) = testutil::chain(&[&alice, &carol]);
+        let chain_id = storage.genesis_hash();
+        let bob = testutil::address(&testutil::keypair(11));
+
+        let payer = testutil::address(&carol);
+        assert!(testutil::send(&storage, &sponsored(chain_id, &alice, &bob, &payer, None)).is_err());
+        assert!(testutil::send(&storage, &sponsored(chain_id, &alice, &bob, &payer, Some(&alice))).is_err());
+        // Dave has nothing to pay with
+        assert!(testutil::send(&storage, &sponsored(chain_id, &alice, &bob, &testutil::address(&dave), Some(&dave))).is_err());
+
+        let tx = sponsored(chain_id, &alice, &bob, &payer, Some(&carol));
+        testutil::send(&storage, &tx).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs[0].hash, tx.hash);
+
+        let alice = testutil::address(&alice);
+        assert_eq!(testutil::balance(&storage, &alice, "KCN"), FUNDS);
+        assert_eq!(testutil::balance(&storage, &alice, "USD"), FUNDS - 100);
+        assert_eq!(testutil::balance(&storage, &bob, "USD"), 100);
+        assert_eq!(testutil::balance(&storage, &payer, "KCN"), FUNDS - 1000);
+        // The fee payer's nonce isn't used
+        assert_eq!(storage.address_nonce_mined(&payer).unwrap(), None);
+    }
+
+    #[test]
+    fn rotated_key_replaces_the_old_one() {
+        let alice = testutil::keypair(10);
+        let carol = testutil::keypair(12);
+        let new_key = testutil::keypair(20);
+        let (storage, producer) = testutil::chain(&[&alice, &carol]);
+        let chain_id = storage.genesis_hash();
+        let address = testutil::address(&alice);
+        let bob = testutil::address(&testutil::keypair(11));
+        let transfer = |signer: &Keypair, amount: u64| testutil::sign(chain_id, signer, json!({
+            "from": address.address,
+            "to": bob.address,
+            "coin": "KCN",
+            "amount": amount,
+            "fee": 1000,
+            "memo": "",
+            "nonce": 1
+        }));
+
+        let rotation = testutil::sign(chain_id, &alice, json!({
+            "type": "rotate_key",
+            "from": address.address,
+            "key": testutil::public_key(&new_key),
+            "fee": 1000,
+            "memo": "",
+            "nonce": 0
+        }));
+        testutil::send(&storage, &rotation).unwrap();
+        // Signed with the old key while the rotation is pending, as sender and as fee payer
+        let pending = transfer(&alice, 100);
+        testutil::send(&storage, &pending).unwrap();
+        let paid_by_alice = sponsored(chain_id, &carol, &bob, &address, Some(&alice));
+        testutil::send(&storage, &paid_by_alice).unwrap();
+
+        let block = testutil::mine(&storage, &producer);
+        assert_eq!(block.txs.len(), 1);
+        assert_eq!(storage.address_key(&address).unwrap().to_bytes(), new_key.public.to_bytes());
+        for tx in [&pending, &paid_by_alice].iter() {
+            assert!(!storage.mempool_exists(&tx.hash).unwrap());
+            assert_eq!(storage.mempool_get_pruned(&tx.hash).unwrap().reason, "signed with a rotated key");
+        }
+
+        assert!(testutil::send(&storage, &transfer(&alice, 200)).is_err());
+        let tx = transfer(&new_key, 300);
+        testutil::send(&storage, &tx).unwrap();
+        assert_eq!(testutil::mine(&storage, &producer).txs[0].hash, tx.hash);
+        assert_eq!(testutil::balance(&storage, &bob, "KCN"), 300);
+    }
+}
@@ -0,0 +1,186 @@
+//! Helpers for the tests that run whole kcoin processes. The crate is a binary, so these tests
+//! only see it through its command line and rpc calls. Transactions are signed the version 1 way,
+//! which needs nothing but the fields of a transfer, so the nodes run with
+//! `--accept-v1-transactions`.
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ed25519_dalek::{Keypair, SecretKey, PublicKey};
+use sha2::{Sha256, Sha512, Digest};
+use bech32::{Bech32, convert_bits};
+use hex;
+use serde_json::Value;
+
+#[path = "../../src/rpcclient.rs"]
+mod rpcclient;
+
+use self::rpcclient::RpcClient;
+
+static DATADIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// The key with the secret key `seed` repeated 32 times, the same as `--producer-key` files
+/// written by `Node::start`.
+pub fn keypair(seed: u8) -> Keypair {
+    let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = PublicKey::from_secret::<Sha512>(&secret);
+    Keypair { secret, public }
+}
+
+/// The hex encoded public key of `keypair`.
+pub fn public_key(keypair: &Keypair) -> String {
+    hex::encode(&keypair.public.to_bytes()[..])
+}
+
+/// The mainnet address of `keypair`.
+pub fn address(keypair: &Keypair) -> String {
+    let data = convert_bits(&keypair.public.to_bytes(), 8, 5, true).unwrap();
+    Bech32::new_check_data("kcn".to_owned(), data).unwrap().to_string()
+}
+
+/// A genesis file giving `amount` KCN base units to each of `owners`. Fees go to the first owner.
+/// `producers` are the seeds of the producer keys.
+pub fn genesis_json(owners: &[&Keypair], amount: u64, producers: &[u8], producer_timeout: u64, timestamp: i64) -> String {
+    let allocations: Vec<Value> = owners.iter()
+        .map(|o| json!({"address": address(o), "coin": "KCN", "amount": amount}))
+        .collect();
+    let producers: Vec<String> = producers.iter().map(|seed| public_key(&keypair(*seed))).collect();
+    json!({
+        "network": "kcn",
+        "timestamp": timestamp,
+        "fee_address": address(owners[0]),
+        "allocations": allocations,
+        "producers": producers,
+        "producer_timeout": producer_timeout
+    }).to_string()
+}
+
+/// A version 1 transfer of `amount` KCN, as `tx_send` takes it.
+pub fn transfer(keypair: &Keypair, to: &str, amount: u64, nonce: u64) -> Value {
+    let tx = json!({
+        "amount": amount,
+        "coin": "KCN",
+        "fee": 1000,
+        "from": address(keypair),
+        "memo": "",
+        "nonce": nonce,
+        "to": to
+    });
+    let data = Sha256::digest(tx.to_string().as_bytes());
+    let signature = keypair.sign::<Sha512>(&data);
+    json!({"signature": hex::encode(&signature.to_bytes()[..]), "tx": tx})
+}
+
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+pub fn url(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+/// A directory under the system temp dir that no other test uses.
+fn datadir() -> PathBuf {
+    let dir = env::temp_dir().join(format!("kcoin-process-{}-{}", process::id(), DATADIRS.fetch_add(1, Ordering::SeqCst)));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A kcoin process with its own datadir. It gets killed when dropped.
+pub struct Node {
+    child: Child,
+    output: Arc<Mutex<Vec<String>>>,
+    pub rpc: RpcClient
+}
+
+impl Node {
+    /// Starts the kcoin binary cargo built for the tests. It produces with the key of
+    /// `keypair(seed)`. Returns once the node answers rpc calls.
+    pub fn start(port: u16, genesis_json: &str, seed: u8, args: &[&str]) -> Node {
+        let dir = datadir();
+        fs::write(dir.join("genesis.json"), genesis_json).unwrap();
+        fs::write(dir.join("producer.key"), hex::encode(&[seed; 32])).unwrap();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_kcoin"))
+            .arg("--datadir").arg(dir.join("data"))
+            .arg("--genesis").arg(dir.join("genesis.json"))
+            .arg("--producer-key").arg(dir.join("producer.key"))
+            .arg("--rpc-port").arg(port.to_string())
+            .arg("--block-time").arg("1")
+            .arg("--accept-v1-transactions")
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        // The node logs to stdout. Keep the lines so tests can see what it did.
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let lines = output.clone();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                match line {
+                    Ok(line) => lines.lock().unwrap().push(line),
+                    Err(_) => break
+                }
+            }
+        });
+
+        let node = Node { child, output, rpc: RpcClient::new(&url(port)).unwrap() };
+        assert!(wait(Duration::from_secs(30), || node.rpc.call("chain_getHeight", json!({})).is_ok()), "node on port {} did not start", port);
+        node
+    }
+
+    pub fn height(&self) -> u32 {
+        self.rpc.call("chain_getHeight", json!({})).unwrap()["height"].as_u64().unwrap() as u32
+    }
+
+    pub fn block(&self, height: u32) -> Value {
+        self.rpc.call("chain_getBlockByHeight", json!({"height": height})).unwrap()
+    }
+
+    pub fn block_hash(&self, height: u32) -> String {
+        self.block(height)["hash"].as_str().unwrap().to_owned()
+    }
+
+    pub fn send(&self, tx: &Value) -> Result<Value, rpcclient::Error> {
+        self.rpc.call("tx_send", tx.clone())
+    }
+
+    /// Whether the node logged a line containing `text`.
+    pub fn logged(&self, text: &str) -> bool {
+        self.output.lock().unwrap().iter().any(|line| line.contains(text))
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Polls `done` until it returns true or `timeout` passed.
+pub fn wait<F: Fn() -> bool>(timeout: Duration, done: F) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    done()
+}
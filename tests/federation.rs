@@ -0,0 +1,62 @@
+//! Federated producers running as separate processes. They take a while and need free ports, so
+//! they only run with `cargo test -- --ignored`. The in-process tests in `src/federation.rs` cover
+//! the same rules.
+
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate failure;
+extern crate ed25519_dalek;
+extern crate sha2;
+extern crate bech32;
+extern crate hex;
+
+mod common;
+
+use std::time::Duration;
+use common::Node;
+
+#[test]
+#[ignore]
+fn processes_agree_on_blocks() {
+    let alice = common::keypair(10);
+    let bob = common::keypair(11);
+    let genesis_json = common::genesis_json(&[&alice], 1000000000, &[1, 2, 3], 30, common::now() - 600);
+    let ports: Vec<u16> = (0..3).map(|_| common::free_port()).collect();
+    let nodes: Vec<Node> = (0..3).map(|i| {
+        let peers: Vec<String> = ports.iter().filter(|p| **p != ports[i]).map(|p| common::url(*p)).collect();
+        Node::start(ports[i], &genesis_json, i as u8 + 1, &["--peers", peers.join(",").as_str()])
+    }).collect();
+
+    let tx = common::transfer(&alice, &common::address(&bob), 1000, 0);
+    // Only the producer whose turn it is makes the block, so every node gets the transaction
+    for node in nodes.iter() {
+        let _ = node.send(&tx);
+    }
+
+    assert!(common::wait(Duration::from_secs(60), || {
+        nodes.iter().all(|n| n.height() >= 1) && nodes.iter().all(|n| n.block_hash(1) == nodes[0].block_hash(1))
+    }));
+}
+
+#[test]
+#[ignore]
+fn processes_take_over_from_a_missing_producer() {
+    let alice = common::keypair(10);
+    let bob = common::keypair(11);
+    let timestamp = common::now();
+    let genesis_json = common::genesis_json(&[&alice], 1000000000, &[1, 2, 3], 16, timestamp);
+    // The second producer, whose turn height 1 is, never shows up
+    let ports: Vec<u16> = (0..2).map(|_| common::free_port()).collect();
+    let nodes: Vec<Node> = [1u8, 3].iter().enumerate().map(|(i, seed)| {
+        Node::start(ports[i], &genesis_json, *seed, &["--peers", common::url(ports[1 - i]).as_str()])
+    }).collect();
+
+    let tx = common::transfer(&alice, &common::address(&bob), 1000, 0);
+    for node in nodes.iter() {
+        let _ = node.send(&tx);
+    }
+
+    assert!(common::wait(Duration::from_secs(60), || {
+        nodes.iter().all(|n| n.height() >= 1) && nodes[0].block_hash(1) == nodes[1].block_hash(1)
+    }));
+    assert!(nodes[0].block(1)["time"].as_i64().unwrap() >= timestamp + 16);
+}
@@ -0,0 +1,42 @@
+//! Followers running as separate processes next to their primary. They need free ports, so they
+//! only run with `cargo test -- --ignored`.
+
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate failure;
+extern crate ed25519_dalek;
+extern crate sha2;
+extern crate bech32;
+extern crate hex;
+
+mod common;
+
+use std::time::Duration;
+use common::Node;
+
+#[test]
+#[ignore]
+fn follows_the_configured_producer_only() {
+    let alice = common::keypair(10);
+    let genesis_json = common::genesis_json(&[&alice], 1000000000, &[], 30, 0);
+    let primary_port = common::free_port();
+    let primary = Node::start(primary_port, &genesis_json, 1, &[]);
+    let primary_url = common::url(primary_port);
+    let follower = Node::start(common::free_port(), &genesis_json, 2, &[
+        "--follow", primary_url.as_str(),
+        "--chain-producer-key", common::public_key(&common::keypair(1)).as_str()
+    ]);
+    let impostor = Node::start(common::free_port(), &genesis_json, 3, &[
+        "--follow", primary_url.as_str(),
+        "--chain-producer-key", common::public_key(&common::keypair(9)).as_str()
+    ]);
+
+    primary.send(&common::transfer(&alice, &common::address(&common::keypair(11)), 1000, 0)).unwrap();
+
+    assert!(common::wait(Duration::from_secs(30), || follower.height() >= 1));
+    assert_eq!(follower.block_hash(1), primary.block_hash(1));
+    // The other follower got to see the block too and turned it down
+    assert!(common::wait(Duration::from_secs(30), || impostor.logged("invalid block 1: invalid producer signature")));
+    assert_eq!(impostor.height(), 0);
+    // Followers don't take transactions
+    assert!(follower.send(&common::transfer(&alice, &common::address(&alice), 1000, 1)).is_err());
+}